@@ -0,0 +1,52 @@
+use chip8_backend::cache::BlockCache;
+use chip8_backend::Chip8;
+use criterion::{criterion_group, criterion_main, Criterion};
+
+// Instructions per iteration
+const CYCLES: u32 = 10_000;
+
+// Mostly arithmetic in a tight loop, with a draw and a register store every
+// 256 iterations so the cache also sees memory writes
+const ROM: &[u8] = &[
+    0x60, 0x00, // 200: LD V0, 0
+    0xA2, 0x30, // 202: LD I, 0x230
+    0x70, 0x01, // 204: ADD V0, 1
+    0x81, 0x00, // 206: LD V1, V0
+    0x81, 0x04, // 208: ADD V1, V0
+    0x82, 0x13, // 20A: XOR V2, V1
+    0x81, 0x16, // 20C: SHR V1
+    0x83, 0x14, // 20E: ADD V3, V1
+    0x83, 0x25, // 210: SUB V3, V2
+    0x30, 0x00, // 212: SE V0, 0
+    0x12, 0x04, // 214: JP 0x204
+    0xD2, 0x35, // 216: DRW V2, V3, 5
+    0xF3, 0x55, // 218: LD [I], V3
+    0x12, 0x00, // 21A: JP 0x200
+];
+
+fn load() -> Chip8 {
+    let mut chip8 = Chip8::new();
+    chip8.load_rom(ROM).unwrap();
+    chip8
+}
+
+fn block_cache(c: &mut Criterion) {
+    let mut group = c.benchmark_group("block_cache");
+    group.bench_function("interpreter", |b| {
+        let mut chip8 = load();
+        b.iter(|| {
+            for _ in 0..CYCLES {
+                chip8.try_cycle().unwrap();
+            }
+        })
+    });
+    group.bench_function("cached", |b| {
+        let mut chip8 = load();
+        let mut cache = BlockCache::new();
+        b.iter(|| cache.run(&mut chip8, CYCLES).unwrap())
+    });
+    group.finish();
+}
+
+criterion_group!(benches, block_cache);
+criterion_main!(benches);
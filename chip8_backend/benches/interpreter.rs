@@ -0,0 +1,163 @@
+use chip8_backend::filter::Pipeline;
+use chip8_backend::movie::Movie;
+use chip8_backend::quirks::Quirks;
+use chip8_backend::render::{self, Palette};
+use chip8_backend::Chip8;
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use std::path::Path;
+
+// Same as the frontends
+const CYCLES_PER_FRAME: u32 = 10;
+const FRAMES: u32 = 60;
+
+// ROMs from the roms/ submodule, skipped if it hasn't been checked out
+const ROMS: &[&str] = &[
+    "games/Pong [Paul Vervalin, 1990].ch8",
+    "games/Space Invaders [David Winter].ch8",
+    "games/Tetris [Fran Dachille, 1991].ch8",
+    "games/Brix [Andreas Gustafsson, 1990].ch8",
+];
+
+// Arithmetic, skips and jumps, no drawing
+const ALU: &[u8] = &[
+    0x70, 0x01, // 200: ADD V0, 1
+    0x81, 0x04, // 202: ADD V1, V0
+    0x82, 0x13, // 204: XOR V2, V1
+    0x83, 0x25, // 206: SUB V3, V2
+    0x84, 0x36, // 208: SHR V4, V3
+    0x40, 0x00, // 20A: SNE V0, 0
+    0x60, 0x00, // 20C: LD V0, 0
+    0x12, 0x00, // 20E: JP 0x200
+];
+
+// Draws an 8x15 sprite at a moving position every other instruction
+const DRAW: &[u8] = &[
+    0xA0, 0x50, // 200: LD I, 0x050 (font)
+    0xD0, 0x1F, // 202: DRW V0, V1, 15
+    0x70, 0x03, // 204: ADD V0, 3
+    0xD0, 0x1F, // 206: DRW V0, V1, 15
+    0x71, 0x01, // 208: ADD V1, 1
+    0x12, 0x02, // 20A: JP 0x202
+];
+
+fn load(rom: &[u8]) -> Chip8 {
+    let mut chip8 = Chip8::new();
+    chip8.load_rom(rom).unwrap();
+    chip8
+}
+
+fn run(chip8: &mut Chip8, cycles: u32) {
+    for _ in 0..cycles {
+        chip8.try_cycle().unwrap();
+    }
+}
+
+fn instructions(c: &mut Criterion) {
+    let cycles = 10_000;
+    let mut group = c.benchmark_group("instructions");
+    group.throughput(Throughput::Elements(cycles as u64));
+    for (name, rom) in [("alu", ALU), ("draw", DRAW)] {
+        group.bench_function(name, |b| {
+            let mut chip8 = load(rom);
+            b.iter(|| run(&mut chip8, cycles))
+        });
+    }
+    group.finish();
+}
+
+// One second of emulated time per iteration
+fn roms(c: &mut Criterion) {
+    let mut group = c.benchmark_group("roms");
+    for name in ROMS {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("../roms")
+            .join(name);
+        let rom = match std::fs::read(&path) {
+            Ok(rom) => rom,
+            Err(_) => {
+                eprintln!("Skipping {}, run git submodule update", path.display());
+                continue;
+            }
+        };
+        group.bench_with_input(BenchmarkId::from_parameter(name), &rom, |b, rom| {
+            let mut chip8 = load(rom);
+            b.iter(|| {
+                for _ in 0..FRAMES {
+                    for _ in 0..CYCLES_PER_FRAME {
+                        // Some ROMs stop on a bad opcode eventually; start them over
+                        if chip8.try_cycle().is_err() {
+                            chip8.reset();
+                            chip8.load_rom(rom).unwrap();
+                        }
+                    }
+                    chip8.cycle_timer();
+                }
+            })
+        });
+    }
+    group.finish();
+}
+
+// Display to RGBA, as the frontends do every frame
+fn framebuffer(c: &mut Criterion) {
+    let mut chip8 = load(DRAW);
+    run(&mut chip8, 1000);
+    let palette = Palette::default();
+    c.bench_function("framebuffer/rgba", |b| {
+        let mut rgba = vec![0u8; render::rgba8_len(1)];
+        b.iter(|| {
+            render::render_rgba8(chip8.get_display(), &palette, 1, &mut rgba);
+            criterion::black_box(&rgba);
+        })
+    });
+    c.bench_function("framebuffer/rgba_x10", |b| {
+        let mut rgba = vec![0u8; render::rgba8_len(10)];
+        b.iter(|| {
+            render::render_rgba8(chip8.get_display(), &palette, 10, &mut rgba);
+            criterion::black_box(&rgba);
+        })
+    });
+    let filters: Pipeline = "scale2x,scanlines,grid,bloom".parse().unwrap();
+    c.bench_function("framebuffer/filters_x10", |b| {
+        let mut rgba = vec![0u8; render::rgba8_len(1)];
+        b.iter(|| {
+            render::render_rgba8(chip8.get_display(), &palette, 1, &mut rgba);
+            criterion::black_box(filters.apply(&rgba, 10));
+        })
+    });
+}
+
+// There's no save-state format yet; the nearest are copying the whole machine
+// and a movie, which restores a run by replaying its inputs
+fn state(c: &mut Criterion) {
+    let mut chip8 = load(DRAW);
+    run(&mut chip8, 1000);
+    c.bench_function("state/clone", |b| {
+        b.iter(|| criterion::black_box(chip8.clone()))
+    });
+
+    // An hour of play with a key pressed and released every second
+    let mut movie = Movie::new(DRAW, Quirks::default(), CYCLES_PER_FRAME, 0);
+    for second in 0..3600 {
+        movie.record(second * 60, (second % 16) as u8, true);
+        movie.record(second * 60 + 10, (second % 16) as u8, false);
+    }
+    let mut json = Vec::new();
+    movie.write(&mut json).unwrap();
+    let mut group = c.benchmark_group("state");
+    group.throughput(Throughput::Bytes(json.len() as u64));
+    group.bench_function("movie_write", |b| {
+        let mut out = Vec::with_capacity(json.len());
+        b.iter(|| {
+            out.clear();
+            movie.write(&mut out).unwrap();
+        })
+    });
+    group.bench_function("movie_read", |b| {
+        b.iter(|| Movie::read(json.as_slice()).unwrap())
+    });
+    group.finish();
+}
+
+criterion_group!(benches, instructions, roms, framebuffer, state);
+criterion_main!(benches);
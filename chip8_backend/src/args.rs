@@ -0,0 +1,118 @@
+// Command line parsing shared by the binaries
+//
+// Options are taken out of the argument list by name, in any order, leaving
+// the positional arguments. Anything wrong prints the message and the
+// binary's usage, then exits with status 2.
+
+use std::fmt::Display;
+use std::process::exit;
+use std::str::FromStr;
+
+pub struct Args {
+    args: Vec<String>,
+    usage: &'static str,
+}
+
+impl Args {
+    pub fn new(args: impl IntoIterator<Item = String>, usage: &'static str) -> Self {
+        Self {
+            args: args.into_iter().collect(),
+            usage,
+        }
+    }
+
+    // The process's arguments, without the program name
+    pub fn from_env(usage: &'static str) -> Self {
+        Self::new(std::env::args().skip(1), usage)
+    }
+
+    // Whether a flag without a value was given
+    pub fn flag(&mut self, flag: &str) -> bool {
+        match self.args.iter().position(|a| a == flag) {
+            Some(index) => {
+                self.args.remove(index);
+                true
+            }
+            None => false,
+        }
+    }
+
+    // The value following an option, if the option was given
+    pub fn value<T>(&mut self, flag: &str) -> Option<T>
+    where
+        T: FromStr,
+        T::Err: Display,
+    {
+        self.try_value(flag).unwrap_or_else(|e| self.fail(e))
+    }
+
+    // The positional arguments left, which must be exactly count of them
+    pub fn finish(self, count: usize) -> Vec<String> {
+        if let Some(option) = self.args.iter().find(|a| a.starts_with("--")) {
+            self.fail(format!("Unknown option {}", option));
+        }
+        if self.args.len() != count {
+            eprintln!("{}", self.usage);
+            exit(2);
+        }
+        self.args
+    }
+
+    pub fn fail(&self, message: impl Display) -> ! {
+        eprintln!("{}", message);
+        eprintln!("{}", self.usage);
+        exit(2);
+    }
+
+    fn try_value<T>(&mut self, flag: &str) -> Result<Option<T>, String>
+    where
+        T: FromStr,
+        T::Err: Display,
+    {
+        let index = match self.args.iter().position(|a| a == flag) {
+            Some(index) => index,
+            None => return Ok(None),
+        };
+        self.args.remove(index);
+        if index >= self.args.len() {
+            return Err(format!("{} needs a value", flag));
+        }
+        let value = self.args.remove(index);
+        value
+            .parse()
+            .map(Some)
+            .map_err(|e| format!("Invalid value {} for {}: {}", value, flag, e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(line: &str) -> Args {
+        Args::new(line.split(' ').map(String::from), "Usage: test")
+    }
+
+    #[test]
+    fn takes_options_in_any_order() {
+        let mut args = args("--json game.ch8 --frames 60");
+        assert_eq!(args.value::<u32>("--frames"), Some(60));
+        assert_eq!(args.value::<u32>("--cycles"), None);
+        assert!(args.flag("--json"));
+        assert!(!args.flag("--dump"));
+        assert_eq!(args.finish(1), ["game.ch8"]);
+    }
+
+    #[test]
+    fn bad_values_are_errors() {
+        let mut args = args("--frames ten --cycles");
+        assert_eq!(
+            args.try_value::<u32>("--frames"),
+            Err("Invalid value ten for --frames: invalid digit found in string".to_string())
+        );
+        assert_eq!(
+            args.try_value::<u32>("--cycles"),
+            Err("--cycles needs a value".to_string())
+        );
+    }
+}
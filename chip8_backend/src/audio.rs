@@ -0,0 +1,320 @@
+// Sound output as PCM samples
+//
+// The generator turns the sound timer into samples at any rate. Frontends
+// tell it whether the timer is running once per frame and pull samples from
+// their audio callback; recordings render a frame's worth at a time so audio
+// lines up with video frames. Starts and stops fade in and out over a few
+// milliseconds instead of clicking. XO-CHIP pattern audio (F002, Fx3A) isn't
+// run by the interpreter yet, so only the sound timer's tone is heard.
+
+use std::fmt;
+use std::io::{self, Seek, SeekFrom, Write};
+use std::str::FromStr;
+
+const FPS: u64 = 60;
+// Lower rates are raised to this, so there's a sample every frame and room
+// below the Nyquist frequency for the pitch
+const MIN_RATE: u32 = FPS as u32;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Waveform {
+    Square,
+    Sine,
+    Triangle,
+    // A new random level every half cycle
+    Noise,
+}
+
+impl FromStr for Waveform {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "square" => Ok(Waveform::Square),
+            "sine" => Ok(Waveform::Sine),
+            "triangle" => Ok(Waveform::Triangle),
+            "noise" => Ok(Waveform::Noise),
+            _ => Err(format!(
+                "Unknown waveform {}, expected square, sine, triangle or noise",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Waveform {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Waveform::Square => "square",
+            Waveform::Sine => "sine",
+            Waveform::Triangle => "triangle",
+            Waveform::Noise => "noise",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+// Defaults to a 440 Hz square wave at a quarter volume, as chip8_sdl has
+// always played
+#[derive(Debug, Clone)]
+pub struct Generator {
+    rate: u32,
+    waveform: Waveform,
+    frequency: f32,
+    volume: f32,
+    // Seconds to fade in or out
+    fade: f32,
+    // Whether the sound timer is running
+    sound: bool,
+    // Envelope, 0.0 (silent) to 1.0
+    level: f32,
+    // Position in the current cycle, 0.0 to 1.0
+    phase: f32,
+    // Noise: LFSR state and the level it last gave
+    lfsr: u16,
+    noise: f32,
+    // Frames rendered by render_frame, to spread rate / 60 samples per frame
+    frame: u64,
+}
+
+impl Generator {
+    pub fn new(rate: u32) -> Self {
+        Self {
+            rate: rate.max(MIN_RATE),
+            waveform: Waveform::Square,
+            frequency: 440.0,
+            volume: 0.25,
+            fade: 0.005,
+            sound: false,
+            level: 0.0,
+            phase: 0.0,
+            lfsr: 0xACE1,
+            noise: 1.0,
+            frame: 0,
+        }
+    }
+
+    pub fn get_rate(&self) -> u32 {
+        self.rate
+    }
+
+    // E.g. once the output device reports its rate
+    pub fn set_rate(&mut self, rate: u32) {
+        self.rate = rate.max(MIN_RATE);
+        self.frame = 0;
+    }
+
+    pub fn get_waveform(&self) -> Waveform {
+        self.waveform
+    }
+
+    pub fn set_waveform(&mut self, waveform: Waveform) {
+        self.waveform = waveform;
+    }
+
+    // Hz, as set; it's kept between 1 Hz and half the rate when playing
+    pub fn get_frequency(&self) -> f32 {
+        self.frequency
+    }
+
+    // Ignores NaN, keeping the last frequency
+    pub fn set_frequency(&mut self, frequency: f32) {
+        if !frequency.is_nan() {
+            self.frequency = frequency;
+        }
+    }
+
+    // 0.0 to 1.0
+    pub fn get_volume(&self) -> f32 {
+        self.volume
+    }
+
+    // Ignores NaN, keeping the last volume
+    pub fn set_volume(&mut self, volume: f32) {
+        if !volume.is_nan() {
+            self.volume = volume.clamp(0.0, 1.0);
+        }
+    }
+
+    // Seconds; 0 switches instantly
+    pub fn get_fade(&self) -> f32 {
+        self.fade
+    }
+
+    pub fn set_fade(&mut self, fade: f32) {
+        self.fade = fade.max(0.0);
+    }
+
+    // Call once per frame with Chip8::get_sound
+    pub fn set_sound(&mut self, sound: bool) {
+        self.sound = sound;
+    }
+
+    // Fills the buffer with samples from -1.0 to 1.0, for audio callbacks
+    pub fn fill(&mut self, out: &mut [f32]) {
+        let frequency = self.frequency.clamp(1.0, self.rate as f32 / 2.0);
+        let step = frequency / self.rate as f32;
+        let fade = match self.fade * self.rate as f32 {
+            samples if samples >= 1.0 => 1.0 / samples,
+            _ => 1.0,
+        };
+        let target = if self.sound { 1.0 } else { 0.0 };
+        for sample in out {
+            if self.level < target {
+                self.level = (self.level + fade).min(target);
+            } else if self.level > target {
+                self.level = (self.level - fade).max(target);
+            }
+            if self.level == 0.0 {
+                *sample = 0.0;
+                continue;
+            }
+            *sample = self.wave() * self.volume * self.level;
+            let before = self.phase;
+            self.phase = (self.phase + step) % 1.0;
+            if self.phase < before || (before < 0.5 && self.phase >= 0.5) {
+                self.next_noise();
+            }
+        }
+    }
+
+    // Appends one frame of samples for the sound timer's state. Frames
+    // alternate between rounding the sample count down and up, so n frames
+    // always make n * rate / 60 samples.
+    pub fn render_frame(&mut self, sound: bool, out: &mut Vec<f32>) {
+        let start = self.frame * self.rate as u64 / FPS;
+        self.frame += 1;
+        let end = self.frame * self.rate as u64 / FPS;
+        let len = out.len();
+        out.resize(len + (end - start) as usize, 0.0);
+        self.set_sound(sound);
+        self.fill(&mut out[len..]);
+    }
+
+    // At the current phase, -1.0 to 1.0
+    fn wave(&self) -> f32 {
+        match self.waveform {
+            Waveform::Square if self.phase < 0.5 => 1.0,
+            Waveform::Square => -1.0,
+            Waveform::Sine => (self.phase * std::f32::consts::TAU).sin(),
+            Waveform::Triangle => 1.0 - 4.0 * (self.phase - 0.5).abs(),
+            Waveform::Noise => self.noise,
+        }
+    }
+
+    // 16-bit Galois LFSR
+    fn next_noise(&mut self) {
+        let bit = self.lfsr & 1;
+        self.lfsr >>= 1;
+        if bit != 0 {
+            self.lfsr ^= 0xB400;
+        }
+        self.noise = self.lfsr as f32 / u16::MAX as f32 * 2.0 - 1.0;
+    }
+}
+
+// 16-bit mono PCM WAV; the sizes in the header are filled in by finish
+pub struct WavWriter<W: Write + Seek> {
+    out: W,
+    samples: u32,
+}
+
+impl<W: Write + Seek> WavWriter<W> {
+    pub fn new(mut out: W, rate: u32) -> io::Result<Self> {
+        out.write_all(b"RIFF")?;
+        out.write_all(&0u32.to_le_bytes())?;
+        out.write_all(b"WAVEfmt ")?;
+        out.write_all(&16u32.to_le_bytes())?;
+        // PCM, one channel
+        out.write_all(&1u16.to_le_bytes())?;
+        out.write_all(&1u16.to_le_bytes())?;
+        out.write_all(&rate.to_le_bytes())?;
+        // Bytes per second, bytes per sample, bits per sample
+        out.write_all(&(rate * 2).to_le_bytes())?;
+        out.write_all(&2u16.to_le_bytes())?;
+        out.write_all(&16u16.to_le_bytes())?;
+        out.write_all(b"data")?;
+        out.write_all(&0u32.to_le_bytes())?;
+        Ok(Self { out, samples: 0 })
+    }
+
+    // Samples from -1.0 to 1.0
+    pub fn write(&mut self, samples: &[f32]) -> io::Result<()> {
+        for &sample in samples {
+            let value = (sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16;
+            self.out.write_all(&value.to_le_bytes())?;
+        }
+        self.samples += samples.len() as u32;
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> io::Result<W> {
+        let data = self.samples * 2;
+        self.out.seek(SeekFrom::Start(4))?;
+        self.out.write_all(&(36 + data).to_le_bytes())?;
+        self.out.seek(SeekFrom::Start(40))?;
+        self.out.write_all(&data.to_le_bytes())?;
+        self.out.seek(SeekFrom::End(0))?;
+        self.out.flush()?;
+        Ok(self.out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tiny_rates_are_raised() {
+        let mut generator = Generator::new(0);
+        assert_eq!(generator.get_rate(), MIN_RATE);
+        generator.set_rate(1);
+        generator.set_fade(0.0);
+        let mut samples = Vec::new();
+        generator.render_frame(true, &mut samples);
+        assert_eq!(samples, [0.25]);
+    }
+
+    #[test]
+    fn pitch_survives_rate_changes() {
+        let mut generator = Generator::new(44100);
+        generator.set_rate(60);
+        generator.set_rate(48000);
+        assert_eq!(generator.get_frequency(), 440.0);
+        generator.set_frequency(f32::NAN);
+        generator.set_volume(f32::NAN);
+        assert_eq!(generator.get_frequency(), 440.0);
+        assert_eq!(generator.get_volume(), 0.25);
+    }
+
+    #[test]
+    fn square_wave_cycles_at_the_pitch() {
+        let mut generator = Generator::new(800);
+        generator.set_fade(0.0);
+        generator.set_volume(1.0);
+        generator.set_frequency(100.0);
+        generator.set_sound(true);
+        let mut samples = [0.0; 16];
+        generator.fill(&mut samples);
+        assert_eq!(samples[..8], [1.0, 1.0, 1.0, 1.0, -1.0, -1.0, -1.0, -1.0]);
+        assert_eq!(samples[..8], samples[8..]);
+        generator.set_sound(false);
+        generator.fill(&mut samples);
+        assert!(samples.iter().all(|&sample| sample == 0.0));
+    }
+
+    #[test]
+    fn wav_header_has_the_sizes() {
+        let mut wav = WavWriter::new(io::Cursor::new(Vec::new()), 8000).unwrap();
+        wav.write(&[0.0, 1.0, -2.0]).unwrap();
+        let bytes = wav.finish().unwrap().into_inner();
+        assert_eq!(bytes.len(), 44 + 6);
+        assert_eq!(&bytes[..4], b"RIFF");
+        assert_eq!(bytes[4..8], (36u32 + 6).to_le_bytes());
+        assert_eq!(bytes[24..28], 8000u32.to_le_bytes());
+        assert_eq!(&bytes[36..40], b"data");
+        assert_eq!(bytes[40..44], 6u32.to_le_bytes());
+        // Clamped to full scale
+        assert_eq!(bytes[44..], [0, 0, 0xFF, 0x7F, 0x01, 0x80]);
+    }
+}
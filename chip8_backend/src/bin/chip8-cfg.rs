@@ -0,0 +1,29 @@
+use chip8_backend::args::Args;
+use chip8_backend::cfg::build;
+use std::io::{BufWriter, Write};
+
+const USAGE: &str = "Usage: chip8-cfg <rom> [--json] [--output <file>]
+  Write the ROM's control-flow graph as Graphviz DOT, or JSON with --json.
+  Render DOT with: chip8-cfg game.ch8 | dot -Tsvg > game.svg";
+
+fn main() {
+    let mut args = Args::from_env(USAGE);
+    let json = args.flag("--json");
+    let output = args.value::<String>("--output");
+    let args = args.finish(1);
+    let rom = std::fs::read(&args[0]).expect("Failed to read ROM");
+    let cfg = build(&rom);
+
+    let out: Box<dyn Write> = match output {
+        Some(path) => Box::new(std::fs::File::create(path).expect("Failed to create output")),
+        None => Box::new(std::io::stdout().lock()),
+    };
+    let mut out = BufWriter::new(out);
+    if json {
+        cfg.write_json(&mut out)
+    } else {
+        cfg.write_dot(&mut out)
+    }
+    .and_then(|_| out.flush())
+    .expect("Failed to write graph");
+}
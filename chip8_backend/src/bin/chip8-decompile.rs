@@ -0,0 +1,17 @@
+use chip8_backend::decompile::decompile;
+use std::io::{BufWriter, Write};
+use std::process::exit;
+
+// Usage: chip8-decompile <rom>
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.len() != 1 {
+        eprintln!("Usage: chip8-decompile <rom>");
+        exit(2);
+    }
+    let rom = std::fs::read(&args[0]).expect("Failed to read ROM");
+    let mut out = BufWriter::new(std::io::stdout().lock());
+    decompile(&rom, &mut out)
+        .and_then(|_| out.flush())
+        .expect("Failed to write pseudocode");
+}
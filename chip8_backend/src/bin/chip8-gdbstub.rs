@@ -0,0 +1,33 @@
+use chip8_backend::debugger::Debugger;
+use chip8_backend::gdb::GdbStub;
+use chip8_backend::Chip8;
+use std::net::TcpListener;
+
+const DEFAULT_PORT: u16 = 1234;
+
+// Usage: chip8-gdbstub <rom> [port]
+// then in gdb: target remote localhost:1234
+fn main() {
+    let mut args = std::env::args().skip(1);
+    let rom_name = args.next().expect("No ROM provided");
+    let port = args
+        .next()
+        .map(|p| p.parse().expect("Invalid port"))
+        .unwrap_or(DEFAULT_PORT);
+    let rom = std::fs::read(rom_name).expect("Failed to read ROM");
+
+    let mut emu = Chip8::new();
+    emu.load_rom(&rom).unwrap_or_else(|e| panic!("{}", e));
+    let mut debugger = Debugger::new(emu);
+
+    let listener = TcpListener::bind(("127.0.0.1", port)).expect("Failed to bind port");
+    println!("Waiting for debugger on 127.0.0.1:{}", port);
+    let (stream, addr) = listener.accept().expect("Failed to accept connection");
+    println!("Debugger connected from {}", addr);
+
+    GdbStub::new(&mut debugger, stream)
+        .and_then(|mut stub| stub.run())
+        .expect("Debugger connection failed");
+    println!("Debugger disconnected");
+    println!("{}", debugger.chip8());
+}
@@ -0,0 +1,211 @@
+use chip8_backend::args::Args;
+use chip8_backend::audio::{Generator, WavWriter, Waveform};
+use chip8_backend::database;
+use chip8_backend::filter::{Image, Pipeline};
+use chip8_backend::movie::Movie;
+use chip8_backend::phosphor::{Persistence, Phosphor};
+use chip8_backend::quirks::{detect, Overrides, Platform};
+use chip8_backend::record::Recorder;
+use chip8_backend::render::{self, Palette};
+use chip8_backend::Chip8;
+use std::io::{BufWriter, Write};
+use std::process::exit;
+
+const USAGE: &str = "Usage: chip8-headless <rom> [--frames <n>] [--cycles <n>]
+                      [--platform chip8|schip|xochip]
+                      [--quirks <quirk>[=on|off],...]
+                      [--screenshot <file.png>] [--scale <n>]
+                      [--filter <filter>[,<filter>...]]
+                      [--phosphor decay:<fraction>|or:<frames>]
+                      [--movie <file.json>] [--seed <n>]
+                      [--record <file.gif|file.png|file.y4m|->] [--every <n>]
+                      [--record-audio <file.wav>]
+                      [--waveform square|sine|triangle|noise]
+                      [--pitch <Hz>] [--volume <0-1>]
+  Run a ROM without a window for a number of 60 Hz frames (default 60), then
+  print the display unless recording, or save it as a PNG with --screenshot. Instructions per
+  frame come from the ROM database, or --cycles (default 10).
+  --quirks changes the platform's quirks: shift, load_store, jump, vf_reset
+  and clipping.
+  --movie replays input recorded by chip8_sdl, for as many frames as it lasts.
+  --record saves every frame, or one in every n, as a GIF, numbered PNGs or
+  Y4M video (- for stdout, e.g. | ffmpeg -i - demo.mp4).
+  --record-audio saves the sound, lined up with the frames, as 44.1 kHz WAV,
+  with the tone set as for chip8_sdl.";
+
+const FRAMES: u64 = 60;
+const CYCLES_PER_FRAME: u32 = 10;
+const SCALE: usize = 10;
+const SAMPLE_RATE: u32 = 44100;
+
+fn main() {
+    let mut args = Args::from_env(USAGE);
+    let frames = args.value("--frames").unwrap_or(FRAMES);
+    let cycles = args.value::<u32>("--cycles");
+    let platform = args.value::<Platform>("--platform");
+    let overrides = args.value::<Overrides>("--quirks").unwrap_or_default();
+    let screenshot = args.value::<String>("--screenshot");
+    let scale = args.value("--scale").unwrap_or(SCALE);
+    let filters = args.value::<Pipeline>("--filter").unwrap_or_default();
+    let persistence = args.value::<Persistence>("--phosphor");
+    let movie = args.value::<String>("--movie");
+    let seed = args.value::<u64>("--seed");
+    let record = args.value::<String>("--record");
+    let every = args.value("--every").unwrap_or(1);
+    let record_audio = args.value::<String>("--record-audio");
+    let waveform = args.value::<Waveform>("--waveform");
+    let pitch = args.value::<f32>("--pitch");
+    let volume = args.value::<f32>("--volume");
+    if scale == 0 || every == 0 {
+        args.fail("--scale and --every must be at least 1");
+    }
+    if pitch.is_some_and(|hz| !hz.is_finite() || hz <= 0.0)
+        || volume.is_some_and(|level| !(0.0..=1.0).contains(&level))
+    {
+        args.fail("--pitch must be above 0 Hz and --volume from 0 to 1");
+    }
+    let args = args.finish(1);
+    let rom = std::fs::read(&args[0]).expect("Failed to read ROM");
+
+    let metadata = database::lookup(&rom);
+    let palette = metadata
+        .as_ref()
+        .and_then(|m| m.colors.as_ref())
+        .and_then(Palette::from_database)
+        .unwrap_or_default();
+    let movie = movie.map(|path| {
+        let file = std::fs::File::open(path).expect("Failed to open movie");
+        Movie::read(std::io::BufReader::new(file)).unwrap_or_else(|e| {
+            eprintln!("Failed to read movie: {}", e);
+            exit(2);
+        })
+    });
+    let (mut emu, cycles, frames) = match &movie {
+        Some(movie) => {
+            let emu = movie.start(&rom).unwrap_or_else(|e| {
+                eprintln!("{}", e);
+                exit(1);
+            });
+            (emu, movie.cycles_per_frame, movie.frames)
+        }
+        None => {
+            let mut quirks = platform.map_or_else(|| detect(&rom).quirks, |p| p.quirks());
+            overrides.apply(&mut quirks);
+            let cycles = cycles
+                .or_else(|| metadata.as_ref().and_then(|m| m.tickrate))
+                .unwrap_or(CYCLES_PER_FRAME);
+            let mut emu = Chip8::with_quirks(quirks);
+            if let Some(seed) = seed {
+                emu.set_seed(seed);
+            }
+            if let Err(e) = emu.load_rom(&rom) {
+                eprintln!("{}", e);
+                exit(1);
+            }
+            (emu, cycles, frames)
+        }
+    };
+
+    let mut phosphor = persistence.map(Phosphor::new);
+    let mut recorder =
+        record.map(|path| Recorder::create(&path, every).expect("Failed to start recording"));
+    let mut audio = record_audio.map(|path| {
+        let file = BufWriter::new(std::fs::File::create(path).expect("Failed to create WAV"));
+        let wav = WavWriter::new(file, SAMPLE_RATE).expect("Failed to write WAV");
+        let mut generator = Generator::new(SAMPLE_RATE);
+        if let Some(waveform) = waveform {
+            generator.set_waveform(waveform);
+        }
+        if let Some(pitch) = pitch {
+            generator.set_frequency(pitch);
+        }
+        if let Some(volume) = volume {
+            generator.set_volume(volume);
+        }
+        (generator, wav)
+    });
+    let mut samples = Vec::new();
+    let mut frame = vec![0; render::rgba8_len(1)];
+    for n in 0..frames {
+        if let Some(movie) = &movie {
+            movie.apply(n, &mut emu);
+        }
+        for _ in 0..cycles {
+            if let Err(e) = emu.try_cycle() {
+                eprintln!("Stopped in frame {}: {}", n, e);
+                exit(1);
+            }
+        }
+        emu.cycle_timer();
+        if let Some((generator, wav)) = audio.as_mut() {
+            generator.render_frame(emu.get_sound(), &mut samples);
+            wav.write(&samples).expect("Failed to write WAV");
+            samples.clear();
+        }
+        if let Some(phosphor) = phosphor.as_mut() {
+            phosphor.update(emu.get_display());
+        }
+        if let Some(recorder) = recorder.as_mut() {
+            let image = render_frame(
+                &emu,
+                phosphor.as_ref(),
+                &palette,
+                &filters,
+                scale,
+                &mut frame,
+            );
+            recorder.add_frame(&image).expect("Failed to record frame");
+        }
+    }
+    if let Some((_, wav)) = audio {
+        wav.finish().expect("Failed to write WAV");
+    }
+    let recording = recorder.is_some();
+    if let Some(recorder) = recorder {
+        let written = recorder.finish().expect("Failed to finish recording");
+        eprintln!("Recorded {} frames", written);
+    }
+
+    match screenshot {
+        Some(path) => {
+            let file = std::fs::File::create(&path).expect("Failed to create screenshot");
+            render_frame(
+                &emu,
+                phosphor.as_ref(),
+                &palette,
+                &filters,
+                scale,
+                &mut frame,
+            )
+            .write_png(BufWriter::new(file))
+            .expect("Failed to write screenshot");
+        }
+        // Would corrupt a recording going to stdout
+        None if recording => {}
+        None => {
+            let mut out = std::io::stdout().lock();
+            for row in emu.get_display() {
+                let line: String = (0..64)
+                    .map(|x| if row & (1 << 63 >> x) != 0 { '#' } else { '.' })
+                    .collect();
+                writeln!(out, "{}", line).expect("Failed to write display");
+            }
+        }
+    }
+}
+
+// The display, or the phosphor's view of it, filtered and scaled
+fn render_frame(
+    emu: &Chip8,
+    phosphor: Option<&Phosphor>,
+    palette: &Palette,
+    filters: &Pipeline,
+    scale: usize,
+    frame: &mut [u8],
+) -> Image {
+    match phosphor {
+        Some(phosphor) => phosphor.render_rgba8(palette, 1, frame),
+        None => render::render_rgba8(emu.get_display(), palette, 1, frame),
+    }
+    filters.apply(frame, scale)
+}
@@ -0,0 +1,44 @@
+use chip8_backend::args::Args;
+use chip8_backend::lint::lint;
+use chip8_backend::quirks::Platform;
+use std::process::exit;
+
+const USAGE: &str = "Usage: chip8-lint <rom> [--platform chip8|schip|xochip]
+  Check a ROM for likely bugs and platform-specific code without running it.
+  The platform the ROM is meant for defaults to chip8.";
+
+fn main() {
+    let mut args = Args::from_env(USAGE);
+    let declared = args
+        .value::<Platform>("--platform")
+        .unwrap_or(Platform::Chip8);
+    let args = args.finish(1);
+    let rom = std::fs::read(&args[0]).expect("Failed to read ROM");
+
+    let report = lint(&rom, declared);
+    for diagnostic in &report.diagnostics {
+        println!("{}", diagnostic);
+    }
+    let code = report.code.iter().filter(|&&c| c).count();
+    println!("{} of {} ROM bytes reachable as code", code, rom.len());
+
+    let quirks = report.suggested_quirks();
+    println!(
+        "Suggested platform: {} (--platform {})",
+        report.platform,
+        report.platform.name()
+    );
+    println!(
+        "Suggested quirks: shift: {}, load_store: {}, jump: {}, vf_reset: {}, clipping: {}",
+        quirks.shift, quirks.load_store, quirks.jump, quirks.vf_reset, quirks.clipping
+    );
+    if report.quirks.is_empty() {
+        println!("No quirk-dependent code found");
+    } else {
+        println!("Depends on quirks: {}", report.quirks.join(", "));
+    }
+
+    if report.has_warnings() {
+        exit(1);
+    }
+}
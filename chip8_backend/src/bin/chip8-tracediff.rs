@@ -0,0 +1,175 @@
+use chip8_backend::args::Args;
+use chip8_backend::decode::{decode, Instruction};
+use chip8_backend::quirks::{detect, Overrides, Platform};
+use chip8_backend::trace::{TraceState, Tracer};
+use chip8_backend::Chip8;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::process::exit;
+
+const USAGE: &str = "Usage:
+  chip8-tracediff <rom> <reference.jsonl> [--ipf N] [--ignore-timers] [--context N]
+      Run rom and stop at the first instruction where the emulator's state
+      differs from the reference trace.
+  chip8-tracediff --dump <rom> <count> [--ipf N]
+      Write a reference trace of the first count instructions to stdout.
+
+Both take [--platform chip8|schip|xochip] [--quirks <quirk>[=on|off],...] to
+match the emulator that made the reference; the platform is detected from the
+ROM by default. Quirks are shift, load_store, jump, vf_reset and clipping.
+
+The reference format is described in chip8_backend/src/trace.rs.";
+
+// Instructions per 60Hz timer tick, same as the frontends
+const DEFAULT_IPF: u32 = 10;
+const DEFAULT_CONTEXT: usize = 16;
+
+struct Options {
+    ipf: u32,
+    ignore_timers: bool,
+    context: usize,
+}
+
+fn main() {
+    let mut args = Args::from_env(USAGE);
+    let mut options = Options {
+        ipf: DEFAULT_IPF,
+        ignore_timers: false,
+        context: DEFAULT_CONTEXT,
+    };
+    let dump = args.flag("--dump");
+    options.ignore_timers = args.flag("--ignore-timers");
+    if let Some(ipf) = args.value::<u32>("--ipf") {
+        options.ipf = ipf.max(1);
+    }
+    if let Some(context) = args.value("--context") {
+        options.context = context;
+    }
+    let platform = args.value::<Platform>("--platform");
+    let overrides = args.value::<Overrides>("--quirks").unwrap_or_default();
+    let args = args.finish(2);
+
+    let rom = std::fs::read(&args[0]).expect("Failed to read ROM");
+    let mut quirks = platform.map_or_else(|| detect(&rom).quirks, |p| p.quirks());
+    overrides.apply(&mut quirks);
+    let mut emu = Chip8::with_quirks(quirks);
+    if let Err(e) = emu.load_rom(&rom) {
+        eprintln!("{}", e);
+        exit(1);
+    }
+
+    if dump {
+        let count = args[1].parse().expect("Invalid instruction count");
+        dump_trace(&mut emu, count, &options);
+    } else {
+        let reference = std::fs::File::open(&args[1]).expect("Failed to open reference trace");
+        exit(diff_trace(&mut emu, BufReader::new(reference), &options));
+    }
+}
+
+fn dump_trace(emu: &mut Chip8, count: usize, options: &Options) {
+    let mut out = BufWriter::new(std::io::stdout().lock());
+    for n in 0..count {
+        let state = TraceState::capture(emu);
+        serde_json::to_writer(&mut out, &state).expect("Failed to write trace");
+        writeln!(out).expect("Failed to write trace");
+        if let Err(e) = emu.try_cycle() {
+            eprintln!("Stopped after {} instructions: {}", n + 1, e);
+            break;
+        }
+        tick(emu, n, options);
+    }
+}
+
+// Returns the process exit code: 0 if the traces agree, 1 if they diverge
+fn diff_trace(emu: &mut Chip8, reference: impl BufRead, options: &Options) -> i32 {
+    let mut tracer = Tracer::ring_buffer(options.context);
+    let mut last = None;
+    let mut count = 0;
+    for (n, line) in reference.lines().enumerate() {
+        let line = line.expect("Failed to read reference trace");
+        if line.trim().is_empty() {
+            continue;
+        }
+        let mut expected: TraceState = serde_json::from_str(&line)
+            .unwrap_or_else(|e| panic!("Invalid reference trace on line {}: {}", n + 1, e));
+        if options.ignore_timers {
+            expected.dt = None;
+            expected.st = None;
+        }
+        // Random numbers can't match, so take the reference's result for RND
+        if let (Some(Instruction::Rnd(x, _)), Some(v)) = (last, expected.v) {
+            emu.set_register(x, v[x]);
+        }
+
+        let actual = TraceState::capture(emu);
+        let mismatches = expected.mismatches(&actual);
+        if !mismatches.is_empty() {
+            println!("Divergence at instruction {} (line {})", count, n + 1);
+            for (name, expected, actual) in mismatches {
+                println!("  {}: expected 0x{:X}, got 0x{:X}", name, expected, actual);
+            }
+            report(emu, &tracer);
+            return 1;
+        }
+
+        last = decode(actual.opcode.unwrap_or_default());
+        if let Err(e) = tracer.step(emu) {
+            println!("Emulator stopped at instruction {}: {}", count, e);
+            report(emu, &tracer);
+            return 1;
+        }
+        tick(emu, count, options);
+        count += 1;
+    }
+    println!("No divergence in {} instructions", count);
+    0
+}
+
+fn tick(emu: &mut Chip8, n: usize, options: &Options) {
+    if (n + 1).is_multiple_of(options.ipf as usize) {
+        emu.cycle_timer();
+    }
+}
+
+fn report(emu: &Chip8, tracer: &Tracer) {
+    println!();
+    println!("Last instructions:");
+    for record in tracer.records() {
+        let op = match decode(record.opcode) {
+            Some(instruction) => instruction.to_string(),
+            None => "???".to_string(),
+        };
+        let changes: Vec<String> = record
+            .registers
+            .iter()
+            .map(|(x, value)| format!("V{:X}={:02X}", x, value))
+            .chain(
+                record
+                    .writes
+                    .iter()
+                    .map(|(a, v)| format!("[{:03X}]={:02X}", a, v)),
+            )
+            .collect();
+        println!(
+            "  {:04X}  {:04X}  {:<18} {}",
+            record.pc,
+            record.opcode,
+            op,
+            changes.join(" ")
+        );
+    }
+    println!();
+    println!("{}", emu);
+    hexdump("Memory at I", emu, emu.get_i());
+    hexdump("Memory at PC", emu, emu.get_pc());
+}
+
+fn hexdump(title: &str, emu: &Chip8, addr: u16) {
+    let start = (addr as usize & 0xFFF & !0xF).saturating_sub(0x10);
+    let end = (start + 0x30).min(emu.get_memory().len());
+    println!("{} ({:04X}):", title, addr);
+    for (row, bytes) in emu.get_memory()[start..end].chunks(16).enumerate() {
+        let hex: Vec<String> = bytes.iter().map(|b| format!("{:02X}", b)).collect();
+        println!("  {:04X}: {}", start + row * 16, hex.join(" "));
+    }
+}
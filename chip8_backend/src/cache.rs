@@ -0,0 +1,273 @@
+// Block cache, a faster way to run the interpreter
+//
+// Decodes straight-line runs of instructions once and replays them, instead of
+// fetching and decoding every opcode each cycle. Blocks end at anything that
+// changes control flow, at Fx0A (which may not advance) and at memory writes
+// (Fx33, Fx55), after which any block covering the written bytes is dropped so
+// self-modifying code behaves the same as in the interpreter. Results are
+// identical to calling try_cycle the same number of times.
+//
+// Writes made through Chip8 directly (load_rom, get_memory_mut, reset) aren't
+// seen by the cache; call clear after them.
+
+use crate::decode::{decode, Instruction};
+use crate::{Chip8, Error};
+use std::ops::Range;
+
+const MEMORY_SIZE: usize = 4096;
+// Longest block, which also bounds how far back invalidation has to look
+const MAX_BLOCK: usize = 32;
+
+pub struct BlockCache {
+    // Decoded instructions for blocks starting at each address
+    blocks: Vec<Option<Box<[Instruction]>>>,
+    hits: u64,
+    misses: u64,
+}
+
+impl BlockCache {
+    pub fn new() -> Self {
+        Self {
+            blocks: vec![None; MEMORY_SIZE],
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    // Execute exactly `cycles` instructions, stopping early on an error
+    pub fn run(&mut self, chip8: &mut Chip8, cycles: u32) -> Result<(), Error> {
+        let mut remaining = cycles as usize;
+        while remaining > 0 {
+            let pc = chip8.get_pc() as usize;
+            if !self.prepare(chip8, pc) {
+                // Let the interpreter report the error
+                chip8.try_cycle()?;
+                remaining -= 1;
+                continue;
+            }
+            let block = self.blocks[pc].as_deref().unwrap_or_default();
+            let count = block.len().min(remaining);
+            let last = block[count - 1];
+            for &instruction in &block[..count - 1] {
+                chip8.execute_instruction(instruction)?;
+            }
+            // Only the last instruction of a block can write memory
+            let writes = last.memory_writes(chip8.get_i() & 0xFFF);
+            chip8.execute_instruction(last)?;
+            remaining -= count;
+            if let Some(range) = writes {
+                // Writes past the end of memory wrap to 0
+                if range.end > MEMORY_SIZE {
+                    self.invalidate(0..range.end - MEMORY_SIZE);
+                }
+                self.invalidate(range);
+            }
+        }
+        Ok(())
+    }
+
+    // Drop blocks that include any byte in range
+    pub fn invalidate(&mut self, range: Range<usize>) {
+        let from = range.start.saturating_sub(MAX_BLOCK * 2);
+        for start in from..range.end.min(MEMORY_SIZE) {
+            let overlaps = match &self.blocks[start] {
+                Some(block) => start + block.len() * 2 > range.start,
+                None => false,
+            };
+            if overlaps {
+                self.blocks[start] = None;
+            }
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.blocks.fill(None);
+    }
+
+    // Times a block was reused and decoded
+    pub fn stats(&self) -> (u64, u64) {
+        (self.hits, self.misses)
+    }
+
+    // Make sure there's a block at pc, returning false if there can't be one
+    fn prepare(&mut self, chip8: &Chip8, pc: usize) -> bool {
+        if pc >= MEMORY_SIZE {
+            return false;
+        }
+        if self.blocks[pc].is_some() {
+            self.hits += 1;
+            return true;
+        }
+        let block = decode_block(chip8.get_memory(), pc);
+        if block.is_empty() {
+            return false;
+        }
+        self.misses += 1;
+        self.blocks[pc] = Some(block.into_boxed_slice());
+        true
+    }
+}
+
+impl Default for BlockCache {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn decode_block(memory: &[u8], start: usize) -> Vec<Instruction> {
+    let mut block = Vec::new();
+    let mut addr = start;
+    // Stop before the end of memory, where the interpreter wraps the fetch
+    while addr + 1 < MEMORY_SIZE && block.len() < MAX_BLOCK {
+        let opcode = (memory[addr] as u16) << 8 | memory[addr + 1] as u16;
+        let instruction = match decode(opcode) {
+            // Only CHIP-8 instructions run, the others are errors
+            Some(instruction) if instruction.size() == 2 => instruction,
+            _ => break,
+        };
+        block.push(instruction);
+        if ends_block(&instruction) {
+            break;
+        }
+        addr += 2;
+    }
+    block
+}
+
+fn ends_block(instruction: &Instruction) -> bool {
+    use Instruction::*;
+
+    instruction.is_skip()
+        || instruction.memory_writes(0).is_some()
+        || matches!(
+            instruction,
+            Halt | Sys(_) | Ret | Jp(_) | Call(_) | JpV0(_) | LdVxK(_)
+        )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    type State = (
+        [u8; 16],
+        u16,
+        u16,
+        u8,
+        u8,
+        u8,
+        [u16; 16],
+        [u8; 4096],
+        [u64; 32],
+    );
+
+    fn state(chip8: &Chip8) -> State {
+        (
+            *chip8.get_registers(),
+            chip8.get_i(),
+            chip8.get_pc(),
+            chip8.get_sp(),
+            chip8.get_dt(),
+            chip8.get_st(),
+            *chip8.get_stack(),
+            *chip8.get_memory(),
+            *chip8.get_display(),
+        )
+    }
+
+    // Runs rom through try_cycle and through the cache in steps of each
+    // size, checking the two agree after every step
+    fn compare(rom: &[u8], cycles: u32) -> Chip8 {
+        let mut last = None;
+        for step in [1, 3, 7, 32, 100] {
+            let mut interpreter = Chip8::new();
+            interpreter.load_rom(rom).unwrap();
+            let mut cached = Chip8::new();
+            cached.load_rom(rom).unwrap();
+            let mut cache = BlockCache::new();
+            let mut done = 0;
+            while done < cycles {
+                let count = step.min(cycles - done);
+                let mut result = Ok(());
+                for _ in 0..count {
+                    result = interpreter.try_cycle();
+                    if result.is_err() {
+                        break;
+                    }
+                }
+                assert_eq!(cache.run(&mut cached, count), result);
+                assert_eq!(state(&cached), state(&interpreter), "after {}", done);
+                if result.is_err() {
+                    break;
+                }
+                done += count;
+            }
+            last = Some(interpreter);
+        }
+        last.unwrap()
+    }
+
+    fn rom(code: &[(u16, [u8; 2])]) -> Vec<u8> {
+        let end = code
+            .iter()
+            .map(|&(addr, _)| addr as usize + 2)
+            .max()
+            .unwrap();
+        let mut rom = vec![0; end - 0x200];
+        for &(addr, bytes) in code {
+            let offset = addr as usize - 0x200;
+            rom[offset..offset + 2].copy_from_slice(&bytes);
+        }
+        rom
+    }
+
+    #[test]
+    fn self_modifying_code() {
+        let rom = rom(&[
+            (0x200, [0x6B, 0x00]), // LD VB, 00
+            (0x202, [0x7B, 0x01]), // ADD VB, 01
+            (0x204, [0x3B, 0x03]), // SE VB, 03: rewrite on the third pass
+            (0x206, [0x12, 0x0E]), // JP 20E
+            (0x208, [0x60, 0xE6]), // LD V0, 230
+            (0x20A, [0xA2, 0x13]), // LD I, 213
+            (0x20C, [0xF0, 0x33]), // LD B, V0: 213-215 = 2, 3, 0
+            (0x20E, [0x6C, 0x00]), // LD VC, 00
+            (0x210, [0x7C, 0x01]), // ADD VC, 01
+            (0x212, [0x6D, 0x07]), // LD VD, 07, then LD VD, 02
+            (0x214, [0x7E, 0x01]), // ADD VE, 01, then SYS 300
+            (0x216, [0x12, 0x02]), // JP 202
+            (0x300, [0x69, 0x00]), // LD V9, 00
+            (0x302, [0x79, 0x01]), // ADD V9, 01
+            (0x304, [0x39, 0x03]), // SE V9, 03: rewrite on the third pass
+            (0x306, [0x13, 0x10]), // JP 310
+            (0x308, [0x60, 0x13]), // LD V0, 13
+            (0x30A, [0x61, 0x1C]), // LD V1, 1C
+            (0x30C, [0xA3, 0x12]), // LD I, 312
+            (0x30E, [0xF1, 0x55]), // LD [I], V1: 312-313 = JP 31C
+            (0x310, [0x78, 0x01]), // ADD V8, 01
+            (0x312, [0x77, 0x01]), // ADD V7, 01, then JP 31C
+            (0x314, [0x13, 0x02]), // JP 302
+            (0x31C, [0x66, 0xEE]), // LD V6, EE
+            (0x31E, [0x13, 0x1E]), // JP 31E
+        ]);
+        let chip8 = compare(&rom, 200);
+        // Both rewritten blocks ran as rewritten
+        assert_eq!(chip8.get_registers()[0xD], 0x02);
+        assert_eq!(chip8.get_registers()[0x6], 0xEE);
+        assert_eq!(chip8.get_pc(), 0x31E);
+    }
+
+    #[test]
+    fn unsupported_opcode_inside_a_block() {
+        let rom = rom(&[
+            (0x200, [0x60, 0x01]), // LD V0, 01
+            (0x202, [0x61, 0x02]), // LD V1, 02
+            (0x204, [0x00, 0xFF]), // HIGH, SUPER-CHIP only
+            (0x206, [0x62, 0x03]), // LD V2, 03
+            (0x208, [0x12, 0x08]), // JP 208
+        ]);
+        let chip8 = compare(&rom, 10);
+        assert_eq!(chip8.get_pc(), 0x204);
+        assert_eq!(chip8.get_registers()[..3], [1, 2, 0]);
+    }
+}
@@ -0,0 +1,275 @@
+// Control-flow graph of a ROM
+//
+// Basic blocks start at 0x200, at jump, call and skip targets and after any
+// instruction that changes control flow. Blocks end at jumps, skips, calls and
+// returns. Bnnn targets depend on V0 at runtime, so blocks ending in one are
+// marked indirect and have no outgoing edges.
+
+use crate::decode::{decode, Instruction};
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::{self, Write};
+
+const START: usize = 0x200;
+const MEMORY_SIZE: usize = 4096;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EdgeKind {
+    // Falls through to the next instruction, including when a skip isn't taken
+    Next,
+    Jump,
+    // Skip taken
+    Skip,
+    Call,
+    // From a call to the instruction after it, once the subroutine returns
+    Return,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Line {
+    pub addr: u16,
+    pub opcode: u16,
+    pub text: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Block {
+    pub start: u16,
+    // First address after the block
+    pub end: u16,
+    pub lines: Vec<Line>,
+    // Ends in Bnnn, whose targets aren't known
+    pub indirect: bool,
+    // Ends in an opcode that doesn't decode
+    pub invalid: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct Edge {
+    // Start of the block the edge leaves
+    pub from: u16,
+    pub to: u16,
+    pub kind: EdgeKind,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Cfg {
+    pub entry: u16,
+    pub blocks: Vec<Block>,
+    pub edges: Vec<Edge>,
+    // Call targets
+    pub subroutines: BTreeSet<u16>,
+}
+
+pub fn build(rom: &[u8]) -> Cfg {
+    let rom_end = (START + rom.len()).min(MEMORY_SIZE);
+    let mut memory = vec![0; MEMORY_SIZE];
+    memory[START..rom_end].copy_from_slice(&rom[..rom_end - START]);
+    let fetch = |addr: u16| -> u16 {
+        let addr = addr as usize & 0xFFF;
+        (memory[addr] as u16) << 8 | memory[(addr + 1) & 0xFFF] as u16
+    };
+    let in_rom = |addr: u16| (START..rom_end.saturating_sub(1)).contains(&(addr as usize));
+
+    // Find every reachable instruction and where blocks must start
+    let mut instructions: BTreeMap<u16, Option<Instruction>> = BTreeMap::new();
+    let mut leaders = BTreeSet::from([START as u16]);
+    let mut subroutines = BTreeSet::new();
+    let mut queue = vec![START as u16];
+    while let Some(addr) = queue.pop() {
+        if !in_rom(addr) || instructions.contains_key(&addr) {
+            continue;
+        }
+        let instruction = decode(fetch(addr));
+        instructions.insert(addr, instruction);
+        let instruction = match instruction {
+            Some(instruction) => instruction,
+            None => continue,
+        };
+        let (successors, ends_block) = successors(instruction, addr, &fetch);
+        if let Instruction::Call(nnn) = instruction {
+            subroutines.insert(nnn);
+        }
+        for &(target, _) in &successors {
+            queue.push(target);
+            if ends_block {
+                leaders.insert(target);
+            }
+        }
+    }
+
+    // Cut the instructions into blocks at leaders and block-ending instructions
+    let mut blocks = Vec::new();
+    let mut edges = Vec::new();
+    for &start in &leaders {
+        if !instructions.contains_key(&start) {
+            continue;
+        }
+        let mut block = Block {
+            start,
+            end: start,
+            lines: Vec::new(),
+            indirect: false,
+            invalid: false,
+        };
+        let mut addr = start;
+        loop {
+            let instruction = instructions[&addr];
+            let opcode = fetch(addr);
+            block.lines.push(Line {
+                addr,
+                opcode,
+                text: match instruction {
+                    Some(instruction) => instruction.to_string(),
+                    None => format!("??? 0x{:04X}", opcode),
+                },
+            });
+            let instruction = match instruction {
+                Some(instruction) => instruction,
+                None => {
+                    block.invalid = true;
+                    block.end = addr + 2;
+                    break;
+                }
+            };
+            block.end = addr + instruction.size();
+            let (successors, ends_block) = successors(instruction, addr, &fetch);
+            block.indirect = matches!(instruction, Instruction::JpV0(_));
+            let next = block.end;
+            if ends_block || leaders.contains(&next) || !instructions.contains_key(&next) {
+                for (to, kind) in successors {
+                    edges.push(Edge {
+                        from: start,
+                        to,
+                        kind,
+                    });
+                }
+                break;
+            }
+            addr = next;
+        }
+        blocks.push(block);
+    }
+
+    Cfg {
+        entry: START as u16,
+        blocks,
+        edges,
+        subroutines,
+    }
+}
+
+// Where control can go after an instruction, and whether the instruction
+// ends a basic block
+fn successors(
+    instruction: Instruction,
+    addr: u16,
+    fetch: &impl Fn(u16) -> u16,
+) -> (Vec<(u16, EdgeKind)>, bool) {
+    let next = addr.wrapping_add(instruction.size());
+    match instruction {
+        Instruction::Halt | Instruction::Exit | Instruction::Ret | Instruction::JpV0(_) => {
+            (Vec::new(), true)
+        }
+        Instruction::Jp(nnn) => (vec![(nnn, EdgeKind::Jump)], true),
+        Instruction::Call(nnn) => (vec![(nnn, EdgeKind::Call), (next, EdgeKind::Return)], true),
+        _ if instruction.is_skip() => {
+            // Skips the whole of a following F000 nnnn on XO-CHIP
+            let skipped = decode(fetch(next)).map_or(2, |i| i.size());
+            (
+                vec![
+                    (next, EdgeKind::Next),
+                    (next.wrapping_add(skipped), EdgeKind::Skip),
+                ],
+                true,
+            )
+        }
+        _ => (vec![(next, EdgeKind::Next)], false),
+    }
+}
+
+impl Cfg {
+    pub fn write_json(&self, out: &mut impl Write) -> io::Result<()> {
+        serde_json::to_writer_pretty(&mut *out, self)?;
+        writeln!(out)
+    }
+
+    // Graphviz graph with one record per block; render with `dot -Tsvg`
+    pub fn write_dot(&self, out: &mut impl Write) -> io::Result<()> {
+        writeln!(out, "digraph cfg {{")?;
+        writeln!(
+            out,
+            "  node [shape=box, fontname=\"monospace\", fontsize=10];"
+        )?;
+        let starts: BTreeSet<u16> = self.blocks.iter().map(|b| b.start).collect();
+        for block in &self.blocks {
+            let mut label = String::new();
+            if block.start == self.entry {
+                label += "entry\\l";
+            } else if self.subroutines.contains(&block.start) {
+                label += &format!("sub_{:04X}\\l", block.start);
+            }
+            for line in &block.lines {
+                label += &format!("{:04X}  {}\\l", line.addr, escape(&line.text));
+            }
+            let mut style = String::new();
+            if block.indirect {
+                label += "(indirect jump)\\l";
+                style += ", color=red";
+            }
+            if block.invalid {
+                style += ", color=red, style=dashed";
+            }
+            writeln!(
+                out,
+                "  b{:04X} [label=\"{}\"{}];",
+                block.start, label, style
+            )?;
+        }
+        // Targets outside the ROM or in the middle of a block
+        let missing: BTreeSet<u16> = self
+            .edges
+            .iter()
+            .map(|e| e.to)
+            .filter(|to| !starts.contains(to))
+            .collect();
+        for to in missing {
+            writeln!(
+                out,
+                "  b{:04X} [label=\"{:04X}\\l(not in ROM)\\l\", style=dashed];",
+                to, to
+            )?;
+        }
+        for edge in &self.edges {
+            let style = match edge.kind {
+                EdgeKind::Next => "",
+                EdgeKind::Jump => " [label=\"jump\"]",
+                EdgeKind::Skip => " [label=\"skip\", color=blue]",
+                EdgeKind::Call => " [label=\"call\", color=darkgreen]",
+                EdgeKind::Return => " [label=\"return\", style=dotted]",
+            };
+            writeln!(out, "  b{:04X} -> b{:04X}{};", edge.from, edge.to, style)?;
+        }
+        writeln!(out, "}}")
+    }
+}
+
+fn escape(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn skip_at_the_end_of_memory() {
+        let mut rom = vec![0; MEMORY_SIZE - START];
+        // JP FFE, then SE V0, 0 in the last two bytes of memory
+        rom[..2].copy_from_slice(&[0x1F, 0xFE]);
+        rom[0xDFE..].copy_from_slice(&[0x30, 0x00]);
+        let cfg = build(&rom);
+        assert!(cfg.blocks.iter().any(|b| b.start == 0xFFE));
+    }
+}
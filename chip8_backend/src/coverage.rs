@@ -0,0 +1,211 @@
+// Code and data coverage
+//
+// Tracks how each byte of RAM was used while a ROM ran, so testers can see
+// which code paths a play session exercised. Exported as an annotated
+// disassembly or a heatmap PNG with one cell per address, 64 addresses per row:
+// green: executed, blue: read as sprite data (Dxyn),
+// yellow: read by Fx65, red: written by Fx33/Fx55, dark grey: never touched.
+
+use crate::decode::{decode, Instruction};
+use crate::observe::{Observer, Step};
+use crate::{Chip8, Error};
+use std::io::{self, Write};
+
+pub const EXECUTED: u8 = 1 << 0;
+pub const SPRITE: u8 = 1 << 1;
+pub const READ: u8 = 1 << 2;
+pub const WRITTEN: u8 = 1 << 3;
+
+// Heatmap cell size in pixels
+const HEATMAP_SCALE: usize = 8;
+const HEATMAP_COLUMNS: usize = 64;
+
+pub struct Coverage {
+    flags: Vec<u8>,
+}
+
+impl Coverage {
+    pub fn new() -> Self {
+        Self {
+            flags: vec![0; 4096],
+        }
+    }
+
+    // Execute one instruction and record what it touched
+    pub fn step(&mut self, chip8: &mut Chip8) -> Result<(), Error> {
+        chip8.cycle_observed(&mut [self])
+    }
+
+    // Combination of EXECUTED, SPRITE, READ and WRITTEN for each address
+    pub fn flags(&self) -> &[u8] {
+        &self.flags
+    }
+
+    pub fn clear(&mut self) {
+        self.flags.fill(0);
+    }
+
+    // Disassembly of the ROM (and any RAM past it that was touched) with the
+    // flags for each address: X executed, S sprite, R read, W written
+    pub fn write_disassembly(
+        &self,
+        chip8: &Chip8,
+        rom_len: usize,
+        out: &mut impl Write,
+    ) -> io::Result<()> {
+        let start = 0x200;
+        let touched = self
+            .flags
+            .iter()
+            .rposition(|&f| f != 0)
+            .map_or(0, |a| a + 1);
+        let end = (start + rom_len).max(touched).min(self.flags.len());
+        let memory = chip8.get_memory();
+
+        let count = |flag: u8| {
+            self.flags[start..end]
+                .iter()
+                .filter(|&&f| f & flag != 0)
+                .count()
+        };
+        let untouched = self.flags[start..end].iter().filter(|&&f| f == 0).count();
+        writeln!(out, "Range: {:04X}-{:04X}", start, end.max(start + 1) - 1)?;
+        writeln!(out, "Executed: {} bytes", count(EXECUTED))?;
+        writeln!(out, "Sprite data: {} bytes", count(SPRITE))?;
+        writeln!(out, "Read by Fx65: {} bytes", count(READ))?;
+        writeln!(out, "Written by Fx33/Fx55: {} bytes", count(WRITTEN))?;
+        writeln!(out, "Never touched: {} bytes", untouched)?;
+        writeln!(out)?;
+
+        let mut addr = start;
+        while addr < end {
+            let flags = self.flags[addr];
+            if flags == 0 {
+                let run = self.flags[addr..end]
+                    .iter()
+                    .take_while(|&&f| f == 0)
+                    .count();
+                writeln!(
+                    out,
+                    "{:04X}-{:04X}         never touched ({} bytes)",
+                    addr,
+                    addr + run - 1,
+                    run
+                )?;
+                addr += run;
+            } else if flags & EXECUTED != 0 && addr + 1 < memory.len() {
+                let opcode = (memory[addr] as u16) << 8 | memory[addr + 1] as u16;
+                let text = match decode(opcode) {
+                    Some(instruction) => instruction.to_string(),
+                    None => format!("??? 0x{:04X}", opcode),
+                };
+                writeln!(
+                    out,
+                    "{:04X}  {:02X} {:02X}  {}  {}",
+                    addr,
+                    memory[addr],
+                    memory[addr + 1],
+                    flag_letters(flags | self.flags[addr + 1]),
+                    text
+                )?;
+                addr += 2;
+            } else {
+                let byte = memory[addr];
+                write!(
+                    out,
+                    "{:04X}  {:02X}     {}  .byte 0x{:02X}",
+                    addr,
+                    byte,
+                    flag_letters(flags),
+                    byte
+                )?;
+                if flags & SPRITE != 0 {
+                    let bits: String = (0..8)
+                        .map(|bit| if byte & (0x80 >> bit) != 0 { '#' } else { '.' })
+                        .collect();
+                    write!(out, "  {}", bits)?;
+                }
+                writeln!(out)?;
+                addr += 1;
+            }
+        }
+        Ok(())
+    }
+
+    // Heatmap of all 4K as a PNG
+    pub fn write_heatmap(&self, out: impl Write) -> io::Result<()> {
+        let width = HEATMAP_COLUMNS * HEATMAP_SCALE;
+        let height = self.flags.len() / HEATMAP_COLUMNS * HEATMAP_SCALE;
+        let mut pixels = vec![0; width * height * 3];
+        for (y, row) in pixels.chunks_mut(width * 3).enumerate() {
+            for (x, pixel) in row.chunks_mut(3).enumerate() {
+                let addr = y / HEATMAP_SCALE * HEATMAP_COLUMNS + x / HEATMAP_SCALE;
+                pixel.copy_from_slice(&heat_colour(self.flags[addr]));
+            }
+        }
+        let mut encoder = png::Encoder::new(out, width as u32, height as u32);
+        encoder.set_color(png::ColorType::Rgb);
+        encoder.set_depth(png::BitDepth::Eight);
+        encoder
+            .write_header()
+            .and_then(|mut writer| writer.write_image_data(&pixels))
+            .map_err(io::Error::other)
+    }
+}
+
+impl Observer for Coverage {
+    fn observe(&mut self, step: &Step, _chip8: &Chip8) {
+        if step.error.is_some() {
+            return;
+        }
+        let pc = step.pc as usize & 0xFFF;
+        self.flags[pc] |= EXECUTED;
+        self.flags[(pc + 1) & 0xFFF] |= EXECUTED;
+        let instruction = match step.instruction {
+            Some(instruction) => instruction,
+            None => return,
+        };
+        let (range, flag) = match instruction {
+            Instruction::Drw(..) => (instruction.memory_reads(step.i), SPRITE),
+            Instruction::LdVxI(_) => (instruction.memory_reads(step.i), READ),
+            _ => (instruction.memory_writes(step.i), WRITTEN),
+        };
+        for addr in range.into_iter().flatten() {
+            self.flags[addr & 0xFFF] |= flag;
+        }
+    }
+}
+
+impl Default for Coverage {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn flag_letters(flags: u8) -> String {
+    [(EXECUTED, 'X'), (SPRITE, 'S'), (READ, 'R'), (WRITTEN, 'W')]
+        .iter()
+        .map(|&(flag, letter)| if flags & flag != 0 { letter } else { '-' })
+        .collect()
+}
+
+fn heat_colour(flags: u8) -> [u8; 3] {
+    if flags == 0 {
+        return [0x20, 0x20, 0x20];
+    }
+    let mut colour = [0u8; 3];
+    let layers = [
+        (EXECUTED, [0x00, 0xC8, 0x00]),
+        (SPRITE, [0x00, 0x50, 0xFF]),
+        (READ, [0xC8, 0xC8, 0x00]),
+        (WRITTEN, [0xFF, 0x00, 0x00]),
+    ];
+    for (flag, layer) in layers {
+        if flags & flag != 0 {
+            for (c, l) in colour.iter_mut().zip(layer) {
+                *c = c.saturating_add(l);
+            }
+        }
+    }
+    colour
+}
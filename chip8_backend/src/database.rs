@@ -0,0 +1,233 @@
+// Known ROMs, keyed by the SHA-1 of the ROM file
+//
+// Uses the layout of https://github.com/chip-8/chip-8-database: programs.json
+// lists programs and the ROM files belonging to them, sha1-hashes.json maps
+// each ROM's hash to its program's index. src/database/update.sh downloads
+// the latest copies of both.
+
+use crate::quirks::{Platform, Quirks};
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::OnceLock;
+
+const PROGRAMS: &str = include_str!("database/programs.json");
+const HASHES: &str = include_str!("database/sha1-hashes.json");
+
+#[derive(Debug, Deserialize)]
+struct Program {
+    title: String,
+    #[serde(default)]
+    description: Option<String>,
+    #[serde(default)]
+    release: Option<String>,
+    #[serde(default)]
+    authors: Vec<String>,
+    #[serde(default)]
+    roms: HashMap<String, Rom>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Rom {
+    #[serde(default)]
+    platforms: Vec<String>,
+    // Platform id to quirks that differ from that platform's defaults
+    #[serde(default)]
+    quirky_platforms: HashMap<String, HashMap<String, bool>>,
+    #[serde(default)]
+    tickrate: Option<u32>,
+    #[serde(default)]
+    keys: BTreeMap<String, u8>,
+    #[serde(default)]
+    colors: Option<Colors>,
+}
+
+struct Database {
+    programs: Vec<Program>,
+    hashes: HashMap<String, usize>,
+}
+
+// Colours as "#rrggbb" strings
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct Colors {
+    // Background then foreground; XO-CHIP ROMs list one per plane combination
+    #[serde(default)]
+    pub pixels: Vec<String>,
+    #[serde(default)]
+    pub buzzer: Option<String>,
+    #[serde(default)]
+    pub silence: Option<String>,
+}
+
+// What the database knows about a ROM
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Metadata {
+    pub title: String,
+    pub description: Option<String>,
+    // Usually a year
+    pub release: Option<String>,
+    pub authors: Vec<String>,
+    // First platform listed for the ROM that we know, with its quirks
+    pub platform: Option<(Platform, Quirks)>,
+    // Instructions per frame
+    pub tickrate: Option<u32>,
+    // Game action ("up", "a", ...) to CHIP-8 key
+    pub keys: BTreeMap<String, u8>,
+    pub colors: Option<Colors>,
+}
+
+pub fn sha1(rom: &[u8]) -> String {
+    sha1_smol::Sha1::from(rom).digest().to_string()
+}
+
+pub fn lookup(rom: &[u8]) -> Option<Metadata> {
+    lookup_sha1(&sha1(rom))
+}
+
+pub fn lookup_sha1(hash: &str) -> Option<Metadata> {
+    database().lookup_sha1(hash)
+}
+
+impl Database {
+    fn parse(programs: &str, hashes: &str) -> serde_json::Result<Self> {
+        Ok(Database {
+            programs: serde_json::from_str(programs)?,
+            hashes: serde_json::from_str(hashes)?,
+        })
+    }
+
+    fn lookup_sha1(&self, hash: &str) -> Option<Metadata> {
+        let hash = hash.to_ascii_lowercase();
+        let program = self.programs.get(*self.hashes.get(&hash)?)?;
+        let rom = program.roms.get(&hash)?;
+        // Platforms are listed in order of preference; use the first one we have
+        let platform = rom.platforms.iter().find_map(|id| {
+            let platform = platform_for(id)?;
+            let mut quirks = platform.quirks();
+            if let Some(overrides) = rom.quirky_platforms.get(id) {
+                apply_overrides(&mut quirks, overrides);
+            }
+            Some((platform, quirks))
+        });
+        Some(Metadata {
+            title: program.title.clone(),
+            description: program.description.clone(),
+            release: program.release.clone(),
+            authors: program.authors.clone(),
+            platform,
+            tickrate: rom.tickrate,
+            keys: rom.keys.clone(),
+            colors: rom.colors.clone(),
+        })
+    }
+}
+
+impl Metadata {
+    // "Title (release) by authors", leaving out what's unknown
+    pub fn display_name(&self) -> String {
+        let mut name = self.title.clone();
+        if let Some(release) = &self.release {
+            name += &format!(" ({})", release);
+        }
+        if !self.authors.is_empty() {
+            name += &format!(" by {}", self.authors.join(", "));
+        }
+        name
+    }
+}
+
+fn database() -> &'static Database {
+    static DATABASE: OnceLock<Database> = OnceLock::new();
+    DATABASE.get_or_init(|| {
+        Database::parse(PROGRAMS, HASHES).expect("Invalid programs.json or sha1-hashes.json")
+    })
+}
+
+// chip-8-database platform ids
+fn platform_for(id: &str) -> Option<Platform> {
+    match id {
+        "originalChip8" | "hybridVIP" | "modernChip8" => Some(Platform::Chip8),
+        "chip48" | "superchip1" | "superchip" => Some(Platform::SuperChip),
+        "xochip" => Some(Platform::XoChip),
+        _ => None,
+    }
+}
+
+// chip-8-database quirk names, see its quirks.json
+fn apply_overrides(quirks: &mut Quirks, overrides: &HashMap<String, bool>) {
+    for (name, &value) in overrides {
+        match name.as_str() {
+            "shift" => quirks.shift = value,
+            "memoryLeaveIUnchanged" => quirks.load_store = value,
+            "jump" => quirks.jump = value,
+            "logic" => quirks.vf_reset = value,
+            "wrap" => quirks.clipping = !value,
+            _ => {}
+        }
+    }
+}
+
+// Parse "#rrggbb" into its components
+pub fn parse_color(color: &str) -> Option<(u8, u8, u8)> {
+    let hex = color.strip_prefix('#')?;
+    if hex.len() != 6 {
+        return None;
+    }
+    let value = u32::from_str_radix(hex, 16).ok()?;
+    Some(((value >> 16) as u8, (value >> 8) as u8, value as u8))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn looks_up_platform_and_quirks() {
+        let rom = [0x00, 0xE0, 0x12, 0x02];
+        let hash = sha1(&rom);
+        let programs = format!(
+            r#"[{{
+                "title": "Test",
+                "release": "2024",
+                "authors": ["A", "B"],
+                "roms": {{
+                    "{}": {{
+                        "platforms": ["megachip8", "superchip", "originalChip8"],
+                        "quirkyPlatforms": {{ "superchip": {{ "shift": false, "wrap": true }} }},
+                        "tickrate": 30
+                    }}
+                }}
+            }}]"#,
+            hash
+        );
+        let hashes = format!(r#"{{ "{}": 0 }}"#, hash);
+        let database = Database::parse(&programs, &hashes).unwrap();
+
+        let metadata = database.lookup_sha1(&hash.to_ascii_uppercase()).unwrap();
+        assert_eq!(metadata.display_name(), "Test (2024) by A, B");
+        assert_eq!(metadata.tickrate, Some(30));
+        // megachip8 isn't supported, so the first platform we have is used
+        let (platform, quirks) = metadata.platform.unwrap();
+        assert_eq!(platform, Platform::SuperChip);
+        assert_eq!(
+            quirks,
+            Quirks {
+                shift: false,
+                clipping: false,
+                ..Platform::SuperChip.quirks()
+            }
+        );
+        assert!(database.lookup_sha1(&sha1(&[0x12, 0x00])).is_none());
+    }
+
+    #[test]
+    fn shipped_hashes_are_found() {
+        let database = database();
+        for (hash, &index) in &database.hashes {
+            let metadata = database
+                .lookup_sha1(hash)
+                .unwrap_or_else(|| panic!("{} is missing from program {}", hash, index));
+            assert_eq!(metadata.title, database.programs[index].title);
+        }
+    }
+}
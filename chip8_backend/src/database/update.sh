@@ -0,0 +1,9 @@
+#!/bin/sh
+# Replaces programs.json and sha1-hashes.json with the CHIP-8 database's
+# latest copies
+set -e
+cd "$(dirname "$0")"
+base=https://raw.githubusercontent.com/chip-8/chip-8-database/master/database
+for file in programs.json sha1-hashes.json; do
+	curl -fsSL "$base/$file" -o "$file"
+done
@@ -0,0 +1,113 @@
+use crate::{Chip8, Error};
+use std::collections::BTreeSet;
+
+// Instructions executed per 60Hz timer tick, same as the frontends
+const CYCLES_PER_FRAME: u32 = 10;
+
+// Why execution stopped
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopReason {
+    // A single step finished
+    Step,
+    // PC reached a breakpoint at this address
+    Breakpoint(u16),
+    // The instruction at PC failed
+    Error(Error),
+}
+
+// Wraps a Chip8 with breakpoints and stepping, and keeps the timers running at
+// the same rate as the frontends while stepping through code.
+pub struct Debugger {
+    chip8: Chip8,
+    breakpoints: BTreeSet<u16>,
+    cycles_per_frame: u32,
+    cycles: u32,
+    // Breakpoint we last stopped on, so resuming steps off it
+    stopped_at: Option<u16>,
+}
+
+impl Debugger {
+    pub fn new(chip8: Chip8) -> Self {
+        Self {
+            chip8,
+            breakpoints: BTreeSet::new(),
+            cycles_per_frame: CYCLES_PER_FRAME,
+            cycles: 0,
+            stopped_at: None,
+        }
+    }
+
+    pub fn chip8(&self) -> &Chip8 {
+        &self.chip8
+    }
+
+    pub fn chip8_mut(&mut self) -> &mut Chip8 {
+        &mut self.chip8
+    }
+
+    pub fn set_cycles_per_frame(&mut self, cycles: u32) {
+        self.cycles_per_frame = cycles.max(1);
+    }
+
+    // Returns false if there was already a breakpoint at addr
+    pub fn add_breakpoint(&mut self, addr: u16) -> bool {
+        self.breakpoints.insert(addr)
+    }
+
+    // Returns false if there was no breakpoint at addr
+    pub fn remove_breakpoint(&mut self, addr: u16) -> bool {
+        self.breakpoints.remove(&addr)
+    }
+
+    pub fn clear_breakpoints(&mut self) {
+        self.breakpoints.clear();
+    }
+
+    pub fn breakpoints(&self) -> impl Iterator<Item = u16> + '_ {
+        self.breakpoints.iter().copied()
+    }
+
+    // Execute exactly one instruction, ignoring breakpoints
+    pub fn step(&mut self) -> StopReason {
+        self.stopped_at = None;
+        if let Err(e) = self.chip8.try_cycle() {
+            return StopReason::Error(e);
+        }
+        self.cycles += 1;
+        if self.cycles >= self.cycles_per_frame {
+            self.cycles = 0;
+            self.chip8.cycle_timer();
+        }
+        StopReason::Step
+    }
+
+    // Run until a breakpoint or error, executing at most max_cycles
+    // instructions. Resuming from the breakpoint we last stopped on runs its
+    // instruction instead of stopping again. Returns None if the budget ran
+    // out first, so callers can poll for input between slices.
+    pub fn resume(&mut self, max_cycles: u32) -> Option<StopReason> {
+        self.resume_until(max_cycles, |_| false)
+    }
+
+    // Like resume, but also stops with StopReason::Step once done returns
+    // true after an instruction. Used for step over/out.
+    pub fn resume_until<F>(&mut self, max_cycles: u32, mut done: F) -> Option<StopReason>
+    where
+        F: FnMut(&Chip8) -> bool,
+    {
+        for _ in 0..max_cycles {
+            let pc = self.chip8.get_pc();
+            if self.breakpoints.contains(&pc) && self.stopped_at != Some(pc) {
+                self.stopped_at = Some(pc);
+                return Some(StopReason::Breakpoint(pc));
+            }
+            if let StopReason::Error(e) = self.step() {
+                return Some(StopReason::Error(e));
+            }
+            if done(&self.chip8) {
+                return Some(StopReason::Step);
+            }
+        }
+        None
+    }
+}
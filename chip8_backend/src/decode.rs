@@ -0,0 +1,309 @@
+use crate::quirks::Platform;
+use std::fmt::Display;
+use std::ops::Range;
+
+// A decoded instruction, named after the mnemonics in Cowgod's reference
+// http://devernay.free.fr/hacks/chip8/C8TECH10.HTM
+// SUPER-CHIP and XO-CHIP instructions are decoded so tools can recognise
+// them, but the interpreter only runs CHIP-8.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Instruction {
+    // 0000: stays at the same address forever
+    Halt,
+    // 00E0
+    Cls,
+    // 00EE
+    Ret,
+    // 0nnn
+    Sys(u16),
+    // 1nnn
+    Jp(u16),
+    // 2nnn
+    Call(u16),
+    // 3xkk
+    SeByte(usize, u8),
+    // 4xkk
+    SneByte(usize, u8),
+    // 5xy0
+    SeReg(usize, usize),
+    // 6xkk
+    LdByte(usize, u8),
+    // 7xkk
+    AddByte(usize, u8),
+    // 8xy0
+    LdReg(usize, usize),
+    // 8xy1
+    Or(usize, usize),
+    // 8xy2
+    And(usize, usize),
+    // 8xy3
+    Xor(usize, usize),
+    // 8xy4
+    AddReg(usize, usize),
+    // 8xy5
+    Sub(usize, usize),
+    // 8xy6
+    Shr(usize, usize),
+    // 8xy7
+    Subn(usize, usize),
+    // 8xyE
+    Shl(usize, usize),
+    // 9xy0
+    SneReg(usize, usize),
+    // Annn
+    LdI(u16),
+    // Bnnn
+    JpV0(u16),
+    // Cxkk
+    Rnd(usize, u8),
+    // Dxyn
+    Drw(usize, usize, u16),
+    // Ex9E
+    Skp(usize),
+    // ExA1
+    Sknp(usize),
+    // Fx07
+    LdVxDt(usize),
+    // Fx0A
+    LdVxK(usize),
+    // Fx15
+    LdDtVx(usize),
+    // Fx18
+    LdStVx(usize),
+    // Fx1E
+    AddI(usize),
+    // Fx29
+    LdF(usize),
+    // Fx33
+    LdB(usize),
+    // Fx55
+    LdIVx(usize),
+    // Fx65
+    LdVxI(usize),
+
+    // SUPER-CHIP
+    // 00Cn
+    ScrollDown(u8),
+    // 00FB
+    ScrollRight,
+    // 00FC
+    ScrollLeft,
+    // 00FD
+    Exit,
+    // 00FE
+    Low,
+    // 00FF
+    High,
+    // Fx30
+    LdHf(usize),
+    // Fx75
+    LdRVx(usize),
+    // Fx85
+    LdVxR(usize),
+
+    // XO-CHIP
+    // 00Dn
+    ScrollUp(u8),
+    // 5xy2
+    Save(usize, usize),
+    // 5xy3
+    Load(usize, usize),
+    // F000 nnnn, the address is in the following word
+    LdILong,
+    // Fn01
+    Plane(u8),
+    // F002
+    Audio,
+    // Fx3A
+    Pitch(usize),
+}
+
+pub fn decode(opcode: u16) -> Option<Instruction> {
+    use Instruction::*;
+
+    let nnn = opcode & 0x0FFF;
+    let kk = (opcode & 0x00FF) as u8;
+    let nibbles = (
+        ((opcode & 0xF000) >> 12) as u8,
+        ((opcode & 0x0F00) >> 8) as u8,
+        ((opcode & 0x00F0) >> 4) as u8,
+        (opcode & 0x000F) as u8,
+    );
+    let x = nibbles.1 as usize;
+    let y = nibbles.2 as usize;
+    let n = nibbles.3 as u16;
+
+    let instruction = match nibbles {
+        (0x0, 0x0, 0x0, 0x0) => Halt,
+        (0x0, 0x0, 0xE, 0x0) => Cls,
+        (0x0, 0x0, 0xE, 0xE) => Ret,
+        (0x0, 0x0, 0xC, n) => ScrollDown(n),
+        (0x0, 0x0, 0xD, n) => ScrollUp(n),
+        (0x0, 0x0, 0xF, 0xB) => ScrollRight,
+        (0x0, 0x0, 0xF, 0xC) => ScrollLeft,
+        (0x0, 0x0, 0xF, 0xD) => Exit,
+        (0x0, 0x0, 0xF, 0xE) => Low,
+        (0x0, 0x0, 0xF, 0xF) => High,
+        (0x0, _, _, _) => Sys(nnn),
+        (0x1, _, _, _) => Jp(nnn),
+        (0x2, _, _, _) => Call(nnn),
+        (0x3, _, _, _) => SeByte(x, kk),
+        (0x4, _, _, _) => SneByte(x, kk),
+        (0x5, _, _, 0x0) => SeReg(x, y),
+        (0x5, _, _, 0x2) => Save(x, y),
+        (0x5, _, _, 0x3) => Load(x, y),
+        (0x6, _, _, _) => LdByte(x, kk),
+        (0x7, _, _, _) => AddByte(x, kk),
+        (0x8, _, _, 0x0) => LdReg(x, y),
+        (0x8, _, _, 0x1) => Or(x, y),
+        (0x8, _, _, 0x2) => And(x, y),
+        (0x8, _, _, 0x3) => Xor(x, y),
+        (0x8, _, _, 0x4) => AddReg(x, y),
+        (0x8, _, _, 0x5) => Sub(x, y),
+        (0x8, _, _, 0x6) => Shr(x, y),
+        (0x8, _, _, 0x7) => Subn(x, y),
+        (0x8, _, _, 0xE) => Shl(x, y),
+        (0x9, _, _, 0x0) => SneReg(x, y),
+        (0xA, _, _, _) => LdI(nnn),
+        (0xB, _, _, _) => JpV0(nnn),
+        (0xC, _, _, _) => Rnd(x, kk),
+        (0xD, _, _, _) => Drw(x, y, n),
+        (0xE, _, 0x9, 0xE) => Skp(x),
+        (0xE, _, 0xA, 0x1) => Sknp(x),
+        (0xF, 0x0, 0x0, 0x0) => LdILong,
+        (0xF, _, 0x0, 0x1) => Plane(nibbles.1),
+        (0xF, 0x0, 0x0, 0x2) => Audio,
+        (0xF, _, 0x0, 0x7) => LdVxDt(x),
+        (0xF, _, 0x0, 0xA) => LdVxK(x),
+        (0xF, _, 0x1, 0x5) => LdDtVx(x),
+        (0xF, _, 0x1, 0x8) => LdStVx(x),
+        (0xF, _, 0x1, 0xE) => AddI(x),
+        (0xF, _, 0x2, 0x9) => LdF(x),
+        (0xF, _, 0x3, 0x0) => LdHf(x),
+        (0xF, _, 0x3, 0x3) => LdB(x),
+        (0xF, _, 0x3, 0xA) => Pitch(x),
+        (0xF, _, 0x5, 0x5) => LdIVx(x),
+        (0xF, _, 0x6, 0x5) => LdVxI(x),
+        (0xF, _, 0x7, 0x5) => LdRVx(x),
+        (0xF, _, 0x8, 0x5) => LdVxR(x),
+        _ => return None,
+    };
+    Some(instruction)
+}
+
+impl Instruction {
+    // The first platform that has this instruction
+    pub fn platform(&self) -> Platform {
+        use Instruction::*;
+
+        match *self {
+            ScrollDown(_) | ScrollRight | ScrollLeft | Exit | Low | High | LdHf(_) | LdRVx(_)
+            | LdVxR(_) => Platform::SuperChip,
+            // 16x16 sprites; on CHIP-8 Dxy0 draws nothing
+            Drw(_, _, 0) => Platform::SuperChip,
+            ScrollUp(_) | Save(..) | Load(..) | LdILong | Plane(_) | Audio | Pitch(_) => {
+                Platform::XoChip
+            }
+            _ => Platform::Chip8,
+        }
+    }
+
+    // Size in bytes, including F000's address word
+    pub fn size(&self) -> u16 {
+        match self {
+            Instruction::LdILong => 4,
+            _ => 2,
+        }
+    }
+
+    // 3xkk, 4xkk, 5xy0, 9xy0, Ex9E and ExA1 conditionally skip the next instruction
+    pub fn is_skip(&self) -> bool {
+        use Instruction::*;
+
+        matches!(
+            self,
+            SeByte(..) | SneByte(..) | SeReg(..) | SneReg(..) | Skp(_) | Sknp(_)
+        )
+    }
+
+    // Memory this instruction writes, given the current value of I
+    pub fn memory_writes(&self, i: u16) -> Option<Range<usize>> {
+        let i = i as usize;
+        match *self {
+            Instruction::LdB(_) => Some(i..i + 3),
+            Instruction::LdIVx(x) => Some(i..i + x + 1),
+            _ => None,
+        }
+    }
+
+    // Memory this instruction reads as data, given the current value of I
+    pub fn memory_reads(&self, i: u16) -> Option<Range<usize>> {
+        let i = i as usize;
+        match *self {
+            Instruction::Drw(_, _, n) => Some(i..i + n as usize),
+            Instruction::LdVxI(x) => Some(i..i + x + 1),
+            _ => None,
+        }
+    }
+}
+
+impl Display for Instruction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        use Instruction::*;
+
+        match *self {
+            Halt => write!(f, "HALT"),
+            Cls => write!(f, "CLS"),
+            Ret => write!(f, "RET"),
+            Sys(nnn) => write!(f, "SYS 0x{:03X}", nnn),
+            Jp(nnn) => write!(f, "JP 0x{:03X}", nnn),
+            Call(nnn) => write!(f, "CALL 0x{:03X}", nnn),
+            SeByte(x, kk) => write!(f, "SE V{:X}, 0x{:02X}", x, kk),
+            SneByte(x, kk) => write!(f, "SNE V{:X}, 0x{:02X}", x, kk),
+            SeReg(x, y) => write!(f, "SE V{:X}, V{:X}", x, y),
+            LdByte(x, kk) => write!(f, "LD V{:X}, 0x{:02X}", x, kk),
+            AddByte(x, kk) => write!(f, "ADD V{:X}, 0x{:02X}", x, kk),
+            LdReg(x, y) => write!(f, "LD V{:X}, V{:X}", x, y),
+            Or(x, y) => write!(f, "OR V{:X}, V{:X}", x, y),
+            And(x, y) => write!(f, "AND V{:X}, V{:X}", x, y),
+            Xor(x, y) => write!(f, "XOR V{:X}, V{:X}", x, y),
+            AddReg(x, y) => write!(f, "ADD V{:X}, V{:X}", x, y),
+            Sub(x, y) => write!(f, "SUB V{:X}, V{:X}", x, y),
+            Shr(x, y) => write!(f, "SHR V{:X}, V{:X}", x, y),
+            Subn(x, y) => write!(f, "SUBN V{:X}, V{:X}", x, y),
+            Shl(x, y) => write!(f, "SHL V{:X}, V{:X}", x, y),
+            SneReg(x, y) => write!(f, "SNE V{:X}, V{:X}", x, y),
+            LdI(nnn) => write!(f, "LD I, 0x{:03X}", nnn),
+            JpV0(nnn) => write!(f, "JP V0, 0x{:03X}", nnn),
+            Rnd(x, kk) => write!(f, "RND V{:X}, 0x{:02X}", x, kk),
+            Drw(x, y, n) => write!(f, "DRW V{:X}, V{:X}, {}", x, y, n),
+            Skp(x) => write!(f, "SKP V{:X}", x),
+            Sknp(x) => write!(f, "SKNP V{:X}", x),
+            LdVxDt(x) => write!(f, "LD V{:X}, DT", x),
+            LdVxK(x) => write!(f, "LD V{:X}, K", x),
+            LdDtVx(x) => write!(f, "LD DT, V{:X}", x),
+            LdStVx(x) => write!(f, "LD ST, V{:X}", x),
+            AddI(x) => write!(f, "ADD I, V{:X}", x),
+            LdF(x) => write!(f, "LD F, V{:X}", x),
+            LdB(x) => write!(f, "LD B, V{:X}", x),
+            LdIVx(x) => write!(f, "LD [I], V{:X}", x),
+            LdVxI(x) => write!(f, "LD V{:X}, [I]", x),
+            ScrollDown(n) => write!(f, "SCD {}", n),
+            ScrollRight => write!(f, "SCR"),
+            ScrollLeft => write!(f, "SCL"),
+            Exit => write!(f, "EXIT"),
+            Low => write!(f, "LOW"),
+            High => write!(f, "HIGH"),
+            LdHf(x) => write!(f, "LD HF, V{:X}", x),
+            LdRVx(x) => write!(f, "LD R, V{:X}", x),
+            LdVxR(x) => write!(f, "LD V{:X}, R", x),
+            ScrollUp(n) => write!(f, "SCU {}", n),
+            Save(x, y) => write!(f, "SAVE V{:X}-V{:X}", x, y),
+            Load(x, y) => write!(f, "LOAD V{:X}-V{:X}", x, y),
+            LdILong => write!(f, "LD I, LONG"),
+            Plane(n) => write!(f, "PLANE {}", n),
+            Audio => write!(f, "AUDIO"),
+            Pitch(x) => write!(f, "PITCH V{:X}", x),
+        }
+    }
+}
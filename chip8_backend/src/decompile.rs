@@ -0,0 +1,663 @@
+// Decompiler to structured pseudocode
+//
+// Works on the control-flow graph: each call target becomes a function, and
+// within a function the usual CHIP-8 patterns are turned back into structure:
+//   skip; JP forward        if (...) { ... }, with an else if the body ends in
+//                           another forward JP
+//   skip; instruction       if (...) instruction
+//   JP backward             while (true) { ... }, or do { ... } while (...)
+//                           when the jump is the target of a skip
+// Anything else becomes a goto. A few idioms are recognised as well: BCD
+// conversion (Fx33 then Fx65), font digits (Fx29), and sprites and sprite
+// tables (Annn that reaches a Dxyn, possibly through Fx1E).
+
+use crate::cfg::{self, Cfg, EdgeKind};
+use crate::decode::{decode, Instruction};
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::{self, Write};
+
+const START: u16 = 0x200;
+const INDENT: &str = "    ";
+
+// What Annn points at, found by looking at how I is used afterwards
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Data {
+    // Drawn directly, with the tallest height used
+    Sprite(u16),
+    // Indexed with Fx1E before drawing
+    SpriteTable(u16),
+    Other,
+}
+
+// Loop the code being emitted is in, for break and continue
+#[derive(Debug, Clone, Copy)]
+struct Scope {
+    head: Option<u16>,
+    exit: Option<u16>,
+}
+
+struct Line {
+    addr: u16,
+    indent: usize,
+    text: String,
+}
+
+struct Decompiler {
+    memory: Vec<u8>,
+    instructions: BTreeMap<u16, Instruction>,
+    data: BTreeMap<u16, Data>,
+    lines: Vec<Line>,
+    // Targets of emitted gotos
+    labels: BTreeSet<u16>,
+}
+
+pub fn decompile(rom: &[u8], out: &mut impl Write) -> io::Result<()> {
+    let cfg = cfg::build(rom);
+    let mut memory = vec![0; 4096];
+    let end = (START as usize + rom.len()).min(memory.len());
+    memory[START as usize..end].copy_from_slice(&rom[..end - START as usize]);
+    let instructions = cfg
+        .blocks
+        .iter()
+        .flat_map(|b| &b.lines)
+        .filter_map(|line| Some((line.addr, decode(line.opcode)?)))
+        .collect();
+    let mut decompiler = Decompiler {
+        memory,
+        instructions,
+        data: BTreeMap::new(),
+        lines: Vec::new(),
+        labels: BTreeSet::new(),
+    };
+    decompiler.find_data();
+
+    let mut entries = vec![cfg.entry];
+    entries.extend(cfg.subroutines.iter().filter(|&&s| s != cfg.entry));
+    for entry in entries {
+        let body = function_body(&cfg, entry);
+        if body.is_empty() {
+            continue;
+        }
+        decompiler.function(entry, &body, out)?;
+    }
+    decompiler.write_data(out)
+}
+
+// Addresses of the instructions reachable from entry without following calls
+fn function_body(cfg: &Cfg, entry: u16) -> BTreeSet<u16> {
+    let blocks: BTreeMap<u16, &cfg::Block> = cfg.blocks.iter().map(|b| (b.start, b)).collect();
+    let mut seen = BTreeSet::new();
+    let mut queue = vec![entry];
+    while let Some(start) = queue.pop() {
+        if !blocks.contains_key(&start) || !seen.insert(start) {
+            continue;
+        }
+        for edge in &cfg.edges {
+            if edge.from == start && edge.kind != EdgeKind::Call {
+                queue.push(edge.to);
+            }
+        }
+    }
+    seen.iter()
+        .flat_map(|start| blocks[start].lines.iter().map(|line| line.addr))
+        .collect()
+}
+
+impl Decompiler {
+    fn function(
+        &mut self,
+        entry: u16,
+        body: &BTreeSet<u16>,
+        out: &mut impl Write,
+    ) -> io::Result<()> {
+        self.lines.clear();
+        self.labels.clear();
+
+        // Contiguous runs of code, starting with the one holding the entry
+        let mut runs: Vec<(u16, u16)> = Vec::new();
+        for &addr in body {
+            let end = addr + self.instructions.get(&addr).map_or(2, |i| i.size());
+            match runs.last_mut() {
+                Some(run) if run.1 == addr => run.1 = end,
+                _ => runs.push((addr, end)),
+            }
+        }
+        runs.sort_by_key(|&(start, end)| !(start <= entry && entry < end));
+
+        let scope = Scope {
+            head: None,
+            exit: None,
+        };
+        for (i, &(start, end)) in runs.iter().enumerate() {
+            if i > 0 {
+                self.labels.insert(start);
+            }
+            self.emit_range(start, end, scope, 1);
+        }
+
+        writeln!(out, "fn {}() {{", function_name(entry))?;
+        let roles = register_roles(body.iter().filter_map(|a| self.instructions.get(a)));
+        if !roles.is_empty() {
+            writeln!(out, "{}// {}", INDENT, roles.join(", "))?;
+        }
+        for line in &self.lines {
+            if self.labels.remove(&line.addr) {
+                writeln!(out, "{}:", label_name(line.addr))?;
+            }
+            let text = INDENT.repeat(line.indent) + &line.text;
+            writeln!(out, "{:<56} // {:04X}", text, line.addr)?;
+        }
+        // Gotos into code that belongs to another function
+        for &label in &self.labels {
+            writeln!(
+                out,
+                "{}// {} is outside this function",
+                INDENT,
+                label_name(label)
+            )?;
+        }
+        writeln!(out, "}}")?;
+        writeln!(out)
+    }
+
+    fn push(&mut self, addr: u16, indent: usize, text: String) {
+        self.lines.push(Line { addr, indent, text });
+    }
+
+    fn emit_range(&mut self, from: u16, to: u16, scope: Scope, indent: usize) {
+        let mut addr = from;
+        while addr < to {
+            let instruction = match self.instructions.get(&addr) {
+                Some(&instruction) => instruction,
+                None => {
+                    self.push(addr, indent, format!("data(0x{:04X});", self.fetch(addr)));
+                    addr += 2;
+                    continue;
+                }
+            };
+            let next = addr + instruction.size();
+
+            // Loops, except for the one we're already inside of
+            let loop_end = if scope.head == Some(addr) && addr == from {
+                None
+            } else {
+                self.instructions
+                    .range(next..to)
+                    .rev()
+                    .find(|&(_, &i)| i == Instruction::Jp(addr))
+                    .map(|(&a, _)| a)
+            };
+            if let Some(jump) = loop_end {
+                let inner = Scope {
+                    head: Some(addr),
+                    exit: Some(jump + 2),
+                };
+                match self.skip_before(addr, jump) {
+                    Some((skip, cond)) => {
+                        self.push(addr, indent, "do {".to_string());
+                        self.emit_range(addr, skip, inner, indent + 1);
+                        self.push(skip, indent, format!("}} while ({});", negate(&cond)));
+                    }
+                    None => {
+                        self.push(addr, indent, "while (true) {".to_string());
+                        self.emit_range(addr, jump, inner, indent + 1);
+                        self.push(jump, indent, "}".to_string());
+                    }
+                }
+                addr = jump + 2;
+                continue;
+            }
+
+            if let Some(cond) = condition(&instruction) {
+                addr = self.emit_skip(addr, next, to, &cond, scope, indent);
+                continue;
+            }
+
+            // BCD followed by reading the digits back
+            if let (Instruction::LdB(x), Some(Instruction::LdVxI(2))) =
+                (instruction, self.instructions.get(&next))
+            {
+                if next < to {
+                    self.push(
+                        addr,
+                        indent,
+                        format!("V0, V1, V2 = digits(V{:X});  // BCD through [I]", x),
+                    );
+                    addr = next + 2;
+                    continue;
+                }
+            }
+
+            let text = self.statement(addr, instruction, scope);
+            self.push(addr, indent, text);
+            addr = next;
+        }
+    }
+
+    // The skip whose skipped instruction is the jump at `jump`, if it's inside
+    // the loop starting at head
+    fn skip_before(&self, head: u16, jump: u16) -> Option<(u16, String)> {
+        let skip = jump.checked_sub(2)?;
+        if skip < head {
+            return None;
+        }
+        Some((skip, condition(self.instructions.get(&skip)?)?))
+    }
+
+    // Emits a skip at addr and whatever it controls, returning where to continue
+    fn emit_skip(
+        &mut self,
+        addr: u16,
+        next: u16,
+        to: u16,
+        cond: &str,
+        scope: Scope,
+        indent: usize,
+    ) -> u16 {
+        let skipped = match self.instructions.get(&next) {
+            Some(&skipped) if next < to => skipped,
+            _ => {
+                self.labels.insert(next + 2);
+                self.push(
+                    addr,
+                    indent,
+                    format!("if ({}) goto {};", cond, label_name(next + 2)),
+                );
+                return next;
+            }
+        };
+        let after = next + skipped.size();
+        match skipped {
+            // skip; JP forward: the skip jumps into a block
+            Instruction::Jp(target) if target > after && target <= to && !scope.is(target) => {
+                // A forward JP at the end of the block jumps over an else
+                let last = self
+                    .instructions
+                    .range(after..target)
+                    .last()
+                    .map(|(&a, &i)| (a, i));
+                match last {
+                    Some((last, Instruction::Jp(end)))
+                        if last + 2 == target && end > target && end <= to && !scope.is(end) =>
+                    {
+                        self.push(addr, indent, format!("if ({}) {{", cond));
+                        self.emit_range(after, last, scope, indent + 1);
+                        self.push(last, indent, "} else {".to_string());
+                        self.emit_range(target, end, scope, indent + 1);
+                        self.push(end.saturating_sub(2), indent, "}".to_string());
+                        end
+                    }
+                    _ => {
+                        self.push(addr, indent, format!("if ({}) {{", cond));
+                        self.emit_range(after, target, scope, indent + 1);
+                        self.push(target.saturating_sub(2), indent, "}".to_string());
+                        target
+                    }
+                }
+            }
+            // skip; skip: branch past the second one
+            _ if condition(&skipped).is_some() => {
+                self.labels.insert(after);
+                self.push(
+                    addr,
+                    indent,
+                    format!("if ({}) goto {};", cond, label_name(after)),
+                );
+                next
+            }
+            _ => {
+                let text = self.statement(next, skipped, scope);
+                self.push(addr, indent, format!("if ({}) {}", negate(cond), text));
+                after
+            }
+        }
+    }
+
+    fn statement(&mut self, addr: u16, instruction: Instruction, scope: Scope) -> String {
+        use Instruction::*;
+
+        match instruction {
+            Halt => "halt();".to_string(),
+            Cls => "clear_screen();".to_string(),
+            Ret => "return;".to_string(),
+            Sys(nnn) => format!("machine_code(0x{:03X});", nnn),
+            Jp(nnn) if scope.head == Some(nnn) => "continue;".to_string(),
+            Jp(nnn) if scope.exit == Some(nnn) => "break;".to_string(),
+            Jp(nnn) if nnn == addr => "halt();  // jumps to itself".to_string(),
+            Jp(nnn) => {
+                self.labels.insert(nnn);
+                format!("goto {};", label_name(nnn))
+            }
+            Call(nnn) => format!("{}();", function_name(nnn)),
+            LdByte(x, kk) => format!("V{:X} = {};", x, kk),
+            AddByte(x, kk) => format!("V{:X} += {};", x, kk),
+            LdReg(x, y) => format!("V{:X} = V{:X};", x, y),
+            Or(x, y) => format!("V{:X} |= V{:X};", x, y),
+            And(x, y) => format!("V{:X} &= V{:X};", x, y),
+            Xor(x, y) => format!("V{:X} ^= V{:X};", x, y),
+            AddReg(x, y) => format!("V{:X} += V{:X};  // VF = carry", x, y),
+            Sub(x, y) => format!("V{:X} -= V{:X};  // VF = !borrow", x, y),
+            Subn(x, y) => format!("V{:X} = V{:X} - V{:X};  // VF = !borrow", x, y, x),
+            Shr(x, y) if x == y => format!("V{:X} >>= 1;", x),
+            Shl(x, y) if x == y => format!("V{:X} <<= 1;", x),
+            Shr(x, y) => format!(
+                "V{:X} = V{:X} >> 1;  // V{:X} >>= 1 with the shift quirk",
+                x, y, x
+            ),
+            Shl(x, y) => format!(
+                "V{:X} = V{:X} << 1;  // V{:X} <<= 1 with the shift quirk",
+                x, y, x
+            ),
+            LdI(nnn) => format!("I = {};", data_name(nnn, self.data.get(&nnn))),
+            JpV0(nnn) => format!("goto *(0x{:03X} + V0);  // jump table", nnn),
+            Rnd(x, kk) => format!("V{:X} = rand() & 0x{:02X};", x, kk),
+            Drw(x, y, n) => format!("VF = draw(V{:X}, V{:X}, I, {});", x, y, n),
+            LdVxDt(x) => format!("V{:X} = delay_timer;", x),
+            LdVxK(x) => format!("V{:X} = wait_key();", x),
+            LdDtVx(x) => format!("delay_timer = V{:X};", x),
+            LdStVx(x) => format!("sound_timer = V{:X};", x),
+            AddI(x) => format!("I += V{:X};", x),
+            LdF(x) => format!("I = font_digit(V{:X});", x),
+            LdB(x) => format!("[I] = bcd(V{:X});", x),
+            LdIVx(x) => format!("store(I, {});", register_range(x)),
+            LdVxI(x) => format!("{} = load(I);", register_range(x)),
+            ScrollDown(n) => format!("scroll_down({});", n),
+            ScrollUp(n) => format!("scroll_up({});", n),
+            ScrollRight => "scroll_right();".to_string(),
+            ScrollLeft => "scroll_left();".to_string(),
+            Exit => "exit();".to_string(),
+            Low => "lores();".to_string(),
+            High => "hires();".to_string(),
+            LdHf(x) => format!("I = big_font_digit(V{:X});", x),
+            LdRVx(x) => format!("save_flags({});", register_range(x)),
+            LdVxR(x) => format!("{} = load_flags();", register_range(x)),
+            Save(x, y) => format!("store(I, V{:X}..V{:X});", x, y),
+            Load(x, y) => format!("V{:X}..V{:X} = load(I);", x, y),
+            LdILong => format!("I = 0x{:04X};", self.fetch(addr + 2)),
+            Plane(n) => format!("plane({});", n),
+            Audio => "load_audio(I);".to_string(),
+            Pitch(x) => format!("pitch = V{:X};", x),
+            // Skips are handled by emit_skip
+            _ => instruction.to_string(),
+        }
+    }
+
+    fn fetch(&self, addr: u16) -> u16 {
+        let addr = addr as usize & 0xFFF;
+        (self.memory[addr] as u16) << 8 | self.memory[(addr + 1) & 0xFFF] as u16
+    }
+
+    // Follow each Annn forwards in a straight line to see what I is used for
+    fn find_data(&mut self) {
+        for (&addr, &instruction) in &self.instructions {
+            let nnn = match instruction {
+                Instruction::LdI(nnn) => nnn,
+                _ => continue,
+            };
+            let mut kind = Data::Other;
+            let mut indexed = false;
+            for (_, &next) in self.instructions.range(addr + 2..).take(16) {
+                match next {
+                    Instruction::AddI(_) => indexed = true,
+                    Instruction::Drw(_, _, n) => {
+                        kind = if indexed {
+                            Data::SpriteTable(n)
+                        } else {
+                            Data::Sprite(n)
+                        };
+                        break;
+                    }
+                    Instruction::LdI(_)
+                    | Instruction::LdF(_)
+                    | Instruction::LdILong
+                    | Instruction::Jp(_)
+                    | Instruction::JpV0(_)
+                    | Instruction::Call(_)
+                    | Instruction::Ret => break,
+                    _ => {}
+                }
+            }
+            // Keep the tallest use of a sprite
+            let entry = self.data.entry(nnn).or_insert(Data::Other);
+            if kind.height() > entry.height() {
+                *entry = kind;
+            }
+        }
+    }
+
+    // Sprites drawn from each address, as bitmaps
+    fn write_data(&self, out: &mut impl Write) -> io::Result<()> {
+        for (&addr, &kind) in &self.data {
+            let height = match kind.height() {
+                Some(height) => height,
+                None => continue,
+            };
+            writeln!(out, "// {}", data_name(addr, Some(&kind)))?;
+            if let Data::SpriteTable(_) = kind {
+                writeln!(out, "// (first entry of {} bytes)", height)?;
+            }
+            for row in 0..height {
+                let byte = self.memory[(addr + row) as usize & 0xFFF];
+                let bits: String = (0..8)
+                    .map(|bit| if byte & (0x80 >> bit) != 0 { '#' } else { '.' })
+                    .collect();
+                writeln!(out, "//   {:04X}  {}", addr + row, bits)?;
+            }
+            writeln!(out)?;
+        }
+        Ok(())
+    }
+}
+
+impl Data {
+    // Bytes per sprite; Dxy0 is a 16x16 SUPER-CHIP sprite, shown as its first 16 bytes
+    fn height(self) -> Option<u16> {
+        match self {
+            Data::Sprite(0) | Data::SpriteTable(0) => Some(16),
+            Data::Sprite(n) | Data::SpriteTable(n) => Some(n),
+            Data::Other => None,
+        }
+    }
+}
+
+impl Scope {
+    // Whether a jump to addr is a break or continue
+    fn is(&self, addr: u16) -> bool {
+        self.head == Some(addr) || self.exit == Some(addr)
+    }
+}
+
+// Condition under which a skip instruction skips
+fn condition(instruction: &Instruction) -> Option<String> {
+    use Instruction::*;
+
+    Some(match *instruction {
+        SeByte(x, kk) => format!("V{:X} == {}", x, kk),
+        SneByte(x, kk) => format!("V{:X} != {}", x, kk),
+        SeReg(x, y) => format!("V{:X} == V{:X}", x, y),
+        SneReg(x, y) => format!("V{:X} != V{:X}", x, y),
+        Skp(x) => format!("key_down(V{:X})", x),
+        Sknp(x) => format!("!key_down(V{:X})", x),
+        _ => return None,
+    })
+}
+
+fn negate(cond: &str) -> String {
+    if let Some(rest) = cond.strip_prefix('!') {
+        rest.to_string()
+    } else if cond.contains("==") {
+        cond.replace("==", "!=")
+    } else if cond.contains("!=") {
+        cond.replace("!=", "==")
+    } else {
+        format!("!{}", cond)
+    }
+}
+
+fn function_name(addr: u16) -> String {
+    if addr == START {
+        "main".to_string()
+    } else {
+        format!("sub_{:04X}", addr)
+    }
+}
+
+fn label_name(addr: u16) -> String {
+    format!("label_{:04X}", addr)
+}
+
+fn data_name(addr: u16, kind: Option<&Data>) -> String {
+    match kind {
+        Some(Data::Sprite(_)) => format!("sprite_{:04X}", addr),
+        Some(Data::SpriteTable(_)) => format!("sprite_table_{:04X}", addr),
+        _ if addr < START => format!("0x{:03X}", addr),
+        _ => format!("data_{:04X}", addr),
+    }
+}
+
+fn register_range(x: usize) -> String {
+    if x == 0 {
+        "V0".to_string()
+    } else {
+        format!("V0..V{:X}", x)
+    }
+}
+
+// Guess what registers are for from how they're used
+fn register_roles<'a>(instructions: impl Iterator<Item = &'a Instruction>) -> Vec<String> {
+    let mut roles: BTreeMap<usize, BTreeSet<&str>> = BTreeMap::new();
+    for instruction in instructions {
+        let mut add = |x: usize, role| {
+            roles.entry(x).or_default().insert(role);
+        };
+        match *instruction {
+            Instruction::Drw(x, y, _) => {
+                add(x, "x");
+                add(y, "y");
+            }
+            Instruction::Skp(x) | Instruction::Sknp(x) | Instruction::LdVxK(x) => add(x, "key"),
+            Instruction::LdVxDt(x) | Instruction::LdDtVx(x) => add(x, "timer"),
+            Instruction::LdStVx(x) => add(x, "sound"),
+            Instruction::LdF(x) | Instruction::LdB(x) => add(x, "number"),
+            Instruction::Rnd(x, _) => add(x, "random"),
+            _ => {}
+        }
+    }
+    roles
+        .into_iter()
+        .filter(|&(x, _)| x != 0xF)
+        .map(|(x, roles)| {
+            format!(
+                "V{:X}: {}",
+                x,
+                roles.into_iter().collect::<Vec<_>>().join("/")
+            )
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decompiled(rom: &[u8]) -> String {
+        let mut out = Vec::new();
+        decompile(rom, &mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn skip_over_jump_is_if_else() {
+        let rom = [
+            0x30, 0x01, // 200: SE V0, 1
+            0x12, 0x08, // 202: JP 208
+            0x61, 0x02, // 204: LD V1, 2
+            0x12, 0x0A, // 206: JP 20A
+            0x61, 0x03, // 208: LD V1, 3
+            0x12, 0x0A, // 20A: JP 20A
+        ];
+        assert_eq!(
+            decompiled(&rom),
+            "\
+fn main() {
+    if (V0 == 1) {                                       // 0200
+        V1 = 2;                                          // 0204
+    } else {                                             // 0206
+        V1 = 3;                                          // 0208
+    }                                                    // 0208
+    halt();  // jumps to itself                          // 020A
+}
+
+"
+        );
+    }
+
+    #[test]
+    fn backward_jump_is_a_loop() {
+        let rom = [
+            0x60, 0x00, // 200: LD V0, 0
+            0x70, 0x01, // 202: ADD V0, 1
+            0x30, 0x0A, // 204: SE V0, 10
+            0x12, 0x02, // 206: JP 202
+            0x12, 0x08, // 208: JP 208
+        ];
+        assert_eq!(
+            decompiled(&rom),
+            "\
+fn main() {
+    V0 = 0;                                              // 0200
+    do {                                                 // 0202
+        V0 += 1;                                         // 0202
+    } while (V0 != 10);                                  // 0204
+    halt();  // jumps to itself                          // 0208
+}
+
+"
+        );
+
+        let rom = [
+            0x00, 0xE0, // 200: CLS
+            0x70, 0x01, // 202: ADD V0, 1
+            0x12, 0x02, // 204: JP 202
+        ];
+        assert_eq!(
+            decompiled(&rom),
+            "\
+fn main() {
+    clear_screen();                                      // 0200
+    while (true) {                                       // 0202
+        V0 += 1;                                         // 0202
+    }                                                    // 0204
+}
+
+"
+        );
+    }
+
+    #[test]
+    fn single_statement_ifs_stay_on_one_line() {
+        let rom = [
+            0x22, 0x06, // 200: CALL 206
+            0x12, 0x02, // 202: JP 202
+            0x00, 0x00, // 204: data
+            0x40, 0x00, // 206: SNE V0, 0
+            0x61, 0x05, // 208: LD V1, 5
+            0x00, 0xEE, // 20A: RET
+        ];
+        assert_eq!(
+            decompiled(&rom),
+            "\
+fn main() {
+    sub_0206();                                          // 0200
+    halt();  // jumps to itself                          // 0202
+}
+
+fn sub_0206() {
+    if (V0 == 0) V1 = 5;                                 // 0206
+    return;                                              // 020A
+}
+
+"
+        );
+    }
+}
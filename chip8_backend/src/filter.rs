@@ -0,0 +1,454 @@
+// CRT-style post-processing on RGBA frames, all on the CPU
+//
+// A pipeline takes the display rendered at one pixel per CHIP-8 pixel. It
+// first applies the smoothing filters, which each double the size, then
+// scales the result to the requested size and applies the effects, which work
+// in units of the CHIP-8 pixels (cells) they now cover. Smoothing only runs
+// while the scale is still a multiple of the size it doubles to, so at scale 1
+// or an odd scale it's skipped rather than scaled back down unevenly.
+
+use crate::render::{encode_png, HEIGHT, WIDTH};
+use std::fmt;
+use std::io::{self, Write};
+use std::str::FromStr;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Filter {
+    // Smoothing: rounds off the corners of diagonal edges
+    Scale2x,
+    Eagle,
+    // Effects, with a strength from 0.0 to 1.0
+    // Darkens the bottom third of each cell
+    Scanlines(f32),
+    // Darkens a line along the right and bottom edges of each cell
+    Grid(f32),
+    // Lit pixels glow into their surroundings
+    Bloom(f32),
+}
+
+impl Filter {
+    fn is_smoothing(&self) -> bool {
+        matches!(self, Filter::Scale2x | Filter::Eagle)
+    }
+}
+
+// "scale2x", "eagle", "scanlines[:strength]", "grid[:strength]" or
+// "bloom[:strength]"
+impl FromStr for Filter {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, strength) = match s.split_once(':') {
+            Some((name, strength)) => match strength.parse::<f32>() {
+                Ok(strength) if (0.0..=1.0).contains(&strength) => (name, Some(strength)),
+                _ => return Err(format!("Strength must be from 0 to 1, got {}", strength)),
+            },
+            None => (s, None),
+        };
+        match (name, strength) {
+            ("scale2x", None) => Ok(Filter::Scale2x),
+            ("eagle", None) => Ok(Filter::Eagle),
+            ("scanlines", _) => Ok(Filter::Scanlines(strength.unwrap_or(0.5))),
+            ("grid", _) => Ok(Filter::Grid(strength.unwrap_or(0.3))),
+            ("bloom", _) => Ok(Filter::Bloom(strength.unwrap_or(0.4))),
+            ("scale2x" | "eagle", Some(_)) => Err(format!("{} has no strength", name)),
+            _ => Err(format!("Unknown filter {}", name)),
+        }
+    }
+}
+
+impl fmt::Display for Filter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Filter::Scale2x => write!(f, "scale2x"),
+            Filter::Eagle => write!(f, "eagle"),
+            Filter::Scanlines(strength) => write!(f, "scanlines:{}", strength),
+            Filter::Grid(strength) => write!(f, "grid:{}", strength),
+            Filter::Bloom(strength) => write!(f, "bloom:{}", strength),
+        }
+    }
+}
+
+// An RGBA8 image
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Image {
+    pub width: usize,
+    pub height: usize,
+    pub pixels: Vec<u8>,
+}
+
+impl Image {
+    pub fn new(width: usize, height: usize, pixels: Vec<u8>) -> Self {
+        assert_eq!(pixels.len(), width * height * 4, "Wrong RGBA buffer size");
+        Self {
+            width,
+            height,
+            pixels,
+        }
+    }
+
+    pub fn write_png(&self, out: impl Write) -> io::Result<()> {
+        encode_png(&self.pixels, self.width, self.height, out)
+    }
+
+    // Clamps to the nearest edge
+    fn get(&self, x: isize, y: isize) -> [u8; 4] {
+        let x = x.clamp(0, self.width as isize - 1) as usize;
+        let y = y.clamp(0, self.height as isize - 1) as usize;
+        let i = (y * self.width + x) * 4;
+        [
+            self.pixels[i],
+            self.pixels[i + 1],
+            self.pixels[i + 2],
+            self.pixels[i + 3],
+        ]
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Pipeline {
+    filters: Vec<Filter>,
+}
+
+// Filters separated by commas, e.g. "scale2x,scanlines:0.6,bloom"
+impl FromStr for Pipeline {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let filters = s
+            .split(',')
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+            .map(str::parse)
+            .collect::<Result<_, _>>()?;
+        Ok(Self::new(filters))
+    }
+}
+
+impl fmt::Display for Pipeline {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let names: Vec<String> = self.filters.iter().map(Filter::to_string).collect();
+        write!(f, "{}", names.join(","))
+    }
+}
+
+impl Pipeline {
+    pub fn new(filters: Vec<Filter>) -> Self {
+        Self { filters }
+    }
+
+    pub fn filters(&self) -> &[Filter] {
+        &self.filters
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.filters.is_empty()
+    }
+
+    // Filters a 64x32 RGBA8 frame into one scale times its size
+    pub fn apply(&self, frame: &[u8], scale: usize) -> Image {
+        assert!(scale > 0, "Scale must be at least 1");
+        let mut image = Image::new(WIDTH, HEIGHT, frame[..WIDTH * HEIGHT * 4].to_vec());
+        let mut size = 1;
+        for filter in self.filters.iter().filter(|f| f.is_smoothing()) {
+            if !scale.is_multiple_of(size * 2) {
+                break;
+            }
+            size *= 2;
+            image = match filter {
+                Filter::Scale2x => scale2x(&image),
+                _ => eagle(&image),
+            };
+        }
+        let mut image = resize(&image, WIDTH * scale, HEIGHT * scale);
+        for filter in self.filters.iter().filter(|f| !f.is_smoothing()) {
+            match *filter {
+                Filter::Scanlines(strength) => scanlines(&mut image, scale, strength),
+                Filter::Grid(strength) => grid(&mut image, scale, strength),
+                Filter::Bloom(strength) => bloom(&mut image, scale, strength),
+                _ => {}
+            }
+        }
+        image
+    }
+}
+
+// Nearest neighbour
+fn resize(image: &Image, width: usize, height: usize) -> Image {
+    if (width, height) == (image.width, image.height) {
+        return image.clone();
+    }
+    let mut pixels = Vec::with_capacity(width * height * 4);
+    let mut line = Vec::with_capacity(width * 4);
+    let mut source_row = usize::MAX;
+    for y in 0..height {
+        let row = y * image.height / height;
+        if row != source_row {
+            source_row = row;
+            line.clear();
+            for x in 0..width {
+                let i = (row * image.width + x * image.width / width) * 4;
+                line.extend_from_slice(&image.pixels[i..i + 4]);
+            }
+        }
+        pixels.extend_from_slice(&line);
+    }
+    Image::new(width, height, pixels)
+}
+
+// Doubles the size, each pixel becoming four chosen from it and its
+// neighbours by `corners`
+fn double(image: &Image, corners: impl Fn(&Image, isize, isize) -> [[u8; 4]; 4]) -> Image {
+    let width = image.width * 2;
+    let mut pixels = vec![0; width * image.height * 2 * 4];
+    for y in 0..image.height {
+        for x in 0..image.width {
+            let [e0, e1, e2, e3] = corners(image, x as isize, y as isize);
+            let top = (y * 2 * width + x * 2) * 4;
+            let bottom = top + width * 4;
+            pixels[top..top + 4].copy_from_slice(&e0);
+            pixels[top + 4..top + 8].copy_from_slice(&e1);
+            pixels[bottom..bottom + 4].copy_from_slice(&e2);
+            pixels[bottom + 4..bottom + 8].copy_from_slice(&e3);
+        }
+    }
+    Image::new(width, image.height * 2, pixels)
+}
+
+// Scale2x, also known as EPX
+fn scale2x(image: &Image) -> Image {
+    double(image, |image, x, y| {
+        let e = image.get(x, y);
+        let b = image.get(x, y - 1);
+        let d = image.get(x - 1, y);
+        let f = image.get(x + 1, y);
+        let h = image.get(x, y + 1);
+        if b == h || d == f {
+            return [e; 4];
+        }
+        [
+            if d == b { d } else { e },
+            if b == f { f } else { e },
+            if d == h { d } else { e },
+            if h == f { f } else { e },
+        ]
+    })
+}
+
+// Each corner takes the colour of its three outer neighbours when they agree
+fn eagle(image: &Image) -> Image {
+    double(image, |image, x, y| {
+        let p = |dx, dy| image.get(x + dx, y + dy);
+        let corner = |a: [u8; 4], b: [u8; 4], c: [u8; 4]| {
+            if a == b && b == c {
+                a
+            } else {
+                p(0, 0)
+            }
+        };
+        [
+            corner(p(-1, 0), p(-1, -1), p(0, -1)),
+            corner(p(0, -1), p(1, -1), p(1, 0)),
+            corner(p(-1, 0), p(-1, 1), p(0, 1)),
+            corner(p(1, 0), p(1, 1), p(0, 1)),
+        ]
+    })
+}
+
+fn darken(pixel: &mut [u8], keep: u32) {
+    for channel in &mut pixel[..3] {
+        *channel = (*channel as u32 * keep / 256) as u8;
+    }
+}
+
+// Share of each channel darken keeps, out of 256
+fn keep(strength: f32) -> u32 {
+    ((1.0 - strength) * 256.0) as u32
+}
+
+fn scanlines(image: &mut Image, cell: usize, strength: f32) {
+    if cell < 2 {
+        return;
+    }
+    let keep = keep(strength);
+    let lines = (cell / 3).max(1);
+    let pitch = image.width * 4;
+    for y in (0..image.height).filter(|y| y % cell >= cell - lines) {
+        for pixel in image.pixels[y * pitch..][..pitch].chunks_exact_mut(4) {
+            darken(pixel, keep);
+        }
+    }
+}
+
+fn grid(image: &mut Image, cell: usize, strength: f32) {
+    if cell < 2 {
+        return;
+    }
+    let keep = keep(strength);
+    let pitch = image.width * 4;
+    for (y, line) in image.pixels.chunks_exact_mut(pitch).enumerate() {
+        let edge = y % cell == cell - 1;
+        for (x, pixel) in line.chunks_exact_mut(4).enumerate() {
+            if edge || x % cell == cell - 1 {
+                darken(pixel, keep);
+            }
+        }
+    }
+}
+
+// Adds a box-blurred copy of the image, about a cell wide, on top of it
+fn bloom(image: &mut Image, cell: usize, strength: f32) {
+    let radius = (cell / 2).max(1);
+    let (width, height) = (image.width, image.height);
+    let window = (radius * 2 + 1) as u32;
+    let pitch = width * 3;
+    let amount = (strength * 256.0) as u32;
+
+    // Blur each row, keeping RGB only, from a copy padded with its edge pixels
+    let mut across = vec![0u32; width * height * 3];
+    let mut padded = vec![0u32; (width + radius * 2) * 3];
+    for y in 0..height {
+        let row = &image.pixels[y * width * 4..][..width * 4];
+        for (x, rgb) in padded.chunks_exact_mut(3).enumerate() {
+            let i = x.saturating_sub(radius).min(width - 1) * 4;
+            for (value, &channel) in rgb.iter_mut().zip(&row[i..i + 3]) {
+                *value = channel as u32;
+            }
+        }
+        let mut sums = [0u32; 3];
+        for rgb in padded[..(radius * 2 + 1) * 3].chunks_exact(3) {
+            for (sum, value) in sums.iter_mut().zip(rgb) {
+                *sum += value;
+            }
+        }
+        let out = &mut across[y * pitch..][..pitch];
+        for x in 0..width {
+            out[x * 3..x * 3 + 3].copy_from_slice(&sums);
+            if x + 1 < width {
+                let leaving = &padded[x * 3..x * 3 + 3];
+                let entering = &padded[(x + radius * 2 + 1) * 3..][..3];
+                for c in 0..3 {
+                    sums[c] = sums[c] + entering[c] - leaving[c];
+                }
+            }
+        }
+    }
+
+    // Then the columns, keeping a running sum for each one as the window moves down
+    let row = |y: isize| &across[y.clamp(0, height as isize - 1) as usize * pitch..][..pitch];
+    let mut sums = vec![0u32; pitch];
+    for y in -(radius as isize)..=radius as isize {
+        for (sum, value) in sums.iter_mut().zip(row(y)) {
+            *sum += value;
+        }
+    }
+    // sum * factor >> 16 is the average times the strength
+    let factor = amount * 256 / (window * window);
+    for y in 0..height as isize {
+        let line = &mut image.pixels[y as usize * width * 4..][..width * 4];
+        for (pixel, sum) in line.chunks_exact_mut(4).zip(sums.chunks_exact(3)) {
+            for (channel, &sum) in pixel[..3].iter_mut().zip(sum) {
+                *channel = (*channel as u32 + ((sum * factor) >> 16)).min(255) as u8;
+            }
+        }
+        let (entering, leaving) = (row(y + radius as isize + 1), row(y - radius as isize));
+        for ((sum, entering), leaving) in sums.iter_mut().zip(entering).zip(leaving) {
+            *sum = *sum + entering - leaving;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BLACK: [u8; 4] = [0, 0, 0, 255];
+    const WHITE: [u8; 4] = [255, 255, 255, 255];
+
+    // Rows of '#' (white) and '.' (black)
+    fn image(rows: &[&str]) -> Image {
+        let pixels = rows
+            .iter()
+            .flat_map(|row| row.chars())
+            .flat_map(|c| if c == '#' { WHITE } else { BLACK })
+            .collect();
+        Image::new(rows[0].len(), rows.len(), pixels)
+    }
+
+    fn rows(image: &Image) -> Vec<String> {
+        image
+            .pixels
+            .chunks_exact(image.width * 4)
+            .map(|row| {
+                row.chunks_exact(4)
+                    .map(|pixel| if pixel == WHITE { '#' } else { '.' })
+                    .collect()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn scale2x_rounds_diagonals() {
+        // The pixels beside the line each take a corner of it
+        let diagonal = image(&["#..", ".#.", "..#"]);
+        assert_eq!(
+            rows(&scale2x(&diagonal)),
+            ["##....", "#.#...", ".###..", "..###.", "...#.#", "....##"]
+        );
+        // Straight edges stay straight
+        let stripe = image(&["###", "...", "..."]);
+        assert_eq!(rows(&scale2x(&stripe)), rows(&resize(&stripe, 6, 6)));
+    }
+
+    #[test]
+    fn eagle_fills_corners() {
+        let corner = image(&["##", "#."]);
+        assert_eq!(rows(&eagle(&corner)), ["####", "####", "###.", "##.."]);
+    }
+
+    #[test]
+    fn scanlines_and_grid_darken_cell_edges() {
+        let mut lit = image(&["###", "###", "###"]);
+        scanlines(&mut lit, 3, 1.0);
+        assert_eq!(rows(&lit), ["###", "###", "..."]);
+
+        let mut lit = image(&["####", "####", "####", "####"]);
+        grid(&mut lit, 2, 1.0);
+        assert_eq!(rows(&lit), ["#.#.", "....", "#.#.", "...."]);
+
+        // Nothing to darken within one-pixel cells
+        let mut lit = image(&["##"]);
+        scanlines(&mut lit, 1, 1.0);
+        grid(&mut lit, 1, 1.0);
+        assert_eq!(rows(&lit), ["##"]);
+    }
+
+    #[test]
+    fn bloom_spreads_light() {
+        let mut dot = image(&["....", ".#..", "....", "...."]);
+        bloom(&mut dot, 2, 1.0);
+        let pixel = |x: usize, y: usize| dot.pixels[(y * 4 + x) * 4];
+        assert_eq!(pixel(1, 1), 255);
+        assert!(pixel(0, 0) > 0);
+        assert!(pixel(2, 2) > 0);
+        assert_eq!(pixel(3, 3), 0);
+    }
+
+    #[test]
+    fn smoothing_needs_an_even_scale() {
+        let mut display = [0u64; 32];
+        display[0] = 1 << 63;
+        display[1] = 1 << 62;
+        let mut frame = vec![0; crate::render::rgba8_len(1)];
+        crate::render::render_rgba8(&display, &Default::default(), 1, &mut frame);
+        let plain = |scale| Pipeline::default().apply(&frame, scale);
+        let smooth = |scale| Pipeline::new(vec![Filter::Scale2x]).apply(&frame, scale);
+
+        assert_eq!(smooth(1), plain(1));
+        assert_eq!(smooth(3), plain(3));
+        let image = smooth(2);
+        assert_ne!(image, plain(2));
+        assert_eq!(rows(&image)[1][..4], *"#.#.");
+        assert_eq!(rows(&smooth(4))[2][..8], *"##..##..");
+    }
+}
@@ -0,0 +1,434 @@
+// GDB remote serial protocol stub
+// https://sourceware.org/gdb/current/onlinedocs/gdb.html/Remote-Protocol.html
+//
+// Register numbering (also described by TARGET_XML):
+// 0-15: V0-VF, 16: I, 17: PC, 18: SP, 19: DT, 20: ST
+// Multi-byte registers are sent little-endian, like any other target.
+
+use crate::debugger::{Debugger, StopReason};
+use crate::Error;
+use std::io::{self, BufRead, BufReader, ErrorKind, Read, Write};
+use std::net::TcpStream;
+
+// Instructions run between checks for a Ctrl-C from the client
+const RESUME_SLICE: u32 = 10_000;
+
+// Reply sizes are bounded by the 4K address space, this leaves plenty of room
+const PACKET_SIZE: usize = 0x4000;
+
+// I and PC can be set anywhere in the 4K address space
+const MAX_ADDR: u16 = 0xFFF;
+
+const SIGINT: u8 = 2;
+const SIGILL: u8 = 4;
+const SIGTRAP: u8 = 5;
+const SIGABRT: u8 = 6;
+
+pub const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <feature name="org.chip8.core">
+    <reg name="v0" bitsize="8" type="uint8" regnum="0" group="general"/>
+    <reg name="v1" bitsize="8" type="uint8" group="general"/>
+    <reg name="v2" bitsize="8" type="uint8" group="general"/>
+    <reg name="v3" bitsize="8" type="uint8" group="general"/>
+    <reg name="v4" bitsize="8" type="uint8" group="general"/>
+    <reg name="v5" bitsize="8" type="uint8" group="general"/>
+    <reg name="v6" bitsize="8" type="uint8" group="general"/>
+    <reg name="v7" bitsize="8" type="uint8" group="general"/>
+    <reg name="v8" bitsize="8" type="uint8" group="general"/>
+    <reg name="v9" bitsize="8" type="uint8" group="general"/>
+    <reg name="va" bitsize="8" type="uint8" group="general"/>
+    <reg name="vb" bitsize="8" type="uint8" group="general"/>
+    <reg name="vc" bitsize="8" type="uint8" group="general"/>
+    <reg name="vd" bitsize="8" type="uint8" group="general"/>
+    <reg name="ve" bitsize="8" type="uint8" group="general"/>
+    <reg name="vf" bitsize="8" type="uint8" group="general"/>
+    <reg name="i" bitsize="16" type="data_ptr" group="general"/>
+    <reg name="pc" bitsize="16" type="code_ptr" group="general" generic="pc"/>
+    <reg name="sp" bitsize="8" type="uint8" group="general"/>
+    <reg name="dt" bitsize="8" type="uint8" group="timers"/>
+    <reg name="st" bitsize="8" type="uint8" group="timers"/>
+  </feature>
+</target>
+"#;
+
+pub struct GdbStub<'a> {
+    debugger: &'a mut Debugger,
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+    no_ack: bool,
+    start_no_ack: bool,
+}
+
+impl<'a> GdbStub<'a> {
+    pub fn new(debugger: &'a mut Debugger, stream: TcpStream) -> io::Result<Self> {
+        stream.set_nodelay(true)?;
+        Ok(Self {
+            debugger,
+            reader: BufReader::new(stream.try_clone()?),
+            writer: stream,
+            no_ack: false,
+            start_no_ack: false,
+        })
+    }
+
+    // Serve packets until the client detaches, kills the target or disconnects
+    pub fn run(&mut self) -> io::Result<()> {
+        while let Some(packet) = self.read_packet()? {
+            match self.handle(&packet)? {
+                Some(reply) => self.write_packet(&reply)?,
+                None => break,
+            }
+            if self.start_no_ack {
+                self.no_ack = true;
+            }
+        }
+        Ok(())
+    }
+
+    // Returns None once the session is over
+    fn handle(&mut self, packet: &str) -> io::Result<Option<String>> {
+        let reply = match packet.as_bytes().first() {
+            Some(b'?') => stop_reply(SIGTRAP),
+            Some(b'g') => self.read_registers(),
+            Some(b'G') => self.write_registers(&packet[1..]),
+            Some(b'p') => self.read_register(&packet[1..]),
+            Some(b'P') => self.write_register(&packet[1..]),
+            Some(b'm') => self.read_memory(&packet[1..]),
+            Some(b'M') => self.write_memory(&packet[1..]),
+            Some(b'Z') => self.breakpoint(&packet[1..], true),
+            Some(b'z') => self.breakpoint(&packet[1..], false),
+            Some(b's') => {
+                set_pc(self.debugger, &packet[1..]);
+                stop_reason_reply(self.debugger.step())
+            }
+            Some(b'c') => {
+                set_pc(self.debugger, &packet[1..]);
+                self.resume()?
+            }
+            Some(b'H') => "OK".to_string(),
+            Some(b'D') => {
+                self.write_packet("OK")?;
+                return Ok(None);
+            }
+            Some(b'k') => return Ok(None),
+            Some(b'q') | Some(b'Q') => self.query(packet),
+            _ => String::new(),
+        };
+        Ok(Some(reply))
+    }
+
+    fn query(&mut self, packet: &str) -> String {
+        if packet.starts_with("qSupported") {
+            format!(
+                "PacketSize={:x};qXfer:features:read+;swbreak+;QStartNoAckMode+",
+                PACKET_SIZE
+            )
+        } else if packet == "QStartNoAckMode" {
+            // The OK itself is still acknowledged, so switch over after sending it
+            self.start_no_ack = true;
+            "OK".to_string()
+        } else if let Some(args) = packet.strip_prefix("qXfer:features:read:target.xml:") {
+            xfer(TARGET_XML, args)
+        } else if packet == "qAttached" {
+            "1".to_string()
+        } else if packet == "qC" {
+            "QC1".to_string()
+        } else if packet == "qfThreadInfo" {
+            "m1".to_string()
+        } else if packet == "qsThreadInfo" {
+            "l".to_string()
+        } else {
+            String::new()
+        }
+    }
+
+    fn read_registers(&self) -> String {
+        let chip8 = self.debugger.chip8();
+        let mut bytes = chip8.get_registers().to_vec();
+        bytes.extend_from_slice(&chip8.get_i().to_le_bytes());
+        bytes.extend_from_slice(&chip8.get_pc().to_le_bytes());
+        bytes.extend_from_slice(&[chip8.get_sp(), chip8.get_dt(), chip8.get_st()]);
+        to_hex(&bytes)
+    }
+
+    fn write_registers(&mut self, args: &str) -> String {
+        let bytes = match from_hex(args) {
+            // SP can be 0 to 16, one past the last stack slot
+            Some(bytes) if bytes.len() == 23 && bytes[20] <= 16 => bytes,
+            _ => return "E01".to_string(),
+        };
+        let i = u16::from_le_bytes([bytes[16], bytes[17]]);
+        let pc = u16::from_le_bytes([bytes[18], bytes[19]]);
+        if i > MAX_ADDR || pc > MAX_ADDR {
+            return "E01".to_string();
+        }
+        let chip8 = self.debugger.chip8_mut();
+        for (x, &value) in bytes[..16].iter().enumerate() {
+            chip8.set_register(x, value);
+        }
+        chip8.set_i(i);
+        chip8.set_pc(pc);
+        chip8.set_sp(bytes[20]);
+        chip8.set_dt(bytes[21]);
+        chip8.set_st(bytes[22]);
+        "OK".to_string()
+    }
+
+    fn read_register(&self, args: &str) -> String {
+        let chip8 = self.debugger.chip8();
+        match usize::from_str_radix(args, 16) {
+            Ok(n @ 0..=15) => to_hex(&[chip8.get_registers()[n]]),
+            Ok(16) => to_hex(&chip8.get_i().to_le_bytes()),
+            Ok(17) => to_hex(&chip8.get_pc().to_le_bytes()),
+            Ok(18) => to_hex(&[chip8.get_sp()]),
+            Ok(19) => to_hex(&[chip8.get_dt()]),
+            Ok(20) => to_hex(&[chip8.get_st()]),
+            _ => "E01".to_string(),
+        }
+    }
+
+    fn write_register(&mut self, args: &str) -> String {
+        let (n, value) = match args.split_once('=') {
+            Some((n, value)) => (usize::from_str_radix(n, 16), from_hex(value)),
+            None => return "E01".to_string(),
+        };
+        let (n, value) = match (n, value) {
+            (Ok(n), Some(value)) if !value.is_empty() => (n, value),
+            _ => return "E01".to_string(),
+        };
+        let word = u16::from_le_bytes([value[0], value.get(1).copied().unwrap_or(0)]);
+        let chip8 = self.debugger.chip8_mut();
+        match n {
+            0..=15 => chip8.set_register(n, value[0]),
+            16 if word <= MAX_ADDR => chip8.set_i(word),
+            17 if word <= MAX_ADDR => chip8.set_pc(word),
+            18 if value[0] <= 16 => chip8.set_sp(value[0]),
+            19 => chip8.set_dt(value[0]),
+            20 => chip8.set_st(value[0]),
+            _ => return "E01".to_string(),
+        }
+        "OK".to_string()
+    }
+
+    fn read_memory(&self, args: &str) -> String {
+        let memory = self.debugger.chip8().get_memory();
+        match parse_range(args, memory.len()) {
+            Some((addr, len)) => to_hex(&memory[addr..addr + len]),
+            None => "E01".to_string(),
+        }
+    }
+
+    fn write_memory(&mut self, args: &str) -> String {
+        let memory = self.debugger.chip8_mut().get_memory_mut();
+        let (range, data) = match args.split_once(':') {
+            Some((range, data)) => (parse_range(range, memory.len()), from_hex(data)),
+            None => return "E01".to_string(),
+        };
+        match (range, data) {
+            (Some((addr, len)), Some(data)) if data.len() == len => {
+                memory[addr..addr + len].copy_from_slice(&data);
+                "OK".to_string()
+            }
+            _ => "E01".to_string(),
+        }
+    }
+
+    // Z0/z0 (software) and Z1/z1 (hardware) both map to debugger breakpoints
+    fn breakpoint(&mut self, args: &str, insert: bool) -> String {
+        let mut parts = args.split(',');
+        let (kind, addr) = (parts.next(), parts.next());
+        let addr = match (kind, addr.map(|a| u16::from_str_radix(a, 16))) {
+            (Some("0") | Some("1"), Some(Ok(addr))) => addr,
+            _ => return String::new(),
+        };
+        if insert {
+            self.debugger.add_breakpoint(addr);
+        } else {
+            self.debugger.remove_breakpoint(addr);
+        }
+        "OK".to_string()
+    }
+
+    // Run until something stops the target, checking for a Ctrl-C between slices
+    fn resume(&mut self) -> io::Result<String> {
+        loop {
+            if let Some(reason) = self.debugger.resume(RESUME_SLICE) {
+                return Ok(stop_reason_reply(reason));
+            }
+            if self.interrupted()? {
+                return Ok(stop_reply(SIGINT));
+            }
+        }
+    }
+
+    fn interrupted(&mut self) -> io::Result<bool> {
+        if !self.reader.buffer().is_empty() {
+            let interrupt = self.reader.buffer()[0] == 0x03;
+            self.reader.consume(1);
+            return Ok(interrupt);
+        }
+        let stream = self.reader.get_mut();
+        stream.set_nonblocking(true)?;
+        let mut byte = [0; 1];
+        let result = stream.read(&mut byte);
+        stream.set_nonblocking(false)?;
+        match result {
+            Ok(0) => Err(ErrorKind::UnexpectedEof.into()),
+            Ok(_) => Ok(byte[0] == 0x03),
+            Err(e) if e.kind() == ErrorKind::WouldBlock => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    // Returns None when the client disconnects
+    fn read_packet(&mut self) -> io::Result<Option<String>> {
+        loop {
+            let mut byte = [0; 1];
+            if self.reader.read(&mut byte)? == 0 {
+                return Ok(None);
+            }
+            match byte[0] {
+                b'$' => {}
+                // A Ctrl-C while stopped just reports the current state
+                0x03 => return Ok(Some("?".to_string())),
+                // Acks, naks and noise between packets
+                _ => continue,
+            }
+            let mut body = Vec::new();
+            self.reader.read_until(b'#', &mut body)?;
+            body.pop();
+            let mut checksum = [0; 2];
+            self.reader.read_exact(&mut checksum)?;
+            if !self.no_ack {
+                let expected = std::str::from_utf8(&checksum)
+                    .ok()
+                    .and_then(|c| u8::from_str_radix(c, 16).ok());
+                if expected != Some(checksum_of(&body)) {
+                    self.writer.write_all(b"-")?;
+                    continue;
+                }
+                self.writer.write_all(b"+")?;
+            }
+            return Ok(Some(String::from_utf8_lossy(&unescape(&body)).into_owned()));
+        }
+    }
+
+    fn write_packet(&mut self, body: &str) -> io::Result<()> {
+        let body = escape(body.as_bytes());
+        let mut packet = Vec::with_capacity(body.len() + 4);
+        packet.push(b'$');
+        packet.extend_from_slice(&body);
+        packet.extend_from_slice(format!("#{:02x}", checksum_of(&body)).as_bytes());
+        loop {
+            self.writer.write_all(&packet)?;
+            self.writer.flush()?;
+            if self.no_ack {
+                return Ok(());
+            }
+            let mut ack = [0; 1];
+            if self.reader.read(&mut ack)? == 0 {
+                return Err(ErrorKind::UnexpectedEof.into());
+            }
+            if ack[0] != b'-' {
+                return Ok(());
+            }
+        }
+    }
+}
+
+fn set_pc(debugger: &mut Debugger, addr: &str) {
+    if let Ok(addr) = u16::from_str_radix(addr, 16) {
+        debugger.chip8_mut().set_pc(addr);
+    }
+}
+
+fn stop_reply(signal: u8) -> String {
+    format!("S{:02x}", signal)
+}
+
+fn stop_reason_reply(reason: StopReason) -> String {
+    match reason {
+        StopReason::Step => stop_reply(SIGTRAP),
+        StopReason::Breakpoint(_) => format!("T{:02x}swbreak:;", SIGTRAP),
+        StopReason::Error(Error::UnknownOpcode(..) | Error::Unsupported(..)) => stop_reply(SIGILL),
+        StopReason::Error(_) => stop_reply(SIGABRT),
+    }
+}
+
+// Answer a qXfer read of "offset,length" from document
+fn xfer(document: &str, args: &str) -> String {
+    let range = args.split_once(',').and_then(|(o, l)| {
+        Some((
+            usize::from_str_radix(o, 16).ok()?,
+            usize::from_str_radix(l, 16).ok()?,
+        ))
+    });
+    let (offset, length) = match range {
+        Some(range) => range,
+        None => return "E01".to_string(),
+    };
+    let document = document.as_bytes();
+    let start = offset.min(document.len());
+    let end = offset.saturating_add(length).min(document.len());
+    let more = if end < document.len() { 'm' } else { 'l' };
+    format!("{}{}", more, String::from_utf8_lossy(&document[start..end]))
+}
+
+// Parse "addr,len" and check it lies inside memory
+fn parse_range(args: &str, size: usize) -> Option<(usize, usize)> {
+    let (addr, len) = args.split_once(',')?;
+    let addr = usize::from_str_radix(addr, 16).ok()?;
+    let len = usize::from_str_radix(len, 16).ok()?;
+    if addr.checked_add(len)? > size {
+        return None;
+    }
+    Some((addr, len))
+}
+
+fn checksum_of(body: &[u8]) -> u8 {
+    body.iter().fold(0u8, |sum, &b| sum.wrapping_add(b))
+}
+
+fn escape(body: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(body.len());
+    for &b in body {
+        if matches!(b, b'#' | b'$' | b'}' | b'*') {
+            out.push(b'}');
+            out.push(b ^ 0x20);
+        } else {
+            out.push(b);
+        }
+    }
+    out
+}
+
+fn unescape(body: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(body.len());
+    let mut bytes = body.iter();
+    while let Some(&b) = bytes.next() {
+        if b == b'}' {
+            if let Some(&next) = bytes.next() {
+                out.push(next ^ 0x20);
+            }
+        } else {
+            out.push(b);
+        }
+    }
+    out
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
+}
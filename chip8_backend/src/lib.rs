@@ -1,5 +1,34 @@
+use decode::{decode, Instruction};
+use quirks::Quirks;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
 use std::fmt::Display;
 
+pub mod args;
+pub mod audio;
+pub mod cache;
+pub mod cfg;
+pub mod coverage;
+pub mod database;
+pub mod debugger;
+pub mod decode;
+pub mod decompile;
+pub mod filter;
+pub mod gdb;
+pub mod lint;
+pub mod movie;
+pub mod observe;
+pub mod phosphor;
+pub mod profile;
+pub mod quirks;
+pub mod record;
+pub mod render;
+pub mod trace;
+
+// Largest ROM that fits between 0x200 and the end of 4K
+pub const MAX_ROM_SIZE: usize = 4096 - 0x200;
+
+#[derive(Clone)]
 pub struct Chip8 {
     // CPU:
     // 16 8-bit general registers V0-VF
@@ -33,17 +62,30 @@ pub struct Chip8 {
     stack: [u16; 16],
 
     // Display
-    // 64x32 pixels
-    display: [bool; 64 * 32],
+    // 64x32 pixels, one u64 per row with the leftmost pixel in the top bit.
+    // SUPER-CHIP hires would need u128 rows, but isn't supported.
+    display: [u64; 32],
+    // Bit per row changed since the frontend last called clear_dirty
+    dirty_rows: u32,
 
     // Input
     // 16 keys
     keys: [bool; 16],
+
+    // Behaviour that differs between platforms
+    quirks: Quirks,
+
+    // For Cxkk; seeded from the OS unless set_seed is called
+    rng: StdRng,
 }
 
 // Public methods
 impl Chip8 {
     pub fn new() -> Self {
+        Self::with_quirks(Quirks::default())
+    }
+
+    pub fn with_quirks(quirks: Quirks) -> Self {
         let mut ram = [0; 4096];
         // Font is copied into memory starting at 0x50
         ram[0x50..(0x50 + FONT_SIZE)].copy_from_slice(&FONT);
@@ -56,8 +98,11 @@ impl Chip8 {
             st: 0,
             ram,
             stack: [0; 16],
-            display: [false; 64 * 32],
+            display: [0; 32],
+            dirty_rows: u32::MAX,
             keys: [false; 16],
+            quirks,
+            rng: StdRng::from_entropy(),
         }
     }
 
@@ -71,31 +116,81 @@ impl Chip8 {
         self.ram = [0; 4096];
         self.ram[0x50..(0x50 + FONT_SIZE)].copy_from_slice(&FONT);
         self.stack = [0; 16];
-        self.display = [false; 64 * 32];
+        self.display = [0; 32];
+        self.dirty_rows = u32::MAX;
         self.keys = [false; 16];
     }
 
-    pub fn load_rom(&mut self, rom: &[u8]) {
+    pub fn load_rom(&mut self, rom: &[u8]) -> Result<(), Error> {
+        if rom.len() > MAX_ROM_SIZE {
+            return Err(Error::RomTooLarge(rom.len()));
+        }
         // Load ROM into memory starting at 0x200
         self.ram[0x200..(0x200 + rom.len())].copy_from_slice(rom);
+        Ok(())
+    }
+
+    pub fn get_quirks(&self) -> Quirks {
+        self.quirks
+    }
+
+    pub fn set_quirks(&mut self, quirks: Quirks) {
+        self.quirks = quirks;
+    }
+
+    // Makes Cxkk repeatable, for replaying recorded input
+    pub fn set_seed(&mut self, seed: u64) {
+        self.rng = StdRng::seed_from_u64(seed);
     }
 
     pub fn keypress(&mut self, key: u8, pressed: bool) {
         self.keys[key as usize] = pressed;
     }
 
-    pub fn get_display(&self) -> &[bool; 64 * 32] {
+    pub fn get_keys(&self) -> &[bool; 16] {
+        &self.keys
+    }
+
+    // Rows of pixels, leftmost pixel in the top bit
+    pub fn get_display(&self) -> &[u64; 32] {
         &self.display
     }
 
+    pub fn get_pixel(&self, x: usize, y: usize) -> bool {
+        self.display[y % 32] & (1 << 63 >> (x % 64)) != 0
+    }
+
+    // Whether any pixel changed since the last clear_dirty, so frontends can
+    // skip redrawing identical frames
+    pub fn frame_changed(&self) -> bool {
+        self.dirty_rows != 0
+    }
+
+    // Bit per row that changed since the last clear_dirty
+    pub fn get_dirty_rows(&self) -> u32 {
+        self.dirty_rows
+    }
+
+    // Call after drawing a frame
+    pub fn clear_dirty(&mut self) {
+        self.dirty_rows = 0;
+    }
+
     pub fn get_sound(&self) -> bool {
         self.st > 0
     }
 
     pub fn cycle(&mut self) {
+        if let Err(e) = self.try_cycle() {
+            panic!("{}", e);
+        }
+    }
+
+    // Like cycle, but reports bad opcodes and stack errors instead of panicking
+    pub fn try_cycle(&mut self) -> Result<(), Error> {
         // Fetch opcode
         let opcode = self.fetch_opcode();
-        self.execute(opcode);
+        self.execute(opcode)
     }
 
     pub fn cycle_timer(&mut self) {
@@ -108,97 +203,208 @@ impl Chip8 {
     }
 }
 
+// Register and memory access, used by the debugger
+impl Chip8 {
+    pub fn get_registers(&self) -> &[u8; 16] {
+        &self.v
+    }
+
+    pub fn set_register(&mut self, x: usize, value: u8) {
+        self.v[x] = value;
+    }
+
+    pub fn get_i(&self) -> u16 {
+        self.i
+    }
+
+    pub fn set_i(&mut self, value: u16) {
+        self.i = value;
+    }
+
+    pub fn get_pc(&self) -> u16 {
+        self.pc
+    }
+
+    pub fn set_pc(&mut self, value: u16) {
+        self.pc = value;
+    }
+
+    pub fn get_sp(&self) -> u8 {
+        self.sp
+    }
+
+    pub fn set_sp(&mut self, value: u8) {
+        self.sp = value;
+    }
+
+    pub fn get_dt(&self) -> u8 {
+        self.dt
+    }
+
+    pub fn set_dt(&mut self, value: u8) {
+        self.dt = value;
+    }
+
+    pub fn get_st(&self) -> u8 {
+        self.st
+    }
+
+    pub fn set_st(&mut self, value: u8) {
+        self.st = value;
+    }
+
+    pub fn get_stack(&self) -> &[u16; 16] {
+        &self.stack
+    }
+
+    pub fn get_memory(&self) -> &[u8; 4096] {
+        &self.ram
+    }
+
+    pub fn get_memory_mut(&mut self) -> &mut [u8; 4096] {
+        &mut self.ram
+    }
+
+    pub fn get_opcode(&self) -> u16 {
+        self.fetch_opcode()
+    }
+}
+
+impl Default for Chip8 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 // Private methods
 impl Chip8 {
-    fn push(&mut self, value: u16) {
+    fn push(&mut self, value: u16) -> Result<(), Error> {
+        if self.sp as usize >= self.stack.len() {
+            return Err(Error::StackOverflow(self.pc));
+        }
         self.stack[self.sp as usize] = value;
         self.sp += 1;
+        Ok(())
     }
 
-    fn pop(&mut self) -> u16 {
+    fn pop(&mut self) -> Result<u16, Error> {
+        if self.sp == 0 {
+            return Err(Error::StackUnderflow(self.pc));
+        }
+        // SP set past the stack from outside, e.g. by a debugger
+        if self.sp as usize > self.stack.len() {
+            return Err(Error::StackOverflow(self.pc));
+        }
         self.sp -= 1;
-        self.stack[self.sp as usize]
+        Ok(self.stack[self.sp as usize])
     }
 
+    // Fetches wrap the PC at 4K, this only keeps it from overflowing
     fn next(&mut self) {
-        self.pc += 2;
+        self.pc = self.pc.wrapping_add(2);
     }
 
     fn skip_if(&mut self, condition: bool) {
         if condition {
-            self.pc += 4;
+            self.pc = self.pc.wrapping_add(4);
         } else {
             self.next();
         }
     }
 
+    // 8xy6/8xyE shift Vy into Vx, or Vx in place with the shift quirk
+    fn shift_source(&self, x: usize, y: usize) -> u8 {
+        if self.quirks.shift {
+            self.v[x]
+        } else {
+            self.v[y]
+        }
+    }
+
+    fn reset_vf(&mut self) {
+        if self.quirks.vf_reset {
+            self.v[0xF] = 0;
+        }
+    }
+
+    // Fx55/Fx65 leave I after the last register unless the load_store quirk is set
+    fn advance_i(&mut self, x: usize) {
+        if !self.quirks.load_store {
+            self.i = self.i.wrapping_add(x as u16 + 1);
+        }
+    }
+
+    // Address I + offset, wrapped at 4K like the PC
+    fn i_addr(&self, offset: usize) -> usize {
+        (self.i as usize + offset) & 0xFFF
+    }
+
     fn fetch_opcode(&self) -> u16 {
         // Fetch opcode from memory
         // Opcodes are 2 bytes long
         // Memory is 8-bit, so we need to combine two bytes
         // Shift first byte left by 8 bits and combine with second byte
-        let pc = self.pc as usize;
+        // Addresses wrap at 4K so a stray PC can't read past the end of RAM
+        let pc = self.pc as usize & 0xFFF;
         let byte1 = self.ram[pc] as u16;
-        let byte2 = self.ram[pc + 1] as u16;
-        byte1 << 8 | byte2
+        let byte2 = self.ram[(pc + 1) & 0xFFF] as u16;
+        (byte1 << 8) | byte2
     }
 
-    fn random_byte(&self) -> u8 {
-        rand::random::<u8>()
+    fn random_byte(&mut self) -> u8 {
+        self.rng.gen()
     }
 
-    fn execute(&mut self, opcode: u16) {
-        let nnn = opcode & 0x0FFF;
-        let kk = (opcode & 0x00FF) as u8;
-        let nibbles = (
-            ((opcode & 0xF000) >> 12) as u8,
-            ((opcode & 0x0F00) >> 8) as u8,
-            ((opcode & 0x00F0) >> 4) as u8,
-            (opcode & 0x000F) as u8,
-        );
-        let x = nibbles.1 as usize;
-        let y = nibbles.2 as usize;
-        let n = nibbles.3 as u16;
-
-        match nibbles {
-            (0x0, 0x0, 0x0, 0x0) => return,
-            (0x0, 0x0, 0xE, 0x0) => self.op_00e0(),
-            (0x0, 0x0, 0xE, 0xE) => self.op_00ee(),
-            (0x0, _, _, _) => self.op_0nnn(nnn),
-            (0x1, _, _, _) => self.op_1nnn(nnn),
-            (0x2, _, _, _) => self.op_2nnn(nnn),
-            (0x3, _, _, _) => self.op_3xkk(x, kk),
-            (0x4, _, _, _) => self.op_4xkk(x, kk),
-            (0x5, _, _, 0x0) => self.op_5xy0(x, y),
-            (0x6, _, _, _) => self.op_6xkk(x, kk),
-            (0x7, _, _, _) => self.op_7xkk(x, kk),
-            (0x8, _, _, 0x0) => self.op_8xy0(x, y),
-            (0x8, _, _, 0x1) => self.op_8xy1(x, y),
-            (0x8, _, _, 0x2) => self.op_8xy2(x, y),
-            (0x8, _, _, 0x3) => self.op_8xy3(x, y),
-            (0x8, _, _, 0x4) => self.op_8xy4(x, y),
-            (0x8, _, _, 0x5) => self.op_8xy5(x, y),
-            (0x8, _, _, 0x6) => self.op_8xy6(x),
-            (0x8, _, _, 0x7) => self.op_8xy7(x, y),
-            (0x8, _, _, 0xE) => self.op_8xye(x),
-            (0x9, _, _, 0x0) => self.op_9xy0(x, y),
-            (0xA, _, _, _) => self.op_annn(nnn),
-            (0xB, _, _, _) => self.op_bnnn(nnn),
-            (0xC, _, _, _) => self.op_cxkk(x, kk),
-            (0xD, _, _, _) => self.op_dxyn(x, y, n),
-            (0xE, _, 0x9, 0xE) => self.op_ex9e(x),
-            (0xE, _, 0xA, 0x1) => self.op_exa1(x),
-            (0xF, _, 0x0, 0x7) => self.op_fx07(x),
-            (0xF, _, 0x0, 0xA) => self.op_fx0a(x),
-            (0xF, _, 0x1, 0x5) => self.op_fx15(x),
-            (0xF, _, 0x1, 0x8) => self.op_fx18(x),
-            (0xF, _, 0x1, 0xE) => self.op_fx1e(x),
-            (0xF, _, 0x2, 0x9) => self.op_fx29(x),
-            (0xF, _, 0x3, 0x3) => self.op_fx33(x),
-            (0xF, _, 0x5, 0x5) => self.op_fx55(x),
-            (0xF, _, 0x6, 0x5) => self.op_fx65(x),
-            _ => panic!("Unknown opcode: {:04X}", opcode),
+    fn execute(&mut self, opcode: u16) -> Result<(), Error> {
+        match decode(opcode) {
+            Some(instruction) => self.execute_instruction(instruction),
+            None => Err(Error::UnknownOpcode(self.pc, opcode)),
+        }
+    }
+
+    pub(crate) fn execute_instruction(&mut self, instruction: Instruction) -> Result<(), Error> {
+        use Instruction::*;
+
+        match instruction {
+            Halt => {}
+            Cls => self.op_00e0(),
+            Ret => self.op_00ee()?,
+            Sys(nnn) => self.op_0nnn(nnn),
+            Jp(nnn) => self.op_1nnn(nnn),
+            Call(nnn) => self.op_2nnn(nnn)?,
+            SeByte(x, kk) => self.op_3xkk(x, kk),
+            SneByte(x, kk) => self.op_4xkk(x, kk),
+            SeReg(x, y) => self.op_5xy0(x, y),
+            LdByte(x, kk) => self.op_6xkk(x, kk),
+            AddByte(x, kk) => self.op_7xkk(x, kk),
+            LdReg(x, y) => self.op_8xy0(x, y),
+            Or(x, y) => self.op_8xy1(x, y),
+            And(x, y) => self.op_8xy2(x, y),
+            Xor(x, y) => self.op_8xy3(x, y),
+            AddReg(x, y) => self.op_8xy4(x, y),
+            Sub(x, y) => self.op_8xy5(x, y),
+            Shr(x, y) => self.op_8xy6(x, y),
+            Subn(x, y) => self.op_8xy7(x, y),
+            Shl(x, y) => self.op_8xye(x, y),
+            SneReg(x, y) => self.op_9xy0(x, y),
+            LdI(nnn) => self.op_annn(nnn),
+            JpV0(nnn) => self.op_bnnn(nnn),
+            Rnd(x, kk) => self.op_cxkk(x, kk),
+            Drw(x, y, n) => self.op_dxyn(x, y, n),
+            Skp(x) => self.op_ex9e(x),
+            Sknp(x) => self.op_exa1(x),
+            LdVxDt(x) => self.op_fx07(x),
+            LdVxK(x) => self.op_fx0a(x),
+            LdDtVx(x) => self.op_fx15(x),
+            LdStVx(x) => self.op_fx18(x),
+            AddI(x) => self.op_fx1e(x),
+            LdF(x) => self.op_fx29(x),
+            LdB(x) => self.op_fx33(x),
+            LdIVx(x) => self.op_fx55(x),
+            LdVxI(x) => self.op_fx65(x),
+            _ => return Err(Error::Unsupported(self.pc, self.fetch_opcode())),
         }
+        Ok(())
     }
 }
 
@@ -206,12 +412,18 @@ impl Chip8 {
 impl Chip8 {
     // Clear display
     fn op_00e0(&mut self) {
-        self.display = [false; 64 * 32];
+        for (y, row) in self.display.iter_mut().enumerate() {
+            if *row != 0 {
+                self.dirty_rows |= 1 << y;
+            }
+            *row = 0;
+        }
         self.next();
     }
     // Return from subroutine
-    fn op_00ee(&mut self) {
-        self.pc = self.pop();
+    fn op_00ee(&mut self) -> Result<(), Error> {
+        self.pc = self.pop()?;
+        Ok(())
     }
     // Jump to address nnn
     fn op_0nnn(&mut self, nnn: u16) {
@@ -222,9 +434,10 @@ impl Chip8 {
         self.pc = nnn;
     }
     // Call subroutine at nnn
-    fn op_2nnn(&mut self, nnn: u16) {
-        self.push(self.pc + 2);
+    fn op_2nnn(&mut self, nnn: u16) -> Result<(), Error> {
+        self.push(self.pc.wrapping_add(2))?;
         self.pc = nnn;
+        Ok(())
     }
     // Skip next instruction if Vx == kk
     fn op_3xkk(&mut self, x: usize, kk: u8) {
@@ -256,16 +469,19 @@ impl Chip8 {
     // Set Vx = Vx OR Vy
     fn op_8xy1(&mut self, x: usize, y: usize) {
         self.v[x] |= self.v[y];
+        self.reset_vf();
         self.next();
     }
     // Set Vx = Vx AND Vy
     fn op_8xy2(&mut self, x: usize, y: usize) {
         self.v[x] &= self.v[y];
+        self.reset_vf();
         self.next();
     }
     // Set Vx = Vx XOR Vy
     fn op_8xy3(&mut self, x: usize, y: usize) {
         self.v[x] ^= self.v[y];
+        self.reset_vf();
         self.next();
     }
     // Set Vx = Vx + Vy, set VF = carry
@@ -283,9 +499,11 @@ impl Chip8 {
         self.next();
     }
     // Set Vx = Vx SHR 1
-    fn op_8xy6(&mut self, x: usize) {
-        self.v[0xF] = self.v[x] & 0x1;
-        self.v[x] >>= 1;
+    fn op_8xy6(&mut self, x: usize, y: usize) {
+        // VF first, then the shift reads its source again, so 8FY6 ends up
+        // with the shifted flag in VF as it always has
+        self.v[0xF] = self.shift_source(x, y) & 0x1;
+        self.v[x] = self.shift_source(x, y) >> 1;
         self.next();
     }
     // Set Vx = Vy - Vx, set VF = NOT borrow
@@ -296,9 +514,10 @@ impl Chip8 {
         self.next();
     }
     // Set Vx = Vx SHL 1
-    fn op_8xye(&mut self, x: usize) {
-        self.v[0xF] = self.v[x] >> 7;
-        self.v[x] <<= 1;
+    fn op_8xye(&mut self, x: usize, y: usize) {
+        // Same order as 8xy6
+        self.v[0xF] = self.shift_source(x, y) >> 7;
+        self.v[x] = self.shift_source(x, y) << 1;
         self.next();
     }
     // Skip next instruction if Vx != Vy
@@ -310,9 +529,14 @@ impl Chip8 {
         self.i = nnn;
         self.next();
     }
-    // Jump to location nnn + V0
+    // Jump to location nnn + V0, or xnn + Vx with the jump quirk
     fn op_bnnn(&mut self, nnn: u16) {
-        self.pc = nnn + self.v[0] as u16;
+        let x = if self.quirks.jump {
+            (nnn >> 8) as usize
+        } else {
+            0
+        };
+        self.pc = nnn + self.v[x] as u16;
     }
     // Set Vx = random byte AND kk
     fn op_cxkk(&mut self, x: usize, kk: u8) {
@@ -321,35 +545,43 @@ impl Chip8 {
     }
     // Display n-byte sprite starting at memory location I at (Vx, Vy), set VF = collision
     fn op_dxyn(&mut self, x: usize, y: usize, n: u16) {
-        let vx = self.v[x];
-        let vy = self.v[y];
+        // The starting position always wraps, the rest of the sprite only
+        // wraps without the clipping quirk
+        let vx = self.v[x] as usize % 64;
+        let vy = self.v[y] as usize % 32;
         self.v[0xF] = 0;
-        for byte in 0..n {
-            let sprite = self.ram[self.i as usize + byte as usize];
-            for bit in 0..8 {
-                let x = (vx + bit) % 64;
-                let y = (vy + byte as u8) % 32;
-                let pixel = (sprite >> (7 - bit)) & 0x1;
-                let index = x as usize + y as usize * 64;
-                if pixel == 1 && self.display[index] {
-                    self.v[0xF] = 1;
-                }
-                self.display[index] ^= pixel == 1;
+        for byte in 0..n as usize {
+            if self.quirks.clipping && vy + byte >= 32 {
+                break;
+            }
+            let y = (vy + byte) % 32;
+            let sprite = (self.ram[self.i_addr(byte)] as u64) << 56;
+            let bits = if self.quirks.clipping {
+                sprite >> vx
+            } else {
+                sprite.rotate_right(vx as u32)
+            };
+            if self.display[y] & bits != 0 {
+                self.v[0xF] = 1;
+            }
+            self.display[y] ^= bits;
+            if bits != 0 {
+                self.dirty_rows |= 1 << y;
             }
         }
         self.next();
     }
     // Skip next instruction if key with the value of Vx is pressed
     fn op_ex9e(&mut self, x: usize) {
-        if self.keys[self.v[x] as usize] {
-            self.pc += 4;
+        if self.keys[self.v[x] as usize & 0xF] {
+            self.pc = self.pc.wrapping_add(4);
         } else {
             self.next();
         }
     }
     // Skip next instruction if key with the value of Vx is not pressed
     fn op_exa1(&mut self, x: usize) {
-        self.skip_if(!self.keys[self.v[x] as usize])
+        self.skip_if(!self.keys[self.v[x] as usize & 0xF])
     }
     // Set Vx = delay timer value
     fn op_fx07(&mut self, x: usize) {
@@ -378,7 +610,7 @@ impl Chip8 {
     }
     // Set I = I + Vx
     fn op_fx1e(&mut self, x: usize) {
-        self.i += self.v[x] as u16;
+        self.i = self.i.wrapping_add(self.v[x] as u16);
         self.next();
     }
     // Set I = location of sprite for digit Vx
@@ -389,27 +621,70 @@ impl Chip8 {
     // Store BCD representation of Vx in memory locations I, I+1, and I+2
     fn op_fx33(&mut self, x: usize) {
         let vx = self.v[x];
-        self.ram[self.i as usize] = vx / 100;
-        self.ram[self.i as usize + 1] = (vx / 10) % 10;
-        self.ram[self.i as usize + 2] = vx % 10;
+        self.ram[self.i_addr(0)] = vx / 100;
+        self.ram[self.i_addr(1)] = (vx / 10) % 10;
+        self.ram[self.i_addr(2)] = vx % 10;
         self.next();
     }
     // Store registers V0 through Vx in memory starting at location I
     fn op_fx55(&mut self, x: usize) {
         for i in 0..=x {
-            self.ram[self.i as usize + i as usize] = self.v[i as usize];
+            self.ram[self.i_addr(i)] = self.v[i];
         }
+        self.advance_i(x);
         self.next();
     }
     // Read registers V0 through Vx from memory starting at location I
     fn op_fx65(&mut self, x: usize) {
         for i in 0..=x {
-            self.v[i as usize] = self.ram[self.i as usize + i as usize];
+            self.v[i] = self.ram[self.i_addr(i)];
         }
+        self.advance_i(x);
         self.next();
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    // Opcode that doesn't decode to any instruction, with the PC it was fetched from
+    UnknownOpcode(u16, u16),
+    // SUPER-CHIP or XO-CHIP instruction, which the interpreter doesn't run
+    Unsupported(u16, u16),
+    // 2nnn with all 16 stack slots in use
+    StackOverflow(u16),
+    // 00EE with an empty stack
+    StackUnderflow(u16),
+    // ROM of this many bytes, more than MAX_ROM_SIZE
+    RomTooLarge(usize),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::UnknownOpcode(pc, opcode) => {
+                write!(f, "Unknown opcode: {:04X} at {:04X}", opcode, pc)
+            }
+            Error::Unsupported(pc, opcode) => {
+                let platform = decode(*opcode).map(|i| i.platform());
+                write!(f, "Unsupported opcode: {:04X} at {:04X}", opcode, pc)?;
+                match platform {
+                    Some(platform) => write!(f, " (needs {})", platform),
+                    None => Ok(()),
+                }
+            }
+            Error::StackOverflow(pc) => write!(f, "Stack overflow at {:04X}", pc),
+            Error::StackUnderflow(pc) => write!(f, "Stack underflow at {:04X}", pc),
+            Error::RomTooLarge(len) => write!(
+                f,
+                "ROM is {} bytes, only {} fit in memory",
+                len, MAX_ROM_SIZE
+            ),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
 impl Display for Chip8 {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         let mut s = String::new();
@@ -422,7 +697,6 @@ impl Display for Chip8 {
         s.push_str(&format!("ST: {:02X}\n", self.st));
         s.push_str(&format!("V: {:02X?}\n", self.v));
         s.push_str(&format!("Stack: {:04X?}\n", self.stack));
-        // s.push_str(&format!("Display: {:02X?}\n", self.display));
         s.push_str(&format!("Keys: {:02X?}\n", self.keys));
         write!(f, "{}", s)
     }
@@ -447,3 +721,72 @@ const FONT: [u8; FONT_SIZE] = [
     0xF0, 0x80, 0xF0, 0x80, 0xF0, // E
     0xF0, 0x80, 0xF0, 0x80, 0x80, // F
 ];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(quirks: Quirks, rom: &[u8]) -> Chip8 {
+        let mut chip8 = Chip8::with_quirks(quirks);
+        chip8.load_rom(rom).unwrap();
+        for _ in 0..rom.len() / 2 {
+            chip8.try_cycle().unwrap();
+        }
+        chip8
+    }
+
+    #[test]
+    fn shifts_into_vf() {
+        // LD VF, 03; SHR VF
+        let chip8 = run(Quirks::default(), &[0x6F, 0x03, 0x8F, 0x06]);
+        assert_eq!(chip8.get_registers()[0xF], 0x00);
+        // LD VF, 81; SHL VF
+        let chip8 = run(Quirks::default(), &[0x6F, 0x81, 0x8F, 0x0E]);
+        assert_eq!(chip8.get_registers()[0xF], 0x02);
+        // LD V1, 03; LD VF, 00; SHR VF, V1 without the shift quirk
+        let quirks = quirks::Platform::Chip8.quirks();
+        let chip8 = run(quirks, &[0x61, 0x03, 0x6F, 0x00, 0x8F, 0x16]);
+        assert_eq!(chip8.get_registers()[0xF], 0x01);
+    }
+
+    #[test]
+    fn memory_access_wraps_at_4k() {
+        // LD I, FF0; LD [I], VF; JP 202, moving I past the end of memory
+        let mut chip8 = Chip8::with_quirks(quirks::Platform::Chip8.quirks());
+        chip8
+            .load_rom(&[0xAF, 0xF0, 0xFF, 0x55, 0x12, 0x02])
+            .unwrap();
+        for _ in 0..1000 {
+            chip8.try_cycle().unwrap();
+        }
+        // LD I, FFF; LD V0, 7B; LD B, V0
+        let chip8 = run(Quirks::default(), &[0xAF, 0xFF, 0x60, 0x7B, 0xF0, 0x33]);
+        assert_eq!(chip8.ram[0xFFF], 1);
+        assert_eq!(chip8.ram[0x000], 2);
+        assert_eq!(chip8.ram[0x001], 3);
+    }
+
+    #[test]
+    fn pc_and_keys_from_a_debugger_dont_panic() {
+        let mut chip8 = Chip8::new();
+        // LD V0, FF at the end of memory; SKP V0 at 0
+        chip8.get_memory_mut()[0xFFE..].copy_from_slice(&[0x60, 0xFF]);
+        chip8.get_memory_mut()[..2].copy_from_slice(&[0xE0, 0x9E]);
+        chip8.set_pc(0xFFFE);
+        chip8.try_cycle().unwrap();
+        assert_eq!(chip8.get_pc(), 0);
+        chip8.try_cycle().unwrap();
+        assert_eq!(chip8.get_pc(), 2);
+    }
+
+    #[test]
+    fn rejects_roms_too_large_for_memory() {
+        let mut chip8 = Chip8::new();
+        assert!(chip8.load_rom(&[0xFF; MAX_ROM_SIZE]).is_ok());
+        assert_eq!(chip8.get_memory()[0xFFF], 0xFF);
+        assert_eq!(
+            chip8.load_rom(&[0; MAX_ROM_SIZE + 1]),
+            Err(Error::RomTooLarge(MAX_ROM_SIZE + 1))
+        );
+    }
+}
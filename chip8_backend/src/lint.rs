@@ -0,0 +1,482 @@
+// Static ROM analysis
+//
+// Follows a ROM's control flow from 0x200 without running it, tracking what
+// is known about I along each path, and reports code that is likely broken or
+// that only works on some platforms. Bnnn targets depend on V0 at runtime and
+// aren't followed, so code only reachable through them is not checked.
+
+use crate::decode::{decode, Instruction};
+use crate::quirks::{Platform, Quirks};
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
+use std::fmt::Display;
+
+const START: usize = 0x200;
+const MEMORY_SIZE: usize = 4096;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    Info,
+    Warning,
+    Error,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub addr: u16,
+    pub severity: Severity,
+    pub message: String,
+}
+
+pub struct LintReport {
+    // Sorted by address
+    pub diagnostics: Vec<Diagnostic>,
+    // Oldest platform with every reachable instruction
+    pub platform: Platform,
+    // Quirks the ROM's behaviour depends on, by field name in Quirks
+    pub quirks: Vec<&'static str>,
+    // Reachable instruction bytes
+    pub code: Vec<bool>,
+}
+
+impl LintReport {
+    pub fn has_warnings(&self) -> bool {
+        self.diagnostics
+            .iter()
+            .any(|d| d.severity >= Severity::Warning)
+    }
+
+    // The platform's behaviour for the quirks the ROM was seen to depend on,
+    // the interpreter's defaults for the rest
+    pub fn suggested_quirks(&self) -> Quirks {
+        let preset = self.platform.quirks();
+        let mut quirks = Quirks::default();
+        for name in &self.quirks {
+            if let (Some(quirk), Some(value)) = (quirks.get_mut(name), preset.get(name)) {
+                *quirk = value;
+            }
+        }
+        quirks
+    }
+}
+
+impl Display for Severity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Severity::Info => write!(f, "info"),
+            Severity::Warning => write!(f, "warning"),
+            Severity::Error => write!(f, "error"),
+        }
+    }
+}
+
+impl Display for Diagnostic {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:04X}: {}: {}", self.addr, self.severity, self.message)
+    }
+}
+
+// What's known about I on a path
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum IValue {
+    Known(u16),
+    // Set by Fx29
+    Font,
+    Unknown,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct State {
+    i: IValue,
+    // Fx55/Fx65 ran since I was last set, so I depends on the load_store quirk
+    after_load_store: bool,
+    // VF was last written by 8xy1/8xy2/8xy3, so it depends on the vf_reset quirk
+    vf_from_logic: bool,
+}
+
+impl State {
+    fn join(self, other: State) -> State {
+        State {
+            i: if self.i == other.i {
+                self.i
+            } else {
+                IValue::Unknown
+            },
+            after_load_store: self.after_load_store || other.after_load_store,
+            vf_from_logic: self.vf_from_logic || other.vf_from_logic,
+        }
+    }
+}
+
+// Subroutine entry, or None for the main program
+type Context = Option<u16>;
+
+struct Linter {
+    memory: Vec<u8>,
+    rom_end: usize,
+    declared: Platform,
+    states: HashMap<(u16, Context), State>,
+    queue: VecDeque<(u16, Context)>,
+    code: Vec<bool>,
+    // Subroutine entries and whether a RET was reached from them
+    subroutines: BTreeMap<u16, bool>,
+    writes: Vec<(u16, u16, u16)>,
+    platform: Platform,
+    quirks: BTreeSet<&'static str>,
+    diagnostics: BTreeSet<(u16, Severity, String)>,
+}
+
+pub fn lint(rom: &[u8], declared: Platform) -> LintReport {
+    let mut memory = vec![0; MEMORY_SIZE];
+    let rom_end = (START + rom.len()).min(MEMORY_SIZE);
+    memory[START..rom_end].copy_from_slice(&rom[..rom_end - START]);
+    let mut linter = Linter {
+        memory,
+        rom_end,
+        declared,
+        states: HashMap::new(),
+        queue: VecDeque::new(),
+        code: vec![false; MEMORY_SIZE],
+        subroutines: BTreeMap::new(),
+        writes: Vec::new(),
+        platform: Platform::Chip8,
+        quirks: BTreeSet::new(),
+        diagnostics: BTreeSet::new(),
+    };
+    let entry = State {
+        i: IValue::Known(0),
+        after_load_store: false,
+        vf_from_logic: false,
+    };
+    linter.visit(START as u16, None, entry);
+    while let Some((addr, context)) = linter.queue.pop_front() {
+        let state = linter.states[&(addr, context)];
+        linter.analyse(addr, context, state);
+    }
+    linter.finish()
+}
+
+impl Linter {
+    fn visit(&mut self, addr: u16, context: Context, state: State) {
+        let key = (addr, context);
+        let joined = match self.states.get(&key) {
+            Some(&old) => {
+                let joined = old.join(state);
+                if joined == old {
+                    return;
+                }
+                joined
+            }
+            None => state,
+        };
+        self.states.insert(key, joined);
+        self.queue.push_back(key);
+    }
+
+    fn report(&mut self, addr: u16, severity: Severity, message: String) {
+        self.diagnostics.insert((addr, severity, message));
+    }
+
+    fn depends_on(&mut self, addr: u16, quirk: &'static str, why: &str) {
+        self.quirks.insert(quirk);
+        self.report(
+            addr,
+            Severity::Info,
+            format!("{}, depends on the {} quirk", why, quirk),
+        );
+    }
+
+    // Addresses wrap at 4K, as in the interpreter
+    fn fetch(&self, addr: u16) -> u16 {
+        let addr = addr as usize & 0xFFF;
+        (self.memory[addr] as u16) << 8 | self.memory[(addr + 1) % MEMORY_SIZE] as u16
+    }
+
+    fn analyse(&mut self, addr: u16, context: Context, mut state: State) {
+        let start = addr as usize;
+        if start < START || start + 1 >= self.rom_end {
+            return;
+        }
+        let opcode = self.fetch(addr);
+        let instruction = match decode(opcode) {
+            Some(instruction) => instruction,
+            None => {
+                self.report(
+                    addr,
+                    Severity::Error,
+                    format!("Reachable undefined opcode {:04X}", opcode),
+                );
+                return;
+            }
+        };
+        let len = instruction.size();
+        for byte in &mut self.code[start..(start + len as usize).min(MEMORY_SIZE)] {
+            *byte = true;
+        }
+
+        let platform = instruction.platform();
+        self.platform = self.platform.max(platform);
+        if platform > self.declared {
+            self.report(
+                addr,
+                Severity::Warning,
+                format!(
+                    "{} needs {}, ROM is declared as {}",
+                    instruction, platform, self.declared
+                ),
+            );
+        }
+        self.check(addr, context, instruction, &mut state);
+
+        let next = addr.wrapping_add(len);
+        let successors: Vec<(u16, Context, State)> = match instruction {
+            Instruction::Halt | Instruction::Exit | Instruction::JpV0(_) => Vec::new(),
+            Instruction::Jp(nnn) => vec![(nnn, context, state)],
+            Instruction::Ret => {
+                if let Some(entry) = context {
+                    self.subroutines.insert(entry, true);
+                }
+                Vec::new()
+            }
+            Instruction::Call(nnn) => {
+                self.subroutines.entry(nnn).or_insert(false);
+                // Whatever the subroutine did to I is unknown after it returns
+                let returned = State {
+                    i: IValue::Unknown,
+                    ..state
+                };
+                vec![(nnn, Some(nnn), state), (next, context, returned)]
+            }
+            _ if instruction.is_skip() => {
+                // Skips the whole of a following F000 nnnn on XO-CHIP
+                let skipped = decode(self.fetch(next)).map_or(2, |i| i.size());
+                vec![
+                    (next, context, state),
+                    (next.wrapping_add(skipped), context, state),
+                ]
+            }
+            _ => vec![(next, context, state)],
+        };
+        for (target, context, state) in successors {
+            let target_addr = target as usize;
+            if target_addr < START || target_addr + 1 >= self.rom_end {
+                self.report(
+                    addr,
+                    Severity::Warning,
+                    format!("Control flow leaves the ROM to {:04X}", target),
+                );
+                continue;
+            }
+            self.visit(target, context, state);
+        }
+    }
+
+    // Diagnostics and quirk dependencies for one instruction, updating state
+    // to what's known after it runs
+    fn check(&mut self, addr: u16, context: Context, instruction: Instruction, state: &mut State) {
+        use Instruction::*;
+
+        if state.vf_from_logic && reads_register(&instruction, 0xF) {
+            self.depends_on(addr, "vf_reset", "VF read after 8xy1/8xy2/8xy3");
+        }
+        if state.after_load_store && uses_i(&instruction) {
+            self.depends_on(addr, "load_store", "I used after Fx55/Fx65");
+        }
+
+        match instruction {
+            Sys(nnn) => self.report(
+                addr,
+                Severity::Warning,
+                format!(
+                    "SYS 0x{:03X} calls machine code, which no interpreter runs",
+                    nnn
+                ),
+            ),
+            Ret if context.is_none() => self.report(
+                addr,
+                Severity::Warning,
+                "RET outside a subroutine".to_string(),
+            ),
+            JpV0(_) => {
+                self.depends_on(addr, "jump", "Indirect jump, targets not analysed");
+            }
+            Shr(x, y) | Shl(x, y) if x != y => {
+                self.depends_on(addr, "shift", "Shift with x != y");
+            }
+            LdI(nnn) if (nnn as usize) < START => self.report(
+                addr,
+                Severity::Warning,
+                format!(
+                    "I set to 0x{:03X}, inside the interpreter area; use Fx29 for font sprites",
+                    nnn
+                ),
+            ),
+            _ => {}
+        }
+
+        if let IValue::Known(i) = state.i {
+            if let Some(range) = instruction.memory_writes(i) {
+                self.writes
+                    .push((addr, range.start as u16, range.end as u16));
+            }
+        }
+        if state.i == IValue::Font && instruction.memory_writes(0).is_some() {
+            self.report(addr, Severity::Warning, "Writes over the font".to_string());
+        }
+
+        state.i = match (instruction, state.i) {
+            (LdI(nnn), _) => IValue::Known(nnn),
+            (LdF(_), _) => IValue::Font,
+            (LdILong, _) => IValue::Known(self.fetch(addr.wrapping_add(2))),
+            (AddI(_), _) => IValue::Unknown,
+            // I after Fx55/Fx65 depends on the load_store quirk; keep the
+            // value from before so writes through it are still checked
+            (_, i) => i,
+        };
+        match instruction {
+            LdI(_) | LdF(_) | LdILong => state.after_load_store = false,
+            LdIVx(_) | LdVxI(_) => state.after_load_store = true,
+            _ => {}
+        }
+        match instruction {
+            Or(0xF, _) | And(0xF, _) | Xor(0xF, _) => state.vf_from_logic = false,
+            Or(..) | And(..) | Xor(..) => state.vf_from_logic = true,
+            _ if writes_register(&instruction, 0xF) => state.vf_from_logic = false,
+            _ => {}
+        }
+    }
+
+    fn finish(mut self) -> LintReport {
+        for (addr, start, end) in std::mem::take(&mut self.writes) {
+            let end = (end as usize).min(MEMORY_SIZE);
+            let start = start as usize;
+            if start < START {
+                self.report(
+                    addr,
+                    Severity::Warning,
+                    format!("Writes to {:03X}, inside the interpreter area", start),
+                );
+            } else if self.code[start.min(end)..end].iter().any(|&c| c) {
+                self.report(
+                    addr,
+                    Severity::Warning,
+                    format!("Writes into code at {:04X}-{:04X}", start, end - 1),
+                );
+            }
+        }
+        let subroutines = std::mem::take(&mut self.subroutines);
+        for (entry, returns) in subroutines {
+            if !returns {
+                self.report(
+                    entry,
+                    Severity::Warning,
+                    "Subroutine has no reachable RET".to_string(),
+                );
+            }
+        }
+        LintReport {
+            diagnostics: self
+                .diagnostics
+                .into_iter()
+                .map(|(addr, severity, message)| Diagnostic {
+                    addr,
+                    severity,
+                    message,
+                })
+                .collect(),
+            platform: self.platform,
+            quirks: self.quirks.into_iter().collect(),
+            code: self.code,
+        }
+    }
+}
+
+fn uses_i(instruction: &Instruction) -> bool {
+    use Instruction::*;
+
+    matches!(
+        instruction,
+        Drw(..) | AddI(_) | LdB(_) | LdIVx(_) | LdVxI(_) | Save(..) | Load(..)
+    )
+}
+
+fn reads_register(instruction: &Instruction, r: usize) -> bool {
+    use Instruction::*;
+
+    match *instruction {
+        SeByte(x, _)
+        | SneByte(x, _)
+        | AddByte(x, _)
+        | Skp(x)
+        | Sknp(x)
+        | LdDtVx(x)
+        | LdStVx(x)
+        | AddI(x)
+        | LdF(x)
+        | LdB(x)
+        | LdHf(x)
+        | Pitch(x) => x == r,
+        SeReg(x, y)
+        | SneReg(x, y)
+        | Or(x, y)
+        | And(x, y)
+        | Xor(x, y)
+        | AddReg(x, y)
+        | Sub(x, y)
+        | Subn(x, y)
+        | Shr(x, y)
+        | Shl(x, y)
+        | Drw(x, y, _) => x == r || y == r,
+        LdReg(_, y) => y == r,
+        JpV0(_) => r == 0,
+        LdIVx(x) | LdRVx(x) => r <= x,
+        Save(x, y) => x.min(y) <= r && r <= x.max(y),
+        _ => false,
+    }
+}
+
+fn writes_register(instruction: &Instruction, r: usize) -> bool {
+    use Instruction::*;
+
+    match *instruction {
+        LdByte(x, _) | AddByte(x, _) | LdReg(x, _) | Rnd(x, _) | LdVxDt(x) | LdVxK(x) => x == r,
+        // These all set VF as a flag
+        AddReg(x, _) | Sub(x, _) | Subn(x, _) | Shr(x, _) | Shl(x, _) => x == r || r == 0xF,
+        Drw(..) => r == 0xF,
+        LdVxI(x) | LdVxR(x) => r <= x,
+        Load(x, y) => x.min(y) <= r && r <= x.max(y),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn skip_at_the_end_of_memory() {
+        let mut rom = vec![0; MEMORY_SIZE - START];
+        // JP FFE, then SE V0, 0 in the last two bytes of memory
+        rom[..2].copy_from_slice(&[0x1F, 0xFE]);
+        rom[0xDFE..].copy_from_slice(&[0x30, 0x00]);
+        let report = lint(&rom, Platform::Chip8);
+        assert!(report.code[0xFFE]);
+        assert!(report
+            .diagnostics
+            .iter()
+            .any(|d| d.addr == 0xFFE && d.message.contains("leaves the ROM")));
+    }
+
+    #[test]
+    fn suggests_quirks_the_rom_depends_on() {
+        // SHR V0, V1 then halt
+        let report = lint(&[0x80, 0x16, 0x12, 0x02], Platform::Chip8);
+        assert_eq!(report.quirks, ["shift"]);
+        assert_eq!(
+            report.suggested_quirks(),
+            Quirks {
+                shift: false,
+                ..Quirks::default()
+            }
+        );
+    }
+}
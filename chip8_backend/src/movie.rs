@@ -0,0 +1,162 @@
+// Recorded input for replaying a run frame for frame
+//
+// A movie holds everything needed to repeat a run exactly: the ROM's hash,
+// quirks, instructions per frame, the seed for Cxkk and every key press with
+// the frame it happened before. Stored as JSON.
+
+use crate::database::sha1;
+use crate::quirks::Quirks;
+use crate::Chip8;
+use serde::{Deserialize, Serialize};
+use std::io::{self, Read, Write};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Input {
+    // Applied before this frame runs
+    pub frame: u64,
+    pub key: u8,
+    pub pressed: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Movie {
+    // SHA-1 of the ROM
+    pub rom: String,
+    pub quirks: Quirks,
+    pub cycles_per_frame: u32,
+    pub seed: u64,
+    // Length of the run
+    pub frames: u64,
+    // In frame order
+    pub inputs: Vec<Input>,
+}
+
+impl Movie {
+    pub fn new(rom: &[u8], quirks: Quirks, cycles_per_frame: u32, seed: u64) -> Self {
+        Self {
+            rom: sha1(rom),
+            quirks,
+            cycles_per_frame,
+            seed,
+            frames: 0,
+            inputs: Vec::new(),
+        }
+    }
+
+    pub fn record(&mut self, frame: u64, key: u8, pressed: bool) {
+        self.inputs.push(Input {
+            frame,
+            key,
+            pressed,
+        });
+        self.frames = self.frames.max(frame + 1);
+    }
+
+    // Call with the number of frames run when recording stops
+    pub fn finish(&mut self, frames: u64) {
+        self.frames = self.frames.max(frames);
+    }
+
+    // A Chip8 set up to replay the movie, if the ROM is the one recorded
+    pub fn start(&self, rom: &[u8]) -> Result<Chip8, String> {
+        let hash = sha1(rom);
+        if hash != self.rom {
+            return Err(format!(
+                "Movie was recorded with ROM {}, not {}",
+                self.rom, hash
+            ));
+        }
+        let mut chip8 = Chip8::with_quirks(self.quirks);
+        chip8.set_seed(self.seed);
+        chip8.load_rom(rom).map_err(|e| e.to_string())?;
+        Ok(chip8)
+    }
+
+    // Presses and releases the keys recorded for a frame
+    pub fn apply(&self, frame: u64, chip8: &mut Chip8) {
+        let start = self.inputs.partition_point(|i| i.frame < frame);
+        for input in self.inputs[start..].iter().take_while(|i| i.frame == frame) {
+            chip8.keypress(input.key, input.pressed);
+        }
+    }
+
+    pub fn write(&self, out: &mut impl Write) -> io::Result<()> {
+        serde_json::to_writer_pretty(&mut *out, self)?;
+        writeln!(out)
+    }
+
+    pub fn read(input: impl Read) -> io::Result<Movie> {
+        let movie: Movie = serde_json::from_reader(input)?;
+        let invalid = |message| Err(io::Error::new(io::ErrorKind::InvalidData, message));
+        for (n, input) in movie.inputs.iter().enumerate() {
+            if input.key > 0xF {
+                return invalid(format!("Input {}: key {} is not 0 to F", n, input.key));
+            }
+            if n > 0 && input.frame < movie.inputs[n - 1].frame {
+                return invalid(format!(
+                    "Input {}: frame {} is out of order",
+                    n, input.frame
+                ));
+            }
+        }
+        Ok(movie)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // V0 = random byte, wait for a key into V1, loop
+    const ROM: &[u8] = &[0xC0, 0xFF, 0xF1, 0x0A, 0x12, 0x00];
+
+    fn movie() -> Movie {
+        let mut movie = Movie::new(ROM, Quirks::default(), 2, 42);
+        movie.record(3, 0x5, true);
+        movie.record(4, 0x5, false);
+        movie.finish(10);
+        movie
+    }
+
+    fn replay(movie: &Movie) -> Chip8 {
+        let mut chip8 = movie.start(ROM).unwrap();
+        for frame in 0..movie.frames {
+            movie.apply(frame, &mut chip8);
+            for _ in 0..movie.cycles_per_frame {
+                chip8.try_cycle().unwrap();
+            }
+        }
+        chip8
+    }
+
+    #[test]
+    fn round_trips_through_json() {
+        let movie = movie();
+        assert_eq!(movie.frames, 10);
+        let mut json = Vec::new();
+        movie.write(&mut json).unwrap();
+        assert_eq!(Movie::read(json.as_slice()).unwrap(), movie);
+    }
+
+    #[test]
+    fn replays_the_same_run() {
+        let movie = movie();
+        let first = replay(&movie);
+        assert_eq!(first.get_registers()[1], 0x5);
+        assert_eq!(first.get_registers(), replay(&movie).get_registers());
+        assert!(movie.start(&[0x12, 0x00]).is_err());
+    }
+
+    #[test]
+    fn rejects_bad_inputs() {
+        let mut bad_key = movie();
+        bad_key.inputs[0].key = 16;
+        let mut out_of_order = movie();
+        out_of_order.inputs.swap(0, 1);
+        for movie in [bad_key, out_of_order] {
+            let mut json = Vec::new();
+            movie.write(&mut json).unwrap();
+            assert!(Movie::read(json.as_slice()).is_err());
+        }
+    }
+}
@@ -0,0 +1,45 @@
+// Hooks for tools that watch execution (tracer, profiler, coverage)
+//
+// Observers are handed each executed instruction along with the state it
+// started from, so several of them can watch the same run.
+
+use crate::decode::{decode, Instruction};
+use crate::{Chip8, Error};
+
+// An executed instruction and the state before it ran
+#[derive(Debug, Clone, Copy)]
+pub struct Step {
+    pub pc: u16,
+    pub opcode: u16,
+    pub instruction: Option<Instruction>,
+    pub i: u16,
+    pub registers: [u8; 16],
+    // Set if the instruction failed, in which case it had no effect
+    pub error: Option<Error>,
+}
+
+pub trait Observer {
+    // Called after each instruction, with chip8 in its new state
+    fn observe(&mut self, step: &Step, chip8: &Chip8);
+}
+
+impl Chip8 {
+    // Like try_cycle, but reports the instruction to each observer
+    pub fn cycle_observed(&mut self, observers: &mut [&mut dyn Observer]) -> Result<(), Error> {
+        let opcode = self.get_opcode();
+        let mut step = Step {
+            pc: self.get_pc(),
+            opcode,
+            instruction: decode(opcode),
+            i: self.get_i(),
+            registers: *self.get_registers(),
+            error: None,
+        };
+        let result = self.try_cycle();
+        step.error = result.err();
+        for observer in observers.iter_mut() {
+            observer.observe(&step, self);
+        }
+        result
+    }
+}
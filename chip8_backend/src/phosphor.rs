@@ -0,0 +1,204 @@
+// Anti-flicker filter
+//
+// Games erase sprites with XOR and draw them again a frame later, so moving
+// sprites blink. Like the phosphor of an old screen, the filter keeps pixels
+// lit for a while after they're cleared. Call update once per frame, even when
+// the display didn't change, then render its output instead of the display.
+
+use crate::render::{fill_argb32, fill_rgba8, Palette, HEIGHT, WIDTH};
+use std::collections::VecDeque;
+use std::fmt;
+use std::str::FromStr;
+
+// Levels below this are shown as off, so fading pixels settle
+const CUTOFF: f32 = 1.0 / 64.0;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Persistence {
+    // Cleared pixels keep this fraction of their brightness each frame
+    Decay(f32),
+    // Pixels lit in any of the last n frames are fully lit
+    FrameOr(usize),
+}
+
+// "decay:0.6" or "or:3"
+impl FromStr for Persistence {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (mode, value) = s
+            .split_once(':')
+            .ok_or_else(|| format!("Expected decay:<fraction> or or:<frames>, got {}", s))?;
+        match mode {
+            "decay" => match value.parse() {
+                Ok(decay) if (0.0..1.0).contains(&decay) => Ok(Persistence::Decay(decay)),
+                _ => Err(format!(
+                    "Decay must be at least 0 and below 1, got {}",
+                    value
+                )),
+            },
+            "or" => match value.parse() {
+                Ok(frames) if frames > 0 => Ok(Persistence::FrameOr(frames)),
+                _ => Err(format!("Frame count must be at least 1, got {}", value)),
+            },
+            _ => Err(format!("Unknown persistence mode {}", mode)),
+        }
+    }
+}
+
+impl fmt::Display for Persistence {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Persistence::Decay(decay) => write!(f, "decay:{}", decay),
+            Persistence::FrameOr(frames) => write!(f, "or:{}", frames),
+        }
+    }
+}
+
+pub struct Phosphor {
+    persistence: Persistence,
+    // Brightness of each pixel from 0.0 to 1.0, row by row
+    levels: Vec<f32>,
+    // Last frames for FrameOr, newest first
+    history: VecDeque<[u64; 32]>,
+}
+
+impl Phosphor {
+    pub fn new(persistence: Persistence) -> Self {
+        Self {
+            persistence,
+            levels: vec![0.0; WIDTH * HEIGHT],
+            history: VecDeque::new(),
+        }
+    }
+
+    pub fn get_persistence(&self) -> Persistence {
+        self.persistence
+    }
+
+    pub fn set_persistence(&mut self, persistence: Persistence) {
+        self.persistence = persistence;
+        self.history.clear();
+    }
+
+    // Forgets previous frames, e.g. after loading a ROM
+    pub fn reset(&mut self) {
+        self.levels.fill(0.0);
+        self.history.clear();
+    }
+
+    // Takes this frame's display, returns whether the output changed
+    pub fn update(&mut self, display: &[u64; 32]) -> bool {
+        match self.persistence {
+            Persistence::Decay(decay) => {
+                let mut changed = false;
+                for (y, &row) in display.iter().enumerate() {
+                    for x in 0..WIDTH {
+                        let level = &mut self.levels[y * WIDTH + x];
+                        let new = if row & (1 << 63 >> x) != 0 {
+                            1.0
+                        } else if *level * decay < CUTOFF {
+                            0.0
+                        } else {
+                            *level * decay
+                        };
+                        changed |= new != *level;
+                        *level = new;
+                    }
+                }
+                changed
+            }
+            Persistence::FrameOr(frames) => {
+                let before = self.combined();
+                self.history.push_front(*display);
+                self.history.truncate(frames);
+                let after = self.combined();
+                for (y, &row) in after.iter().enumerate() {
+                    for x in 0..WIDTH {
+                        self.levels[y * WIDTH + x] = (row & (1 << 63 >> x) != 0) as u8 as f32;
+                    }
+                }
+                before != after
+            }
+        }
+    }
+
+    // Brightness of a pixel, 0.0 to 1.0
+    pub fn get_level(&self, x: usize, y: usize) -> f32 {
+        self.levels[y * WIDTH + x]
+    }
+
+    // Like render::render_rgba8, blending between the palette's first two
+    // colours by brightness
+    pub fn render_rgba8(&self, palette: &Palette, scale: usize, out: &mut [u8]) {
+        fill_rgba8(scale, out, |x, y| palette.blend(self.get_level(x, y)));
+    }
+
+    pub fn render_argb32(&self, palette: &Palette, scale: usize, out: &mut [u32]) {
+        fill_argb32(scale, out, |x, y| palette.blend(self.get_level(x, y)));
+    }
+
+    fn combined(&self) -> [u64; 32] {
+        let mut display = [0; 32];
+        for frame in &self.history {
+            for (row, &other) in display.iter_mut().zip(frame) {
+                *row |= other;
+            }
+        }
+        display
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dot() -> [u64; 32] {
+        let mut display = [0; 32];
+        display[1] = 1 << 62;
+        display
+    }
+
+    #[test]
+    fn decay_fades_out_and_settles() {
+        let mut phosphor = Phosphor::new(Persistence::Decay(0.5));
+        assert!(phosphor.update(&dot()));
+        assert_eq!(phosphor.get_level(1, 1), 1.0);
+        let mut levels = Vec::new();
+        while phosphor.update(&[0; 32]) {
+            levels.push(phosphor.get_level(1, 1));
+        }
+        // 1/64 is the last level kept, then it's cut to 0
+        assert_eq!(levels, [0.5, 0.25, 0.125, 0.0625, 0.03125, 0.015625, 0.0]);
+        assert!(!phosphor.update(&[0; 32]));
+    }
+
+    #[test]
+    fn frame_or_keeps_the_last_frames() {
+        let mut phosphor = Phosphor::new(Persistence::FrameOr(2));
+        assert!(phosphor.update(&dot()));
+        assert!(!phosphor.update(&[0; 32]));
+        assert_eq!(phosphor.get_level(1, 1), 1.0);
+        assert!(phosphor.update(&[0; 32]));
+        assert_eq!(phosphor.get_level(1, 1), 0.0);
+    }
+
+    #[test]
+    fn renders_levels_between_palette_colours() {
+        let mut phosphor = Phosphor::new(Persistence::Decay(0.5));
+        phosphor.update(&dot());
+        phosphor.update(&[0; 32]);
+        let mut out = vec![0; WIDTH * HEIGHT];
+        phosphor.render_argb32(&Palette::default(), 1, &mut out);
+        assert_eq!(out[WIDTH + 1], 0xFF80_8080);
+        assert_eq!(out[0], 0xFF00_0000);
+    }
+
+    #[test]
+    fn parses_persistence() {
+        assert_eq!("decay:0.6".parse(), Ok(Persistence::Decay(0.6)));
+        assert_eq!("or:3".parse(), Ok(Persistence::FrameOr(3)));
+        assert!("decay:1".parse::<Persistence>().is_err());
+        assert!("or:0".parse::<Persistence>().is_err());
+    }
+}
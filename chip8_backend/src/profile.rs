@@ -0,0 +1,320 @@
+// Opt-in profiler
+//
+// Like the Tracer, a Profiler watches instructions run on a Chip8 and keeps
+// statistics about them. Time is measured in instructions, since that's what
+// limits a game at a given instructions-per-frame setting. Call end_frame
+// once per 60Hz frame so per-frame numbers make sense.
+
+use crate::decode::{decode, Instruction};
+use crate::observe::{Observer, Step};
+use crate::{Chip8, Error};
+use std::collections::{BTreeMap, HashMap};
+use std::io::{self, Write};
+
+#[derive(Debug, Default, Clone, Copy)]
+struct Subroutine {
+    calls: u64,
+    // Instructions executed inside the subroutine, including nested calls
+    inclusive: u64,
+    // Instructions executed directly in the subroutine
+    exclusive: u64,
+}
+
+pub struct Profiler {
+    instructions: u64,
+    hits: Vec<u64>,
+    // Shadow call stack of subroutine addresses, following 2nnn/00EE
+    stack: Vec<u16>,
+    // Instruction counts per call stack, for flamegraphs
+    folded: HashMap<Vec<u16>, u64>,
+    calls: HashMap<u16, u64>,
+    // Backward jumps as (from, to) with how often they were taken
+    loops: HashMap<(u16, u16), u64>,
+    draws: u64,
+    frames: u64,
+    wait_frames: u64,
+    waiting: bool,
+}
+
+impl Profiler {
+    pub fn new() -> Self {
+        Self {
+            instructions: 0,
+            hits: vec![0; 4096],
+            stack: Vec::new(),
+            folded: HashMap::new(),
+            calls: HashMap::new(),
+            loops: HashMap::new(),
+            draws: 0,
+            frames: 0,
+            wait_frames: 0,
+            waiting: false,
+        }
+    }
+
+    // Execute one instruction and count it
+    pub fn step(&mut self, chip8: &mut Chip8) -> Result<(), Error> {
+        chip8.cycle_observed(&mut [self])
+    }
+
+    pub fn end_frame(&mut self) {
+        self.frames += 1;
+        if self.waiting {
+            self.wait_frames += 1;
+        }
+        self.waiting = false;
+    }
+
+    // Executions per address
+    pub fn hits(&self) -> &[u64] {
+        &self.hits
+    }
+
+    // Human readable summary, listing the top entries of each table
+    pub fn report(&self, chip8: &Chip8, out: &mut impl Write, top: usize) -> io::Result<()> {
+        let frames = self.frames.max(1) as f64;
+        writeln!(out, "Instructions: {}", self.instructions)?;
+        writeln!(out, "Frames: {}", self.frames)?;
+        writeln!(
+            out,
+            "Instructions per frame: {:.1}",
+            self.instructions as f64 / frames
+        )?;
+        writeln!(
+            out,
+            "Draws: {} ({:.1} per frame)",
+            self.draws,
+            self.draws as f64 / frames
+        )?;
+        writeln!(
+            out,
+            "Frames waiting in Fx0A: {} ({:.1}%)",
+            self.wait_frames,
+            percent(self.wait_frames, self.frames)
+        )?;
+
+        writeln!(out)?;
+        writeln!(out, "Hot addresses:")?;
+        let mut hits: Vec<(usize, u64)> = self
+            .hits
+            .iter()
+            .copied()
+            .enumerate()
+            .filter(|&(_, count)| count > 0)
+            .collect();
+        hits.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        for &(addr, count) in hits.iter().take(top) {
+            writeln!(
+                out,
+                "  {:04X}  {:>10}  {:>5.1}%  {}",
+                addr,
+                count,
+                percent(count, self.instructions),
+                mnemonic(chip8, addr)
+            )?;
+        }
+
+        writeln!(out)?;
+        writeln!(out, "Hot loops:")?;
+        let mut loops: Vec<(&(u16, u16), &u64)> = self.loops.iter().collect();
+        loops.sort_by(|a, b| b.1.cmp(a.1).then(a.0.cmp(b.0)));
+        for (&(from, to), &count) in loops.into_iter().take(top) {
+            let body: u64 = self.hits[to as usize..=from as usize].iter().sum();
+            writeln!(
+                out,
+                "  {:04X}-{:04X}  {:>10} iterations  {:>5.1}% of instructions",
+                to,
+                from,
+                count,
+                percent(body, self.instructions)
+            )?;
+        }
+
+        writeln!(out)?;
+        writeln!(out, "Subroutines:")?;
+        writeln!(
+            out,
+            "  {:<6}  {:>8}  {:>12}  {:>7}  {:>12}  {:>7}",
+            "addr", "calls", "inclusive", "", "exclusive", ""
+        )?;
+        let mut subroutines: Vec<(u16, Subroutine)> = self.subroutines().into_iter().collect();
+        subroutines.sort_by(|a, b| b.1.inclusive.cmp(&a.1.inclusive).then(a.0.cmp(&b.0)));
+        for (addr, sub) in subroutines.into_iter().take(top) {
+            writeln!(
+                out,
+                "  {:04X}    {:>8}  {:>12}  {:>6.1}%  {:>12}  {:>6.1}%",
+                addr,
+                sub.calls,
+                sub.inclusive,
+                percent(sub.inclusive, self.instructions),
+                sub.exclusive,
+                percent(sub.exclusive, self.instructions)
+            )?;
+        }
+        Ok(())
+    }
+
+    // One line per call stack with its instruction count, as consumed by
+    // flamegraph.pl and inferno. Frames are "main" then subroutine addresses.
+    pub fn write_folded(&self, out: &mut impl Write) -> io::Result<()> {
+        let mut stacks: Vec<(&Vec<u16>, &u64)> = self.folded.iter().collect();
+        stacks.sort();
+        for (stack, count) in stacks {
+            write!(out, "main")?;
+            for addr in stack {
+                write!(out, ";sub_{:04X}", addr)?;
+            }
+            writeln!(out, " {}", count)?;
+        }
+        Ok(())
+    }
+
+    fn subroutines(&self) -> BTreeMap<u16, Subroutine> {
+        let mut subroutines: BTreeMap<u16, Subroutine> = BTreeMap::new();
+        for (&addr, &calls) in &self.calls {
+            subroutines.entry(addr).or_default().calls = calls;
+        }
+        for (stack, &count) in &self.folded {
+            if let Some(&top) = stack.last() {
+                subroutines.entry(top).or_default().exclusive += count;
+            }
+            // Recursive calls only count once towards inclusive time
+            let mut seen = stack.clone();
+            seen.sort_unstable();
+            seen.dedup();
+            for addr in seen {
+                subroutines.entry(addr).or_default().inclusive += count;
+            }
+        }
+        subroutines
+    }
+}
+
+impl Observer for Profiler {
+    fn observe(&mut self, step: &Step, chip8: &Chip8) {
+        if step.error.is_some() {
+            return;
+        }
+        // Bnnn can jump past 0xFFF, where fetches wrap to 0
+        let pc = step.pc & 0xFFF;
+        self.instructions += 1;
+        self.hits[pc as usize] += 1;
+        match self.folded.get_mut(&self.stack) {
+            Some(count) => *count += 1,
+            None => {
+                self.folded.insert(self.stack.clone(), 1);
+            }
+        }
+        match step.instruction {
+            Some(Instruction::Call(nnn)) => {
+                self.stack.push(nnn);
+                *self.calls.entry(nnn).or_default() += 1;
+            }
+            Some(Instruction::Ret) => {
+                self.stack.pop();
+            }
+            // Not JP to itself, which is how programs halt rather than a loop
+            Some(Instruction::Jp(nnn)) if nnn < pc => {
+                *self.loops.entry((pc, nnn)).or_default() += 1;
+            }
+            Some(Instruction::Drw(..)) => self.draws += 1,
+            // Fx0A leaves PC alone until a key is pressed
+            Some(Instruction::LdVxK(_)) if chip8.get_pc() == step.pc => self.waiting = true,
+            _ => {}
+        }
+    }
+}
+
+impl Default for Profiler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn percent(part: u64, total: u64) -> f64 {
+    part as f64 * 100.0 / total.max(1) as f64
+}
+
+fn mnemonic(chip8: &Chip8, addr: usize) -> String {
+    let memory = chip8.get_memory();
+    let opcode = (memory[addr] as u16) << 8 | memory[(addr + 1) & 0xFFF] as u16;
+    match decode(opcode) {
+        Some(instruction) => instruction.to_string(),
+        None => format!("??? 0x{:04X}", opcode),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn profile(rom: &[u8], steps: usize) -> Profiler {
+        let mut chip8 = Chip8::new();
+        chip8.load_rom(rom).unwrap();
+        let mut profiler = Profiler::new();
+        for _ in 0..steps {
+            profiler.step(&mut chip8).unwrap();
+        }
+        profiler
+    }
+
+    #[test]
+    fn nested_calls() {
+        let rom = [
+            0x22, 0x06, // 200: CALL 206
+            0x22, 0x06, // 202: CALL 206
+            0x12, 0x04, // 204: JP 204
+            0x22, 0x0C, // 206: CALL 20C
+            0x60, 0x01, // 208: LD V0, 1
+            0x00, 0xEE, // 20A: RET
+            0x61, 0x02, // 20C: LD V1, 2
+            0x00, 0xEE, // 20E: RET
+        ];
+        let profiler = profile(&rom, 15);
+
+        let subroutines = profiler.subroutines();
+        let outer = subroutines[&0x206];
+        assert_eq!((outer.calls, outer.inclusive, outer.exclusive), (2, 10, 6));
+        let inner = subroutines[&0x20C];
+        assert_eq!((inner.calls, inner.inclusive, inner.exclusive), (2, 4, 4));
+
+        let mut folded = Vec::new();
+        profiler.write_folded(&mut folded).unwrap();
+        assert_eq!(
+            String::from_utf8(folded).unwrap(),
+            "main 5\nmain;sub_0206 6\nmain;sub_0206;sub_020C 4\n"
+        );
+        // The final JP 204 halts, it isn't a loop
+        assert!(profiler.loops.is_empty());
+        assert_eq!(profiler.hits()[0x204], 3);
+    }
+
+    #[test]
+    fn backward_jumps_are_loops() {
+        let rom = [
+            0x60, 0x03, // 200: LD V0, 3
+            0x70, 0xFF, // 202: ADD V0, FF
+            0x30, 0x00, // 204: SE V0, 0
+            0x12, 0x02, // 206: JP 202
+            0x12, 0x08, // 208: JP 208
+        ];
+        let profiler = profile(&rom, 14);
+        assert_eq!(profiler.loops.len(), 1);
+        assert_eq!(profiler.loops[&(0x206, 0x202)], 2);
+    }
+
+    #[test]
+    fn jumps_past_the_end_of_memory() {
+        let mut chip8 = Chip8::new();
+        // LD V0, 1; JP V0, FFF to 1000, which fetches the JP 200 at 0
+        chip8.load_rom(&[0x60, 0x01, 0xBF, 0xFF]).unwrap();
+        chip8.get_memory_mut()[..2].copy_from_slice(&[0x12, 0x00]);
+        let mut profiler = Profiler::new();
+        for _ in 0..9 {
+            profiler.step(&mut chip8).unwrap();
+        }
+        assert_eq!(profiler.hits()[0x000], 3);
+        assert!(profiler.loops.is_empty());
+        profiler.report(&chip8, &mut Vec::new(), 10).unwrap();
+    }
+}
@@ -0,0 +1,260 @@
+// Platforms and the behaviour differences ("quirks") between them
+// https://github.com/Timendus/chip8-test-suite#quirks-test
+
+use crate::database;
+use crate::decode::decode;
+use crate::lint::lint;
+use crate::MAX_ROM_SIZE;
+use serde::{Deserialize, Serialize};
+use std::fmt::Display;
+use std::str::FromStr;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Platform {
+    // Original COSMAC VIP interpreter
+    Chip8,
+    // SUPER-CHIP 1.1
+    SuperChip,
+    // Octo's XO-CHIP
+    XoChip,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Quirks {
+    // 8xy6/8xyE shift Vx in place instead of shifting Vy into Vx
+    pub shift: bool,
+    // Fx55/Fx65 leave I alone instead of incrementing it past the last register
+    pub load_store: bool,
+    // Bnnn jumps to xnn + Vx instead of nnn + V0
+    pub jump: bool,
+    // 8xy1/8xy2/8xy3 reset VF to 0
+    pub vf_reset: bool,
+    // Sprites are clipped at the screen edges instead of wrapping around
+    pub clipping: bool,
+}
+
+// Changes to a platform's quirks, parsed from e.g. "shift,clipping=off"; a
+// name on its own turns that quirk on
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Overrides(Vec<(String, bool)>);
+
+// Result of detect, with a human readable reason for the choice
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Detection {
+    pub platform: Platform,
+    pub quirks: Quirks,
+    pub reason: String,
+}
+
+// Guess the platform a ROM was written for: known ROMs come from the
+// database, others from their size and the instructions reachable from 0x200.
+// Unknown ROMs keep the default quirks except where the code reachable from
+// 0x200 depends on one, which is then set as on the detected platform.
+pub fn detect(rom: &[u8]) -> Detection {
+    if let Some(metadata) = database::lookup(rom) {
+        if let Some((platform, quirks)) = metadata.platform {
+            return Detection {
+                platform,
+                quirks,
+                reason: format!("known ROM: {}", metadata.title),
+            };
+        }
+    }
+    if rom.len() > MAX_ROM_SIZE {
+        return Detection {
+            platform: Platform::XoChip,
+            quirks: Quirks::default(),
+            reason: format!("{} bytes is too large for 4K of memory", rom.len()),
+        };
+    }
+    let report = lint(rom, Platform::XoChip);
+    let (platform, reason) = match first_instruction_for(rom, &report.code, report.platform) {
+        Some((addr, text)) => (report.platform, format!("{} at {:04X}", text, addr)),
+        None => (Platform::Chip8, "only CHIP-8 instructions".to_string()),
+    };
+    Detection {
+        platform,
+        quirks: report.suggested_quirks(),
+        reason,
+    }
+}
+
+// First reachable instruction that needs the given platform
+fn first_instruction_for(rom: &[u8], code: &[bool], platform: Platform) -> Option<(u16, String)> {
+    if platform == Platform::Chip8 {
+        return None;
+    }
+    let mut addr = 0x200;
+    while addr + 1 < 0x200 + rom.len() {
+        let offset = addr - 0x200;
+        let opcode = (rom[offset] as u16) << 8 | rom[offset + 1] as u16;
+        match decode(opcode) {
+            Some(instruction) if code[addr] => {
+                if instruction.platform() == platform {
+                    return Some((addr as u16, instruction.to_string()));
+                }
+                addr += instruction.size() as usize;
+            }
+            _ => addr += 1,
+        }
+    }
+    None
+}
+
+impl Platform {
+    pub const ALL: [Platform; 3] = [Platform::Chip8, Platform::SuperChip, Platform::XoChip];
+
+    pub fn quirks(self) -> Quirks {
+        match self {
+            Platform::Chip8 => Quirks {
+                shift: false,
+                load_store: false,
+                jump: false,
+                vf_reset: true,
+                clipping: true,
+            },
+            Platform::SuperChip => Quirks {
+                shift: true,
+                load_store: true,
+                jump: true,
+                vf_reset: false,
+                clipping: true,
+            },
+            Platform::XoChip => Quirks {
+                shift: false,
+                load_store: false,
+                jump: false,
+                vf_reset: false,
+                clipping: false,
+            },
+        }
+    }
+
+    // Short name, as accepted by from_str
+    pub fn name(self) -> &'static str {
+        match self {
+            Platform::Chip8 => "chip8",
+            Platform::SuperChip => "schip",
+            Platform::XoChip => "xochip",
+        }
+    }
+}
+
+impl Display for Platform {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Platform::Chip8 => write!(f, "CHIP-8"),
+            Platform::SuperChip => write!(f, "SUPER-CHIP"),
+            Platform::XoChip => write!(f, "XO-CHIP"),
+        }
+    }
+}
+
+impl FromStr for Platform {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "chip8" | "chip-8" => Ok(Platform::Chip8),
+            "schip" | "superchip" | "super-chip" => Ok(Platform::SuperChip),
+            "xochip" | "xo-chip" => Ok(Platform::XoChip),
+            _ => Err(format!("Unknown platform: {}", s)),
+        }
+    }
+}
+
+impl Quirks {
+    // Names as used by Overrides and the frontends
+    pub const NAMES: [&'static str; 5] = ["shift", "load_store", "jump", "vf_reset", "clipping"];
+
+    pub fn get(&self, name: &str) -> Option<bool> {
+        let mut quirks = *self;
+        quirks.get_mut(name).copied()
+    }
+
+    pub fn get_mut(&mut self, name: &str) -> Option<&mut bool> {
+        match name {
+            "shift" => Some(&mut self.shift),
+            "load_store" => Some(&mut self.load_store),
+            "jump" => Some(&mut self.jump),
+            "vf_reset" => Some(&mut self.vf_reset),
+            "clipping" => Some(&mut self.clipping),
+            _ => None,
+        }
+    }
+}
+
+impl Overrides {
+    pub fn apply(&self, quirks: &mut Quirks) {
+        for (name, value) in &self.0 {
+            if let Some(quirk) = quirks.get_mut(name) {
+                *quirk = *value;
+            }
+        }
+    }
+}
+
+impl FromStr for Overrides {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut overrides = Vec::new();
+        for item in s.split(',').map(str::trim).filter(|item| !item.is_empty()) {
+            let (name, value) = item.split_once('=').unwrap_or((item, "on"));
+            if !Quirks::NAMES.contains(&name) {
+                return Err(format!(
+                    "Unknown quirk {}, expected one of {}",
+                    name,
+                    Quirks::NAMES.join(", ")
+                ));
+            }
+            let value = match value {
+                "on" | "true" | "1" => true,
+                "off" | "false" | "0" => false,
+                _ => return Err(format!("Expected on or off for {}, got {}", name, value)),
+            };
+            overrides.push((name.to_string(), value));
+        }
+        Ok(Overrides(overrides))
+    }
+}
+
+// The interpreter's long-standing behaviour, which matches no single platform
+impl Default for Quirks {
+    fn default() -> Self {
+        Self {
+            shift: true,
+            load_store: true,
+            jump: false,
+            vf_reset: false,
+            clipping: false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unknown_rom_keeps_default_quirks() {
+        // LD V0, 01; ADD V0, V1; JP 204
+        let detection = detect(&[0x60, 0x01, 0x80, 0x14, 0x12, 0x04]);
+        assert_eq!(detection.platform, Platform::Chip8);
+        assert_eq!(detection.quirks, Quirks::default());
+    }
+
+    #[test]
+    fn unknown_rom_gets_quirks_it_depends_on() {
+        // SHR V0, V1; JP 202
+        let detection = detect(&[0x80, 0x16, 0x12, 0x02]);
+        assert_eq!(detection.platform, Platform::Chip8);
+        assert_eq!(
+            detection.quirks,
+            Quirks {
+                shift: false,
+                ..Quirks::default()
+            }
+        );
+    }
+}
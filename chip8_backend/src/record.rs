@@ -0,0 +1,321 @@
+// Recording gameplay as an animated GIF, numbered PNGs or a Y4M video
+//
+// Frames come in at 60 per second as RGBA images, e.g. from
+// filter::Pipeline::apply. The format comes from the file name: .gif, .png for
+// one file per frame numbered after the name, or .y4m, with "-" writing Y4M
+// to stdout for piping into ffmpeg.
+
+use crate::filter::Image;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+const FPS: u64 = 60;
+
+enum Sink {
+    Gif {
+        // Until the first frame gives the size
+        file: Option<BufWriter<File>>,
+        encoder: Option<gif::Encoder<BufWriter<File>>>,
+        // Last frame and when it started, written once the next one differs
+        pending: Option<(Image, u64)>,
+    },
+    Png {
+        // Path without the .png
+        stem: PathBuf,
+    },
+    Y4m {
+        out: Box<dyn Write>,
+        started: bool,
+    },
+}
+
+pub struct Recorder {
+    sink: Sink,
+    // Keep one frame in this many
+    every: u32,
+    // Frames offered so far, kept or not
+    frame: u64,
+    written: u64,
+}
+
+impl Recorder {
+    pub fn create(path: &str, every: u32) -> io::Result<Recorder> {
+        if every == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Can't keep one frame in 0",
+            ));
+        }
+        let sink = if path == "-" {
+            Sink::Y4m {
+                out: Box::new(BufWriter::new(io::stdout())),
+                started: false,
+            }
+        } else {
+            let extension = Path::new(path).extension().and_then(|e| e.to_str());
+            match extension.map(str::to_ascii_lowercase).as_deref() {
+                Some("gif") => Sink::Gif {
+                    file: Some(BufWriter::new(File::create(path)?)),
+                    encoder: None,
+                    pending: None,
+                },
+                Some("png") => Sink::Png {
+                    stem: Path::new(path).with_extension(""),
+                },
+                Some("y4m") => Sink::Y4m {
+                    out: Box::new(BufWriter::new(File::create(path)?)),
+                    started: false,
+                },
+                _ => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("Can't record to {}, use .gif, .png or .y4m", path),
+                    ))
+                }
+            }
+        };
+        Ok(Recorder {
+            sink,
+            every,
+            frame: 0,
+            written: 0,
+        })
+    }
+
+    // Frames written so far
+    pub fn frames(&self) -> u64 {
+        self.written
+    }
+
+    // Call once per frame, every frame
+    pub fn add_frame(&mut self, image: &Image) -> io::Result<()> {
+        let frame = self.frame;
+        self.frame += 1;
+        if !frame.is_multiple_of(self.every as u64) {
+            return Ok(());
+        }
+        match &mut self.sink {
+            Sink::Gif {
+                file,
+                encoder,
+                pending,
+            } => {
+                if encoder.is_none() {
+                    let file = file.take().expect("GIF file already taken");
+                    let mut gif =
+                        gif::Encoder::new(file, image.width as u16, image.height as u16, &[])
+                            .map_err(io::Error::other)?;
+                    gif.set_repeat(gif::Repeat::Infinite)
+                        .map_err(io::Error::other)?;
+                    *encoder = Some(gif);
+                }
+                let encoder = encoder.as_mut().unwrap();
+                match pending.take() {
+                    // Identical frames become one longer frame
+                    Some((last, start)) if last == *image => *pending = Some((last, start)),
+                    // Browsers slow down frames shorter than 2/100 s, so
+                    // drop the last frame instead of writing one
+                    Some((_, start)) if centiseconds(frame) - centiseconds(start) < 2 => {
+                        *pending = Some((image.clone(), start))
+                    }
+                    Some((last, start)) => {
+                        self.written += write_gif_frame(encoder, &last, start, frame)?;
+                        *pending = Some((image.clone(), frame));
+                    }
+                    None => *pending = Some((image.clone(), frame)),
+                }
+            }
+            Sink::Png { stem } => {
+                let path = format!("{}-{:06}.png", stem.display(), self.written);
+                let file = BufWriter::new(File::create(path)?);
+                image.write_png(file)?;
+                self.written += 1;
+            }
+            Sink::Y4m { out, started } => {
+                if !*started {
+                    *started = true;
+                    writeln!(
+                        out,
+                        "YUV4MPEG2 W{} H{} F{}:{} Ip A1:1 C444",
+                        image.width, image.height, FPS, self.every
+                    )?;
+                }
+                writeln!(out, "FRAME")?;
+                out.write_all(&yuv444(image))?;
+                self.written += 1;
+            }
+        }
+        Ok(())
+    }
+
+    // Writes out anything still buffered; returns how many frames were
+    // written in all
+    pub fn finish(mut self) -> io::Result<u64> {
+        match &mut self.sink {
+            Sink::Gif {
+                encoder: Some(encoder),
+                pending: Some((last, start)),
+                ..
+            } => {
+                self.written += write_gif_frame(encoder, last, *start, self.frame)?;
+            }
+            Sink::Y4m { out, .. } => out.flush()?,
+            _ => {}
+        }
+        Ok(self.written)
+    }
+}
+
+// Time at the start of a frame, rounded to GIF's 1/100 s
+fn centiseconds(frame: u64) -> u64 {
+    (frame * 100 + FPS / 2) / FPS
+}
+
+// Shown from frame start until frame end. A GIF frame lasts at most 655.35 s,
+// so longer ones are repeated; returns how many were written.
+fn write_gif_frame(
+    encoder: &mut gif::Encoder<BufWriter<File>>,
+    image: &Image,
+    start: u64,
+    end: u64,
+) -> io::Result<u64> {
+    let (width, height) = (image.width as u16, image.height as u16);
+    let mut colors: HashMap<[u8; 3], u8> = HashMap::new();
+    let mut palette = Vec::new();
+    let mut indices = Vec::with_capacity(image.width * image.height);
+    for pixel in image.pixels.chunks_exact(4) {
+        let rgb = [pixel[0], pixel[1], pixel[2]];
+        let next = colors.len();
+        if next > 255 && !colors.contains_key(&rgb) {
+            break;
+        }
+        let index = *colors.entry(rgb).or_insert_with(|| {
+            palette.extend_from_slice(&rgb);
+            next as u8
+        });
+        indices.push(index);
+    }
+    let mut frame = if indices.len() == image.width * image.height {
+        gif::Frame {
+            width,
+            height,
+            buffer: indices.into(),
+            palette: Some(palette),
+            ..gif::Frame::default()
+        }
+    } else {
+        // Too many colours from filters or phosphor blending; quantize
+        let mut rgba = image.pixels.clone();
+        gif::Frame::from_rgba_speed(width, height, &mut rgba, 10)
+    };
+    let mut delay = centiseconds(end) - centiseconds(start);
+    let mut written = 0;
+    while delay > 0 {
+        frame.delay = delay.min(u16::MAX as u64) as u16;
+        delay -= frame.delay as u64;
+        encoder.write_frame(&frame).map_err(io::Error::other)?;
+        written += 1;
+    }
+    Ok(written)
+}
+
+// Y, U then V planes, BT.601 limited range
+fn yuv444(image: &Image) -> Vec<u8> {
+    let size = image.width * image.height;
+    let mut planes = vec![0; size * 3];
+    for (i, pixel) in image.pixels.chunks_exact(4).enumerate() {
+        let [r, g, b] = [pixel[0] as i32, pixel[1] as i32, pixel[2] as i32];
+        planes[i] = (((66 * r + 129 * g + 25 * b + 128) >> 8) + 16) as u8;
+        planes[size + i] = (((-38 * r - 74 * g + 112 * b + 128) >> 8) + 128) as u8;
+        planes[size * 2 + i] = (((112 * r - 94 * g - 18 * b + 128) >> 8) + 128) as u8;
+    }
+    planes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn path(name: &str) -> String {
+        let dir = std::env::temp_dir().join(format!("chip8-record-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        dir.join(name).to_str().unwrap().to_string()
+    }
+
+    fn solid(value: u8) -> Image {
+        Image::new(
+            2,
+            1,
+            vec![value, value, value, 255, value, value, value, 255],
+        )
+    }
+
+    fn gif_delays(path: &str) -> Vec<u16> {
+        let mut options = gif::DecodeOptions::new();
+        options.set_color_output(gif::ColorOutput::RGBA);
+        let mut decoder = options.read_info(File::open(path).unwrap()).unwrap();
+        let mut delays = Vec::new();
+        while let Some(frame) = decoder.read_next_frame().unwrap() {
+            delays.push(frame.delay);
+        }
+        delays
+    }
+
+    #[test]
+    fn gif_frames_last_until_the_picture_changes() {
+        let path = path("timing.gif");
+        let mut recorder = Recorder::create(&path, 1).unwrap();
+        // 31 frames of black, 1 of white, too short to show, then 28 of grey
+        for frame in 0..60 {
+            let value = match frame {
+                0..=30 => 0,
+                31 => 255,
+                _ => 128,
+            };
+            recorder.add_frame(&solid(value)).unwrap();
+        }
+        assert_eq!(recorder.finish().unwrap(), 2);
+        // Frame 31 starts at 0.52 s, and the grey takes the white's place
+        assert_eq!(gif_delays(&path), [52, 48]);
+    }
+
+    #[test]
+    fn long_gif_frames_are_split() {
+        let path = path("long.gif");
+        let mut recorder = Recorder::create(&path, 1).unwrap();
+        // 700 s of the same picture
+        for _ in 0..700 * FPS {
+            recorder.add_frame(&solid(0)).unwrap();
+        }
+        assert_eq!(recorder.finish().unwrap(), 2);
+        // 70000 centiseconds
+        assert_eq!(gif_delays(&path), [u16::MAX, 4465]);
+    }
+
+    #[test]
+    fn y4m_header_has_the_kept_frame_rate() {
+        let path = path("video.y4m");
+        let mut recorder = Recorder::create(&path, 2).unwrap();
+        for value in [0, 255, 255, 0] {
+            recorder.add_frame(&solid(value)).unwrap();
+        }
+        assert_eq!(recorder.finish().unwrap(), 2);
+        let video = fs::read(&path).unwrap();
+        let header = b"YUV4MPEG2 W2 H1 F60:2 Ip A1:1 C444\nFRAME\n";
+        assert!(video.starts_with(header));
+        // Two frames of three 2-pixel planes
+        assert_eq!(video.len(), header.len() + 6 + b"FRAME\n".len() + 6);
+        // Frames 0 and 2: black is Y 16, white Y 235
+        assert_eq!(video[header.len()], 16);
+        assert_eq!(video[header.len() + 6 + 6], 235);
+    }
+
+    #[test]
+    fn keeps_at_least_one_frame_in_one() {
+        assert!(Recorder::create(&path("none.gif"), 0).is_err());
+        assert!(Recorder::create(&path("video.mp4"), 1).is_err());
+    }
+}
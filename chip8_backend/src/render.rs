@@ -0,0 +1,234 @@
+// Display to pixel buffers
+//
+// Frontends render the whole display into one buffer per frame and upload it
+// as a texture or ImageData, instead of drawing pixels one at a time.
+
+use crate::database::{parse_color, Colors};
+use std::io::{self, Write};
+
+pub const WIDTH: usize = 64;
+pub const HEIGHT: usize = 32;
+
+// Colour for each combination of bit planes: background and foreground for
+// CHIP-8 and SUPER-CHIP, four colours for XO-CHIP's two planes. Only the
+// first plane is drawn so far, so XO-CHIP palettes use their first two.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Palette {
+    colors: Vec<[u8; 3]>,
+}
+
+impl Palette {
+    // Needs at least two colours
+    pub fn new(colors: Vec<[u8; 3]>) -> Self {
+        assert!(colors.len() >= 2, "A palette needs at least two colours");
+        Self { colors }
+    }
+
+    pub fn monochrome(background: [u8; 3], foreground: [u8; 3]) -> Self {
+        Self::new(vec![background, foreground])
+    }
+
+    // Octo's default XO-CHIP colours
+    pub fn xo_chip() -> Self {
+        Self::new(vec![
+            [0x99, 0x66, 0x00],
+            [0xFF, 0xCC, 0x00],
+            [0xFF, 0x66, 0x00],
+            [0x66, 0x22, 0x00],
+        ])
+    }
+
+    // "#rrggbb" strings, as used by the ROM database
+    pub fn from_hex(colors: &[impl AsRef<str>]) -> Option<Self> {
+        let colors = colors
+            .iter()
+            .map(|c| parse_color(c.as_ref()).map(|(r, g, b)| [r, g, b]))
+            .collect::<Option<Vec<_>>>()?;
+        (colors.len() >= 2).then(|| Self::new(colors))
+    }
+
+    pub fn from_database(colors: &Colors) -> Option<Self> {
+        Self::from_hex(&colors.pixels)
+    }
+
+    pub fn colors(&self) -> &[[u8; 3]] {
+        &self.colors
+    }
+
+    fn color(&self, lit: bool) -> [u8; 3] {
+        self.colors[lit as usize]
+    }
+
+    // Between background (0.0) and foreground (1.0)
+    pub(crate) fn blend(&self, level: f32) -> [u8; 3] {
+        let [background, foreground] = [self.colors[0], self.colors[1]];
+        let mix = |b: u8, f: u8| (b as f32 + (f as f32 - b as f32) * level).round() as u8;
+        [
+            mix(background[0], foreground[0]),
+            mix(background[1], foreground[1]),
+            mix(background[2], foreground[2]),
+        ]
+    }
+}
+
+impl Default for Palette {
+    fn default() -> Self {
+        Self::monochrome([0x00, 0x00, 0x00], [0xFF, 0xFF, 0xFF])
+    }
+}
+
+// Bytes needed for an RGBA8 buffer at the given scale
+pub fn rgba8_len(scale: usize) -> usize {
+    WIDTH * scale * HEIGHT * scale * 4
+}
+
+// Writes the display as RGBA8, row by row, each pixel repeated scale times in
+// both directions
+pub fn render_rgba8(display: &[u64; 32], palette: &Palette, scale: usize, out: &mut [u8]) {
+    fill_rgba8(scale, out, |x, y| palette.color(lit(display, x, y)));
+}
+
+// Writes the display as 0xAARRGGBB words, laid out like render_rgba8
+pub fn render_argb32(display: &[u64; 32], palette: &Palette, scale: usize, out: &mut [u32]) {
+    fill_argb32(scale, out, |x, y| palette.color(lit(display, x, y)));
+}
+
+// The display as a PNG, at the given scale
+pub fn write_png(
+    display: &[u64; 32],
+    palette: &Palette,
+    scale: usize,
+    out: impl Write,
+) -> io::Result<()> {
+    if scale == 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Scale must be at least 1",
+        ));
+    }
+    let mut rgba = vec![0; rgba8_len(scale)];
+    render_rgba8(display, palette, scale, &mut rgba);
+    encode_png(&rgba, WIDTH * scale, HEIGHT * scale, out)
+}
+
+// Any RGBA8 image as a PNG, e.g. a filtered frame
+pub fn encode_png(rgba: &[u8], width: usize, height: usize, out: impl Write) -> io::Result<()> {
+    let mut encoder = png::Encoder::new(out, width as u32, height as u32);
+    encoder.set_color(png::ColorType::Rgba);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder
+        .write_header()
+        .and_then(|mut writer| writer.write_image_data(&rgba[..width * height * 4]))
+        .map_err(io::Error::other)
+}
+
+fn lit(display: &[u64; 32], x: usize, y: usize) -> bool {
+    display[y] & (1 << 63 >> x) != 0
+}
+
+// Fills an RGBA8 buffer with the colour of each pixel
+pub(crate) fn fill_rgba8(scale: usize, out: &mut [u8], color: impl Fn(usize, usize) -> [u8; 3]) {
+    assert!(out.len() >= rgba8_len(scale), "RGBA buffer is too small");
+    let pitch = WIDTH * scale * 4;
+    for y in 0..HEIGHT {
+        let line = &mut out[y * scale * pitch..][..pitch];
+        for (x, pixel) in line.chunks_exact_mut(4 * scale).enumerate() {
+            let [r, g, b] = color(x, y);
+            for dot in pixel.chunks_exact_mut(4) {
+                dot.copy_from_slice(&[r, g, b, 0xFF]);
+            }
+        }
+        repeat_line(out, y * scale, scale, pitch);
+    }
+}
+
+// Fills an ARGB32 buffer with the colour of each pixel
+pub(crate) fn fill_argb32(scale: usize, out: &mut [u32], color: impl Fn(usize, usize) -> [u8; 3]) {
+    assert!(
+        out.len() >= rgba8_len(scale) / 4,
+        "ARGB buffer is too small"
+    );
+    let pitch = WIDTH * scale;
+    for y in 0..HEIGHT {
+        let line = &mut out[y * scale * pitch..][..pitch];
+        for (x, pixel) in line.chunks_exact_mut(scale).enumerate() {
+            let [r, g, b] = color(x, y);
+            pixel.fill(0xFF00_0000 | (r as u32) << 16 | (g as u32) << 8 | b as u32);
+        }
+        repeat_line(out, y * scale, scale, pitch);
+    }
+}
+
+// Copies line `first` over the scale - 1 lines after it
+fn repeat_line<T: Copy>(out: &mut [T], first: usize, scale: usize, pitch: usize) {
+    let start = first * pitch;
+    for n in 1..scale {
+        out.copy_within(start..start + pitch, start + n * pitch);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BLUE: [u8; 3] = [0x10, 0x20, 0xC0];
+    const YELLOW: [u8; 3] = [0xFF, 0xCC, 0x00];
+
+    // Top-left and bottom-right pixels lit
+    fn corners() -> [u64; 32] {
+        let mut display = [0; 32];
+        display[0] = 1 << 63;
+        display[31] = 1;
+        display
+    }
+
+    #[test]
+    fn rgba8_repeats_pixels_by_scale() {
+        let palette = Palette::monochrome(BLUE, YELLOW);
+        let mut out = vec![0; rgba8_len(3)];
+        render_rgba8(&corners(), &palette, 3, &mut out);
+        let pixel = |x: usize, y: usize| &out[(y * WIDTH * 3 + x) * 4..][..4];
+        for (x, y) in [(0, 0), (2, 2), (191, 95), (189, 93)] {
+            assert_eq!(pixel(x, y), [YELLOW[0], YELLOW[1], YELLOW[2], 0xFF]);
+        }
+        for (x, y) in [(3, 0), (0, 3), (188, 95), (191, 92)] {
+            assert_eq!(pixel(x, y), [BLUE[0], BLUE[1], BLUE[2], 0xFF]);
+        }
+    }
+
+    #[test]
+    fn argb32_matches_rgba8() {
+        let palette = Palette::from_hex(&["#1020c0", "#ffcc00"]).unwrap();
+        let mut rgba = vec![0; rgba8_len(2)];
+        render_rgba8(&corners(), &palette, 2, &mut rgba);
+        let mut argb = vec![0; rgba8_len(2) / 4];
+        render_argb32(&corners(), &palette, 2, &mut argb);
+        assert_eq!(argb[0], 0xFFFF_CC00);
+        assert_eq!(argb[2], 0xFF10_20C0);
+        for (word, pixel) in argb.iter().zip(rgba.chunks_exact(4)) {
+            assert_eq!(
+                *word,
+                u32::from_be_bytes([pixel[3], pixel[0], pixel[1], pixel[2]])
+            );
+        }
+    }
+
+    #[test]
+    fn palettes_need_two_colours() {
+        assert!(Palette::from_hex(&["#000000"]).is_none());
+        assert!(Palette::from_hex(&["#000000", "yellow"]).is_none());
+        let xo_chip = Palette::xo_chip();
+        assert_eq!(xo_chip.colors().len(), 4);
+        assert_eq!(xo_chip.blend(0.0), xo_chip.colors()[0]);
+        assert_eq!(xo_chip.blend(1.0), xo_chip.colors()[1]);
+        assert_eq!(Palette::default().blend(0.5), [0x80; 3]);
+    }
+
+    #[test]
+    fn png_needs_a_scale() {
+        let mut png = Vec::new();
+        assert!(write_png(&corners(), &Palette::default(), 0, &mut png).is_err());
+        write_png(&corners(), &Palette::default(), 2, &mut png).unwrap();
+        assert!(png.starts_with(b"\x89PNG"));
+    }
+}
@@ -0,0 +1,288 @@
+// Opt-in execution tracing
+//
+// A Tracer watches instructions run on a Chip8 and records what each one did.
+// It either keeps everything until drained to a TraceWriter, or keeps only the
+// last N instructions so they can be dumped after an error.
+//
+// JSON lines format, one object per instruction:
+// {"pc":512,"opcode":24581,"op":"LD V0, 0x05","v":[[0,5]],"i":0,"writes":[]}
+// "v" lists registers that changed as [index, new value], "i" is I after the
+// instruction and "writes" lists memory writes as [address, value].
+//
+// Binary format, all integers little-endian:
+// header: "C8TR", version (u8, currently 1)
+// record: pc (u16), opcode (u16), i (u16),
+//         register count (u8), then per register: index (u8), new value (u8),
+//         write count (u8), then per write: address (u16), value (u8)
+//
+// Reference traces, for comparing against other interpreters, are JSON lines
+// with the full machine state before each instruction:
+// {"pc":512,"opcode":24581,"v":[0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0],"i":0,"sp":0,"dt":0,"st":0}
+// Only "pc" is required, missing fields aren't compared. See TraceState.
+
+use crate::decode::decode;
+use crate::observe::{Observer, Step};
+use crate::{Chip8, Error};
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::io::{self, Write};
+use std::ops::RangeInclusive;
+
+const MAGIC: &[u8; 4] = b"C8TR";
+const VERSION: u8 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TraceFormat {
+    Binary,
+    JsonLines,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceRecord {
+    pub pc: u16,
+    pub opcode: u16,
+    // Registers that changed, as (index, new value)
+    pub registers: Vec<(u8, u8)>,
+    // I after the instruction ran
+    pub i: u16,
+    // Memory writes, as (address, value)
+    pub writes: Vec<(u16, u8)>,
+}
+
+// One line of a reference trace
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TraceState {
+    pub pc: u16,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub opcode: Option<u16>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub v: Option<[u8; 16]>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub i: Option<u16>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sp: Option<u8>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dt: Option<u8>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub st: Option<u8>,
+}
+
+// A field where two states disagree, as (name, expected, actual)
+pub type Mismatch = (String, u16, u16);
+
+impl TraceState {
+    pub fn capture(chip8: &Chip8) -> Self {
+        Self {
+            pc: chip8.get_pc(),
+            opcode: Some(chip8.get_opcode()),
+            v: Some(*chip8.get_registers()),
+            i: Some(chip8.get_i()),
+            sp: Some(chip8.get_sp()),
+            dt: Some(chip8.get_dt()),
+            st: Some(chip8.get_st()),
+        }
+    }
+
+    // Compare against actual, skipping fields either side left out
+    pub fn mismatches(&self, actual: &TraceState) -> Vec<Mismatch> {
+        let mut mismatches = Vec::new();
+        let mut check = |name: &str, expected: Option<u16>, actual: Option<u16>| {
+            if let (Some(expected), Some(actual)) = (expected, actual) {
+                if expected != actual {
+                    mismatches.push((name.to_string(), expected, actual));
+                }
+            }
+        };
+        check("PC", Some(self.pc), Some(actual.pc));
+        check("opcode", self.opcode, actual.opcode);
+        if let (Some(expected), Some(actual)) = (self.v, actual.v) {
+            for x in 0..16 {
+                let name = format!("V{:X}", x);
+                check(&name, Some(expected[x] as u16), Some(actual[x] as u16));
+            }
+        }
+        check("I", self.i, actual.i);
+        check("SP", self.sp.map(u16::from), actual.sp.map(u16::from));
+        check("DT", self.dt.map(u16::from), actual.dt.map(u16::from));
+        check("ST", self.st.map(u16::from), actual.st.map(u16::from));
+        mismatches
+    }
+}
+
+#[derive(Serialize)]
+struct JsonRecord<'a> {
+    pc: u16,
+    opcode: u16,
+    op: String,
+    v: &'a [(u8, u8)],
+    i: u16,
+    writes: &'a [(u16, u8)],
+}
+
+pub struct Tracer {
+    records: VecDeque<TraceRecord>,
+    // Ring buffer size, None keeps everything
+    capacity: Option<usize>,
+    range: Option<RangeInclusive<u16>>,
+}
+
+impl Tracer {
+    pub fn new() -> Self {
+        Self {
+            records: VecDeque::new(),
+            capacity: None,
+            range: None,
+        }
+    }
+
+    // Keep only the last capacity instructions
+    pub fn ring_buffer(capacity: usize) -> Self {
+        Self {
+            records: VecDeque::with_capacity(capacity),
+            capacity: Some(capacity.max(1)),
+            range: None,
+        }
+    }
+
+    // Only record instructions whose address is in range
+    pub fn set_range(&mut self, range: RangeInclusive<u16>) {
+        self.range = Some(range);
+    }
+
+    // Execute one instruction and record it. Failed instructions are recorded
+    // too, so a ring buffer ends with the instruction that caused the error.
+    pub fn step(&mut self, chip8: &mut Chip8) -> Result<(), Error> {
+        chip8.cycle_observed(&mut [self])
+    }
+
+    pub fn records(&self) -> impl Iterator<Item = &TraceRecord> {
+        self.records.iter()
+    }
+
+    pub fn clear(&mut self) {
+        self.records.clear();
+    }
+
+    // Write out and forget everything recorded so far
+    pub fn drain_to<W: Write>(&mut self, writer: &mut TraceWriter<W>) -> io::Result<()> {
+        for record in self.records.drain(..) {
+            writer.write(&record)?;
+        }
+        writer.flush()
+    }
+
+    fn push(&mut self, record: TraceRecord) {
+        if let Some(capacity) = self.capacity {
+            if self.records.len() == capacity {
+                self.records.pop_front();
+            }
+        }
+        self.records.push_back(record);
+    }
+}
+
+impl Observer for Tracer {
+    fn observe(&mut self, step: &Step, chip8: &Chip8) {
+        if let Some(range) = &self.range {
+            if !range.contains(&step.pc) {
+                return;
+            }
+        }
+        let registers = step
+            .registers
+            .iter()
+            .zip(chip8.get_registers())
+            .enumerate()
+            .filter(|(_, (old, new))| old != new)
+            .map(|(x, (_, &new))| (x as u8, new))
+            .collect();
+        let writes = match (step.error, step.instruction) {
+            (None, Some(instruction)) => instruction
+                .memory_writes(step.i)
+                .into_iter()
+                .flatten()
+                .map(|addr| addr & 0xFFF)
+                .map(|addr| (addr as u16, chip8.get_memory()[addr]))
+                .collect(),
+            _ => Vec::new(),
+        };
+        self.push(TraceRecord {
+            pc: step.pc,
+            opcode: step.opcode,
+            registers,
+            i: chip8.get_i(),
+            writes,
+        });
+    }
+}
+
+impl Default for Tracer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub struct TraceWriter<W: Write> {
+    output: W,
+    format: TraceFormat,
+}
+
+impl<W: Write> TraceWriter<W> {
+    // Writes the binary header straight away
+    pub fn new(mut output: W, format: TraceFormat) -> io::Result<Self> {
+        if format == TraceFormat::Binary {
+            output.write_all(MAGIC)?;
+            output.write_all(&[VERSION])?;
+        }
+        Ok(Self { output, format })
+    }
+
+    pub fn write(&mut self, record: &TraceRecord) -> io::Result<()> {
+        match self.format {
+            TraceFormat::JsonLines => self.write_json(record),
+            TraceFormat::Binary => self.write_binary(record),
+        }
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.output.flush()
+    }
+
+    pub fn into_inner(self) -> W {
+        self.output
+    }
+
+    fn write_json(&mut self, record: &TraceRecord) -> io::Result<()> {
+        let op = match decode(record.opcode) {
+            Some(instruction) => instruction.to_string(),
+            None => format!("??? 0x{:04X}", record.opcode),
+        };
+        let json = JsonRecord {
+            pc: record.pc,
+            opcode: record.opcode,
+            op,
+            v: &record.registers,
+            i: record.i,
+            writes: &record.writes,
+        };
+        serde_json::to_writer(&mut self.output, &json)?;
+        self.output.write_all(b"\n")
+    }
+
+    fn write_binary(&mut self, record: &TraceRecord) -> io::Result<()> {
+        let mut bytes = Vec::with_capacity(8 + record.registers.len() * 2);
+        bytes.extend_from_slice(&record.pc.to_le_bytes());
+        bytes.extend_from_slice(&record.opcode.to_le_bytes());
+        bytes.extend_from_slice(&record.i.to_le_bytes());
+        bytes.push(record.registers.len() as u8);
+        for &(x, value) in &record.registers {
+            bytes.extend_from_slice(&[x, value]);
+        }
+        bytes.push(record.writes.len() as u8);
+        for &(addr, value) in &record.writes {
+            bytes.extend_from_slice(&addr.to_le_bytes());
+            bytes.push(value);
+        }
+        self.output.write_all(&bytes)
+    }
+}
@@ -0,0 +1,609 @@
+mod protocol;
+mod symbols;
+
+use chip8_backend::debugger::{Debugger, StopReason};
+use chip8_backend::Chip8;
+use protocol::{read_message, Writer};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::sync::mpsc::{self, TryRecvError};
+use std::time::{Duration, Instant};
+use symbols::SymbolMap;
+
+const CYCLES_PER_FRAME: u32 = 10;
+const FPS: u32 = 60;
+// Frames between chip8/display events while running
+const DISPLAY_INTERVAL: u32 = 6;
+
+const THREAD_ID: i64 = 1;
+const REGISTERS_REF: i64 = 1;
+const STACK_REF: i64 = 2;
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct LaunchArguments {
+    program: PathBuf,
+    symbols: Option<PathBuf>,
+    #[serde(default)]
+    stop_on_entry: bool,
+}
+
+// What the target is doing between requests
+#[derive(Clone, Copy)]
+enum Run {
+    Continue,
+    // Until a different source line starts (or any instruction, without a line table)
+    StepIn { line: Option<(usize, u32)> },
+    // Same as StepIn, but not inside calls made from the current frame
+    StepOver { sp: u8, line: Option<(usize, u32)> },
+    // Until the current subroutine returns
+    StepOut { sp: u8 },
+}
+
+struct Session<W: Write> {
+    out: Writer<W>,
+    debugger: Option<Debugger>,
+    symbols: SymbolMap,
+    stop_on_entry: bool,
+    running: Option<Run>,
+    frames: u32,
+    // Addresses for each source's line breakpoints, replaced per setBreakpoints
+    source_breakpoints: HashMap<PathBuf, Vec<u16>>,
+    instruction_breakpoints: Vec<u16>,
+}
+
+// Usage: chip8_dap
+// Speaks the Debug Adapter Protocol on stdin/stdout. Launch arguments:
+// "program" (ROM path), "symbols" (optional symbol map) and "stopOnEntry".
+fn main() -> io::Result<()> {
+    let (tx, rx) = mpsc::channel();
+    std::thread::spawn(move || {
+        let mut input = io::stdin().lock();
+        while let Ok(Some(message)) = read_message(&mut input) {
+            if tx.send(message).is_err() {
+                break;
+            }
+        }
+    });
+
+    let mut session = Session::new(Writer::new(io::stdout()));
+    let frame = Duration::from_secs(1) / FPS;
+    loop {
+        let message = if session.running.is_some() {
+            match rx.try_recv() {
+                Ok(message) => Some(message),
+                Err(TryRecvError::Empty) => None,
+                Err(TryRecvError::Disconnected) => break,
+            }
+        } else {
+            match rx.recv() {
+                Ok(message) => Some(message),
+                Err(_) => break,
+            }
+        };
+        match message {
+            Some(message) => {
+                if !session.handle(&message)? {
+                    break;
+                }
+            }
+            None => {
+                let start = Instant::now();
+                session.run_frame()?;
+                std::thread::sleep(frame.saturating_sub(start.elapsed()));
+            }
+        }
+    }
+    Ok(())
+}
+
+impl<W: Write> Session<W> {
+    fn new(out: Writer<W>) -> Self {
+        Self {
+            out,
+            debugger: None,
+            symbols: SymbolMap::default(),
+            stop_on_entry: false,
+            running: None,
+            frames: 0,
+            source_breakpoints: HashMap::new(),
+            instruction_breakpoints: Vec::new(),
+        }
+    }
+
+    // Returns false once the client disconnects
+    fn handle(&mut self, request: &Value) -> io::Result<bool> {
+        if request["type"] != "request" {
+            return Ok(true);
+        }
+        let args = &request["arguments"];
+        let command = request["command"].as_str().unwrap_or_default();
+        if self.debugger.is_none() && !matches!(command, "initialize" | "launch" | "disconnect") {
+            self.out.error(request, "No program launched")?;
+            return Ok(true);
+        }
+        let body = match command {
+            "initialize" => Ok(capabilities()),
+            "launch" => self.launch(args),
+            "setBreakpoints" => Ok(self.set_breakpoints(args)),
+            "setInstructionBreakpoints" => Ok(self.set_instruction_breakpoints(args)),
+            "setExceptionBreakpoints" => Ok(json!({})),
+            "configurationDone" => {
+                self.out.response(request, json!({}))?;
+                if self.stop_on_entry {
+                    self.stopped("entry", None)?;
+                } else {
+                    self.running = Some(Run::Continue);
+                }
+                return Ok(true);
+            }
+            "threads" => Ok(json!({ "threads": [{ "id": THREAD_ID, "name": "CHIP-8" }] })),
+            "stackTrace" => Ok(self.stack_trace()),
+            "scopes" => Ok(json!({ "scopes": [
+                { "name": "Registers", "variablesReference": REGISTERS_REF, "expensive": false },
+                { "name": "Stack", "variablesReference": STACK_REF, "expensive": false },
+            ]})),
+            "variables" => Ok(self.variables(args)),
+            "setVariable" => self.set_variable(args),
+            "continue" => {
+                self.running = Some(Run::Continue);
+                Ok(json!({ "allThreadsContinued": true }))
+            }
+            "next" | "stepIn" | "stepOut" => {
+                self.out.response(request, json!({}))?;
+                self.step(command, args)?;
+                return Ok(true);
+            }
+            "pause" => {
+                self.out.response(request, json!({}))?;
+                self.stopped("pause", None)?;
+                return Ok(true);
+            }
+            "readMemory" => Ok(self.read_memory(args)),
+            "writeMemory" => self.write_memory(args),
+            "chip8/keypress" => self.keypress(args),
+            "disconnect" | "terminate" => {
+                self.out.response(request, json!({}))?;
+                self.out.event("terminated", json!({}))?;
+                return Ok(false);
+            }
+            _ => Err(format!("Unsupported request: {}", command)),
+        };
+        match body {
+            Ok(body) => self.out.response(request, body)?,
+            Err(message) => self.out.error(request, &message)?,
+        }
+        if command == "launch" && self.debugger.is_some() {
+            self.out.event("initialized", json!({}))?;
+        }
+        Ok(true)
+    }
+
+    fn launch(&mut self, args: &Value) -> Result<Value, String> {
+        let args: LaunchArguments =
+            serde_json::from_value(args.clone()).map_err(|e| e.to_string())?;
+        let rom = std::fs::read(&args.program)
+            .map_err(|e| format!("Failed to read {}: {}", args.program.display(), e))?;
+        if let Some(path) = &args.symbols {
+            self.symbols = SymbolMap::load(path)
+                .map_err(|e| format!("Failed to load {}: {}", path.display(), e))?;
+        }
+        let mut emu = Chip8::new();
+        emu.load_rom(&rom)
+            .map_err(|e| format!("Failed to load {}: {}", args.program.display(), e))?;
+        let mut debugger = Debugger::new(emu);
+        debugger.set_cycles_per_frame(CYCLES_PER_FRAME);
+        self.debugger = Some(debugger);
+        self.stop_on_entry = args.stop_on_entry;
+        Ok(json!({}))
+    }
+
+    fn set_breakpoints(&mut self, args: &Value) -> Value {
+        let path = PathBuf::from(args["source"]["path"].as_str().unwrap_or_default());
+        let mut addresses = Vec::new();
+        let breakpoints: Vec<Value> = args["breakpoints"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .map(|bp| {
+                let line = bp["line"].as_u64().unwrap_or_default() as u32;
+                match self.symbols.address_for(&path, line) {
+                    Some(addr) => {
+                        addresses.push(addr);
+                        json!({
+                            "verified": true,
+                            "line": line,
+                            "instructionReference": format!("0x{:04X}", addr),
+                        })
+                    }
+                    None => json!({
+                        "verified": false,
+                        "line": line,
+                        "message": "No code at this line",
+                    }),
+                }
+            })
+            .collect();
+        self.source_breakpoints.insert(path, addresses);
+        self.sync_breakpoints();
+        json!({ "breakpoints": breakpoints })
+    }
+
+    fn set_instruction_breakpoints(&mut self, args: &Value) -> Value {
+        self.instruction_breakpoints.clear();
+        let breakpoints: Vec<Value> = args["breakpoints"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .map(|bp| {
+                let offset = bp["offset"].as_i64().unwrap_or_default();
+                match parse_address(&bp["instructionReference"]) {
+                    Some(addr) => {
+                        let addr = (addr as i64 + offset) as u16;
+                        self.instruction_breakpoints.push(addr);
+                        json!({ "verified": true, "instructionReference": format!("0x{:04X}", addr) })
+                    }
+                    None => json!({ "verified": false }),
+                }
+            })
+            .collect();
+        self.sync_breakpoints();
+        json!({ "breakpoints": breakpoints })
+    }
+
+    fn sync_breakpoints(&mut self) {
+        let debugger = self.debugger.as_mut().unwrap();
+        debugger.clear_breakpoints();
+        let lines = self.source_breakpoints.values().flatten();
+        for &addr in lines.chain(&self.instruction_breakpoints) {
+            debugger.add_breakpoint(addr);
+        }
+    }
+
+    fn stack_trace(&self) -> Value {
+        let chip8 = self.debugger.as_ref().unwrap().chip8();
+        let sp = (chip8.get_sp() as usize).min(16);
+        // Each return address points just past its 2nnn
+        let calls = chip8.get_stack()[..sp]
+            .iter()
+            .rev()
+            .map(|&ret| ret.wrapping_sub(2));
+        let frames: Vec<Value> = std::iter::once(chip8.get_pc())
+            .chain(calls)
+            .enumerate()
+            .map(|(id, addr)| self.frame(id, addr))
+            .collect();
+        json!({ "stackFrames": frames, "totalFrames": frames.len() })
+    }
+
+    fn frame(&self, id: usize, addr: u16) -> Value {
+        let name = match self.symbols.label_for(addr) {
+            Some(label) => format!("{} (0x{:04X})", label, addr),
+            None => format!("0x{:04X}", addr),
+        };
+        let mut frame = json!({
+            "id": id,
+            "name": name,
+            "line": 0,
+            "column": 0,
+            "instructionPointerReference": format!("0x{:04X}", addr),
+        });
+        if let Some(entry) = self.symbols.line_for(addr) {
+            frame["line"] = json!(entry.line);
+            frame["column"] = json!(1);
+            frame["source"] = json!({
+                "name": entry.file.file_name().map(|n| n.to_string_lossy()),
+                "path": entry.file,
+            });
+        }
+        frame
+    }
+
+    fn variables(&self, args: &Value) -> Value {
+        let chip8 = self.debugger.as_ref().unwrap().chip8();
+        let variables: Vec<Value> = match args["variablesReference"].as_i64() {
+            Some(REGISTERS_REF) => {
+                let mut variables: Vec<Value> = chip8
+                    .get_registers()
+                    .iter()
+                    .enumerate()
+                    .map(|(x, &v)| byte_variable(&format!("V{:X}", x), v))
+                    .collect();
+                variables.push(json!({
+                    "name": "I",
+                    "value": format!("0x{:04X}", chip8.get_i()),
+                    "variablesReference": 0,
+                    "memoryReference": format!("0x{:04X}", chip8.get_i()),
+                }));
+                variables.push(json!({
+                    "name": "PC",
+                    "value": format!("0x{:04X}", chip8.get_pc()),
+                    "variablesReference": 0,
+                    "memoryReference": format!("0x{:04X}", chip8.get_pc()),
+                }));
+                variables.push(byte_variable("SP", chip8.get_sp()));
+                variables.push(byte_variable("DT", chip8.get_dt()));
+                variables.push(byte_variable("ST", chip8.get_st()));
+                variables
+            }
+            Some(STACK_REF) => {
+                let sp = (chip8.get_sp() as usize).min(16);
+                chip8.get_stack()[..sp]
+                    .iter()
+                    .enumerate()
+                    .map(|(n, &ret)| {
+                        json!({
+                            "name": format!("[{}]", n),
+                            "value": format!("0x{:04X}", ret),
+                            "variablesReference": 0,
+                            "memoryReference": format!("0x{:04X}", ret),
+                        })
+                    })
+                    .collect()
+            }
+            _ => Vec::new(),
+        };
+        json!({ "variables": variables })
+    }
+
+    fn set_variable(&mut self, args: &Value) -> Result<Value, String> {
+        let name = args["name"].as_str().unwrap_or_default();
+        let value = parse_address(&args["value"]).ok_or("Expected a number")?;
+        if args["variablesReference"].as_i64() != Some(REGISTERS_REF) {
+            return Err("Only registers can be changed".to_string());
+        }
+        let chip8 = self.debugger.as_mut().unwrap().chip8_mut();
+        let byte = u8::try_from(value).map_err(|_| format!("{} is 8-bit", name));
+        match name {
+            "I" | "PC" if value > 0xFFF => {
+                return Err(format!("{} must be below 0x1000", name));
+            }
+            "I" => chip8.set_i(value),
+            "PC" => chip8.set_pc(value),
+            "SP" => chip8.set_sp(byte?.min(16)),
+            "DT" => chip8.set_dt(byte?),
+            "ST" => chip8.set_st(byte?),
+            _ => {
+                let x = name
+                    .strip_prefix('V')
+                    .and_then(|x| usize::from_str_radix(x, 16).ok())
+                    .filter(|&x| x < 16)
+                    .ok_or_else(|| format!("Unknown register {}", name))?;
+                chip8.set_register(x, byte?);
+            }
+        }
+        Ok(json!({ "value": format!("0x{:X}", value) }))
+    }
+
+    fn step(&mut self, command: &str, args: &Value) -> io::Result<()> {
+        let debugger = self.debugger.as_mut().unwrap();
+        let chip8 = debugger.chip8();
+        let sp = chip8.get_sp();
+        let instruction = args["granularity"] == "instruction";
+        let run = match command {
+            "stepOut" => Run::StepOut { sp },
+            _ if instruction && command == "stepIn" => {
+                let reason = debugger.step();
+                return self.stop(reason);
+            }
+            _ => {
+                let line = if instruction {
+                    None
+                } else {
+                    self.symbols
+                        .line_for(chip8.get_pc())
+                        .map(line_key(&self.symbols))
+                };
+                if command == "next" {
+                    Run::StepOver { sp, line }
+                } else {
+                    Run::StepIn { line }
+                }
+            }
+        };
+        self.running = Some(run);
+        Ok(())
+    }
+
+    // Run one frame's worth of instructions towards the current goal
+    fn run_frame(&mut self) -> io::Result<()> {
+        let run = match self.running {
+            Some(run) => run,
+            None => return Ok(()),
+        };
+        let symbols = &self.symbols;
+        let has_lines = !symbols.lines.is_empty();
+        // A new line starts here, and it isn't the one we're stepping from
+        let new_line = |chip8: &Chip8, from: Option<(usize, u32)>| {
+            let pc = chip8.get_pc();
+            !has_lines
+                || (symbols.is_line_start(pc)
+                    && symbols.line_for(pc).map(line_key(symbols)) != from)
+        };
+        let debugger = self.debugger.as_mut().unwrap();
+        let reason = match run {
+            Run::Continue => debugger.resume(CYCLES_PER_FRAME),
+            Run::StepIn { line } => {
+                debugger.resume_until(CYCLES_PER_FRAME, |chip8| new_line(chip8, line))
+            }
+            Run::StepOver { sp, line } => debugger.resume_until(CYCLES_PER_FRAME, |chip8| {
+                chip8.get_sp() <= sp && new_line(chip8, line)
+            }),
+            Run::StepOut { sp } => {
+                debugger.resume_until(CYCLES_PER_FRAME, |chip8| chip8.get_sp() < sp)
+            }
+        };
+        match reason {
+            Some(reason) => self.stop(reason),
+            None => {
+                self.frames += 1;
+                if self.frames.is_multiple_of(DISPLAY_INTERVAL) {
+                    self.display()?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    fn stop(&mut self, reason: StopReason) -> io::Result<()> {
+        match reason {
+            StopReason::Step => self.stopped("step", None),
+            StopReason::Breakpoint(_) => self.stopped("breakpoint", None),
+            StopReason::Error(e) => self.stopped("exception", Some(e.to_string())),
+        }
+    }
+
+    fn stopped(&mut self, reason: &str, text: Option<String>) -> io::Result<()> {
+        self.running = None;
+        let mut body = json!({
+            "reason": reason,
+            "threadId": THREAD_ID,
+            "allThreadsStopped": true,
+        });
+        if let Some(text) = text {
+            body["description"] = json!(text);
+            body["text"] = json!(text);
+        }
+        self.out.event("stopped", body)?;
+        self.display()
+    }
+
+    // Custom event so an editor extension can preview the screen. Each row is
+    // 64 pixels as 16 hex digits, most significant bit leftmost.
+    fn display(&mut self) -> io::Result<()> {
+        let display = self.debugger.as_ref().unwrap().chip8().get_display();
+        let rows: Vec<String> = display.iter().map(|row| format!("{:016x}", row)).collect();
+        self.out.event(
+            "chip8/display",
+            json!({ "width": 64, "height": 32, "rows": rows }),
+        )
+    }
+
+    fn read_memory(&self, args: &Value) -> Value {
+        let memory = self.debugger.as_ref().unwrap().chip8().get_memory();
+        let start = memory_start(args).min(memory.len());
+        // Never more than all of memory, whatever the client asks for
+        let count = args["count"]
+            .as_u64()
+            .unwrap_or_default()
+            .min(memory.len() as u64) as usize;
+        let end = start.saturating_add(count).min(memory.len());
+        json!({
+            "address": format!("0x{:04X}", start),
+            "data": base64_encode(&memory[start..end]),
+            "unreadableBytes": count - (end - start),
+        })
+    }
+
+    fn write_memory(&mut self, args: &Value) -> Result<Value, String> {
+        let memory = self.debugger.as_mut().unwrap().chip8_mut().get_memory_mut();
+        let data = base64_decode(args["data"].as_str().unwrap_or_default())
+            .ok_or("Invalid base64 data")?;
+        let start = memory_start(args);
+        if start.saturating_add(data.len()) > memory.len() {
+            return Err("Write past the end of memory".to_string());
+        }
+        memory[start..start + data.len()].copy_from_slice(&data);
+        Ok(json!({ "offset": 0, "bytesWritten": data.len() }))
+    }
+
+    // Custom request so an editor extension can forward key presses
+    fn keypress(&mut self, args: &Value) -> Result<Value, String> {
+        let key = args["key"]
+            .as_u64()
+            .filter(|&k| k < 16)
+            .ok_or("Key must be 0-F")?;
+        let pressed = args["pressed"].as_bool().unwrap_or(true);
+        let chip8 = self.debugger.as_mut().unwrap().chip8_mut();
+        chip8.keypress(key as u8, pressed);
+        Ok(json!({}))
+    }
+}
+
+fn capabilities() -> Value {
+    json!({
+        "supportsConfigurationDoneRequest": true,
+        "supportsSetVariable": true,
+        "supportsSteppingGranularity": true,
+        "supportsInstructionBreakpoints": true,
+        "supportsReadMemoryRequest": true,
+        "supportsWriteMemoryRequest": true,
+        "supportsTerminateRequest": true,
+    })
+}
+
+// Identifies a source line independent of which instruction on it we're at
+fn line_key(symbols: &SymbolMap) -> impl Fn(&symbols::LineEntry) -> (usize, u32) + '_ {
+    move |entry| {
+        let file = symbols.lines.iter().position(|e| e.file == entry.file);
+        (file.unwrap_or_default(), entry.line)
+    }
+}
+
+fn byte_variable(name: &str, value: u8) -> Value {
+    json!({
+        "name": name,
+        "value": format!("0x{:02X}", value),
+        "variablesReference": 0,
+    })
+}
+
+// Memory references are addresses like "0x0200"
+fn memory_start(args: &Value) -> usize {
+    let base = parse_address(&args["memoryReference"]).unwrap_or_default() as i64;
+    base.saturating_add(args["offset"].as_i64().unwrap_or_default())
+        .max(0) as usize
+}
+
+// Accepts "0x200", "512" or a JSON number
+fn parse_address(value: &Value) -> Option<u16> {
+    if let Some(n) = value.as_u64() {
+        return u16::try_from(n).ok();
+    }
+    let s = value.as_str()?.trim();
+    match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => u16::from_str_radix(hex, 16).ok(),
+        None => s.parse().ok(),
+    }
+}
+
+const BASE64: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+fn base64_encode(bytes: &[u8]) -> String {
+    let mut out = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let b = [
+            chunk[0],
+            *chunk.get(1).unwrap_or(&0),
+            *chunk.get(2).unwrap_or(&0),
+        ];
+        let n = (b[0] as u32) << 16 | (b[1] as u32) << 8 | b[2] as u32;
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(BASE64[(n >> (18 - 6 * i) & 0x3F) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+fn base64_decode(s: &str) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(s.len() / 4 * 3);
+    let mut bits = 0u32;
+    let mut count = 0;
+    for c in s.bytes().filter(|&c| c != b'=') {
+        let value = BASE64.iter().position(|&b| b == c)? as u32;
+        bits = bits << 6 | value;
+        count += 6;
+        if count >= 8 {
+            count -= 8;
+            out.push((bits >> count) as u8);
+        }
+    }
+    Some(out)
+}
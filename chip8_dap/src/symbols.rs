@@ -0,0 +1,85 @@
+// Symbol map produced alongside an assembled ROM:
+//
+// {
+//   "labels": { "main": 512, "draw-player": 546 },
+//   "lines": [
+//     { "file": "game.8o", "line": 4, "address": 512 },
+//     { "file": "game.8o", "line": 5, "address": 514 }
+//   ]
+// }
+//
+// "lines" is the assembler's line table: one entry per emitted instruction.
+// Relative file paths are resolved against the symbol map's own directory.
+
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct LineEntry {
+    pub file: PathBuf,
+    pub line: u32,
+    pub address: u16,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct SymbolMap {
+    #[serde(default)]
+    pub labels: BTreeMap<String, u16>,
+    #[serde(default)]
+    pub lines: Vec<LineEntry>,
+}
+
+impl SymbolMap {
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let text = std::fs::read_to_string(path).map_err(|e| e.to_string())?;
+        let mut map: SymbolMap = serde_json::from_str(&text).map_err(|e| e.to_string())?;
+        let dir = path.parent().unwrap_or(Path::new("."));
+        for entry in map.lines.iter_mut() {
+            if entry.file.is_relative() {
+                entry.file = dir.join(&entry.file);
+            }
+        }
+        map.lines.sort_by_key(|entry| entry.address);
+        Ok(map)
+    }
+
+    // Line table entry covering addr: the last one at or before it
+    pub fn line_for(&self, addr: u16) -> Option<&LineEntry> {
+        let index = self.lines.partition_point(|entry| entry.address <= addr);
+        self.lines[..index].last()
+    }
+
+    // True if addr is the first instruction of a source line
+    pub fn is_line_start(&self, addr: u16) -> bool {
+        self.lines
+            .binary_search_by_key(&addr, |e| e.address)
+            .is_ok()
+    }
+
+    // Lowest address emitted for file:line
+    pub fn address_for(&self, file: &Path, line: u32) -> Option<u16> {
+        self.lines
+            .iter()
+            .filter(|entry| entry.line == line && same_file(&entry.file, file))
+            .map(|entry| entry.address)
+            .min()
+    }
+
+    // Closest label at or before addr, for naming stack frames
+    pub fn label_for(&self, addr: u16) -> Option<&str> {
+        self.labels
+            .iter()
+            .filter(|(_, &label)| label <= addr)
+            .max_by_key(|(_, &label)| label)
+            .map(|(name, _)| name.as_str())
+    }
+}
+
+// Editors send absolute paths, which may differ from ours by symlinks
+fn same_file(a: &Path, b: &Path) -> bool {
+    match (a.canonicalize(), b.canonicalize()) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => a == b,
+    }
+}
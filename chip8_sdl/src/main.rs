@@ -1,149 +1,513 @@
+use chip8_backend::audio::{Generator, WavWriter, Waveform};
+use chip8_backend::coverage::Coverage;
+use chip8_backend::database::{self, Metadata};
+use chip8_backend::filter::Pipeline;
+use chip8_backend::movie::Movie;
+use chip8_backend::observe::Observer;
+use chip8_backend::phosphor::{Persistence, Phosphor};
+use chip8_backend::profile::Profiler;
+use chip8_backend::quirks::{detect, Platform};
+use chip8_backend::record::Recorder;
+use chip8_backend::render::{self, Palette};
 use chip8_backend::Chip8;
 use sdl2::{
     audio::{AudioCallback, AudioSpecDesired},
     event::Event,
     keyboard::Keycode,
-    pixels::Color,
-    rect::Rect,
-    render::Canvas,
-    video::Window,
+    pixels::PixelFormatEnum,
 };
 use std::time::Duration;
 
 const CYCLES_PER_FRAME: u32 = 10;
 const FPS: u32 = 60;
+// For --record-audio
+const SAMPLE_RATE: u32 = 44100;
+// Window pixels per CHIP-8 pixel
+const SCALE: usize = 10;
 const KEYMAP: &[Keycode; 16] = COLEMAK_DH;
 
-fn main() {
-    let mut emu = Chip8::new();
+// Number of entries listed in each table of the profile report
+const PROFILE_TOP: usize = 20;
+
+struct Options {
+    rom_name: String,
+    // Write <prefix>.txt and <prefix>.folded profiles on exit
+    profile: Option<String>,
+    // Write <prefix>.txt disassembly and <prefix>.png heatmap on exit
+    coverage: Option<String>,
+    // Overrides the detected platform
+    platform: Option<Platform>,
+    // Anti-flicker filter
+    phosphor: Option<Persistence>,
+    filters: Pipeline,
+    // Record the screen from the start; F9 stops and starts recording
+    record: Option<String>,
+    // Keep one frame in this many when recording
+    every: u32,
+    // Write the keys pressed to a movie on exit, for chip8-headless --movie
+    record_movie: Option<String>,
+    // Write the sound to a WAV file, a frame's worth of samples per frame
+    record_audio: Option<String>,
+    // The tone played while the sound timer runs
+    waveform: Option<Waveform>,
+    pitch: Option<f32>,
+    volume: Option<f32>,
+}
+
+// Usage: chip8_sdl <rom> [--profile <prefix>] [--coverage <prefix>]
+//                        [--platform chip8|schip|xochip]
+//                        [--phosphor decay:<fraction>|or:<frames>]
+//                        [--filter <filter>[,<filter>...]]
+//                        [--record <file.gif|file.png|file.y4m>] [--every <n>]
+//                        [--record-movie <file.json>]
+//                        [--record-audio <file.wav>]
+//                        [--waveform square|sine|triangle|noise]
+//                        [--pitch <Hz>] [--volume <0-1>]
+fn parse_args() -> Options {
+    let mut rom_name = None;
+    let mut profile = None;
+    let mut coverage = None;
+    let mut platform = None;
+    let mut phosphor = None;
+    let mut filters = Pipeline::default();
+    let mut record = None;
+    let mut every = 1;
+    let mut record_movie = None;
+    let mut record_audio = None;
+    let mut waveform = None;
+    let mut pitch = None;
+    let mut volume = None;
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--profile" => profile = Some(args.next().expect("--profile needs a path")),
+            "--coverage" => coverage = Some(args.next().expect("--coverage needs a path")),
+            "--platform" => {
+                let name = args.next().expect("--platform needs a name");
+                platform = Some(name.parse().unwrap_or_else(|e| panic!("{}", e)));
+            }
+            "--phosphor" => {
+                let mode = args.next().expect("--phosphor needs a mode");
+                phosphor = Some(mode.parse().unwrap_or_else(|e| panic!("{}", e)));
+            }
+            "--filter" => {
+                let names = args.next().expect("--filter needs a list of filters");
+                filters = names.parse().unwrap_or_else(|e| panic!("{}", e));
+            }
+            "--record" => record = Some(args.next().expect("--record needs a path")),
+            "--every" => {
+                let n = args.next().expect("--every needs a number");
+                every = n
+                    .parse()
+                    .ok()
+                    .filter(|&n| n > 0)
+                    .expect("--every needs a number of at least 1");
+            }
+            "--record-movie" => {
+                record_movie = Some(args.next().expect("--record-movie needs a path"))
+            }
+            "--record-audio" => {
+                record_audio = Some(args.next().expect("--record-audio needs a path"))
+            }
+            "--waveform" => {
+                let name = args.next().expect("--waveform needs a name");
+                waveform = Some(name.parse().unwrap_or_else(|e| panic!("{}", e)));
+            }
+            "--pitch" => {
+                let hz = args.next().expect("--pitch needs a frequency");
+                pitch = Some(
+                    hz.parse()
+                        .ok()
+                        .filter(|hz: &f32| hz.is_finite() && *hz > 0.0)
+                        .expect("--pitch needs a frequency above 0"),
+                );
+            }
+            "--volume" => {
+                let level = args.next().expect("--volume needs a level");
+                volume = Some(
+                    level
+                        .parse()
+                        .ok()
+                        .filter(|level| (0.0..=1.0).contains(level))
+                        .expect("--volume needs a level from 0 to 1"),
+                );
+            }
+            _ => rom_name = Some(arg),
+        }
+    }
+    Options {
+        rom_name: rom_name.expect("No ROM provided"),
+        profile,
+        coverage,
+        platform,
+        phosphor,
+        filters,
+        record,
+        every,
+        record_movie,
+        record_audio,
+        waveform,
+        pitch,
+        volume,
+    }
+}
 
-    let rom_name = std::env::args().nth(1).expect("No ROM provided");
+fn main() {
+    let options = parse_args();
+    let rom_name = options.rom_name.clone();
     let rom = std::fs::read(rom_name.clone()).expect("Failed to read ROM");
 
-    emu.load_rom(&rom);
+    let detection = detect(&rom);
+    let quirks = match options.platform {
+        Some(platform) => {
+            println!("Platform: {} (from --platform)", platform);
+            platform.quirks()
+        }
+        None => {
+            println!("Platform: {} ({})", detection.platform, detection.reason);
+            detection.quirks
+        }
+    };
+    let metadata = database::lookup(&rom);
+    let controls = metadata.as_ref().map(controls).unwrap_or_default();
+    let cycles_per_frame = metadata
+        .as_ref()
+        .and_then(|m| m.tickrate)
+        .unwrap_or(CYCLES_PER_FRAME);
+    let palette = metadata
+        .as_ref()
+        .and_then(|m| m.colors.as_ref())
+        .and_then(Palette::from_database)
+        .unwrap_or_default();
+    if rom.len() > 4096 - 0x200 {
+        panic!(
+            "ROM is too large, {} ROMs aren't supported",
+            detection.platform
+        );
+    }
+
+    let mut emu = Chip8::with_quirks(quirks);
+    let mut movie = options.record_movie.as_ref().map(|_| {
+        // Any seed will do, as long as the movie has it
+        let seed = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_or(0, |d| d.as_nanos() as u64);
+        emu.set_seed(seed);
+        Movie::new(&rom, quirks, cycles_per_frame, seed)
+    });
+    emu.load_rom(&rom).unwrap_or_else(|e| panic!("{}", e));
+    let mut profiler = options.profile.as_ref().map(|_| Profiler::new());
+    let mut coverage = options.coverage.as_ref().map(|_| Coverage::new());
+    let mut phosphor = options.phosphor.map(Phosphor::new);
 
     let sdl_context = sdl2::init().unwrap();
 
     let audio_subsystem = sdl_context.audio().unwrap();
     let spec = AudioSpecDesired {
-        freq: Some(3000),
+        freq: Some(SAMPLE_RATE as i32),
         channels: Some(1),
         samples: None,
     };
 
-    let device = audio_subsystem
-        .open_playback(None, &spec, |spec| SquareWave {
-            phase_inc: 440.0 / spec.freq as f32,
-            phase: 0.0,
-            volume: 0.25,
+    // Plays all the time, silent unless the sound timer is running
+    let mut device = audio_subsystem
+        .open_playback(None, &spec, |spec| {
+            Speaker(generator(spec.freq as u32, &options))
         })
         .unwrap();
+    device.resume();
 
     let video_subsystem = sdl_context.video().unwrap();
-    let title = format!("Chip-8 Emulator | {}", rom_name.split('/').last().unwrap());
+    let name = match &metadata {
+        Some(metadata) => metadata.display_name(),
+        None => std::path::Path::new(&rom_name)
+            .file_name()
+            .map_or(rom_name.clone(), |name| name.to_string_lossy().into_owned()),
+    };
+    let title = format!("Chip-8 Emulator | {}", name);
     let window = video_subsystem
-        .window(title.as_str(), 640, 320)
+        .window(
+            title.as_str(),
+            (render::WIDTH * SCALE) as u32,
+            (render::HEIGHT * SCALE) as u32,
+        )
         .position_centered()
         .opengl()
         .build()
         .unwrap();
 
     let mut canvas = window.into_canvas().build().unwrap();
-
-    canvas.set_draw_color(Color::RGB(0, 0, 0));
-    canvas.clear();
-    canvas.present();
+    let texture_creator = canvas.texture_creator();
+    // The display at one texel per pixel, stretched to the window when copied,
+    // or at window size when filtered
+    let texture_scale = if options.filters.is_empty() { 1 } else { SCALE };
+    let mut texture = texture_creator
+        .create_texture_streaming(
+            PixelFormatEnum::RGBA32,
+            (render::WIDTH * texture_scale) as u32,
+            (render::HEIGHT * texture_scale) as u32,
+        )
+        .unwrap();
+    let mut pixels = vec![0; render::rgba8_len(1)];
 
     let mut event_pump = sdl_context.event_pump().unwrap();
+    let mut frame: u64 = 0;
+    let mut audio = options.record_audio.as_ref().map(|path| {
+        let file =
+            std::io::BufWriter::new(std::fs::File::create(path).expect("Failed to create WAV"));
+        let wav = WavWriter::new(file, SAMPLE_RATE).expect("Failed to write WAV");
+        (generator(SAMPLE_RATE, &options), wav)
+    });
+    let mut samples = Vec::new();
+    let mut recorder = options
+        .record
+        .as_ref()
+        .map(|path| Recorder::create(path, options.every).expect("Failed to start recording"));
 
     'running: loop {
-        canvas.set_draw_color(Color::RGB(0, 0, 0));
-        canvas.clear();
-        canvas.set_draw_color(Color::RGB(255, 255, 255));
         for event in event_pump.poll_iter() {
             match event {
                 Event::Quit { .. } => break 'running,
+                // Saves what's on screen, filters and all
+                Event::KeyDown {
+                    keycode: Some(Keycode::F12),
+                    repeat: false,
+                    ..
+                } => {
+                    let path = screenshot_path(&rom_name, frame);
+                    let file = std::fs::File::create(&path).expect("Failed to create screenshot");
+                    options
+                        .filters
+                        .apply(&pixels, SCALE)
+                        .write_png(std::io::BufWriter::new(file))
+                        .expect("Failed to write screenshot");
+                    println!("Wrote {}", path);
+                }
+                Event::KeyDown {
+                    keycode: Some(Keycode::F9),
+                    repeat: false,
+                    ..
+                } => match recorder.take() {
+                    Some(recorder) => stop_recording(recorder),
+                    None => {
+                        let path = recording_path(&rom_name, options.record.as_deref(), frame);
+                        recorder = Some(
+                            Recorder::create(&path, options.every)
+                                .expect("Failed to start recording"),
+                        );
+                    }
+                },
                 Event::KeyDown {
                     keycode: Some(keycode),
+                    repeat,
                     ..
                 } => {
-                    if let Some(key) = key_to_input(keycode) {
+                    if let Some(key) = key_to_input(keycode, &controls) {
                         emu.keypress(key, true);
+                        if let (Some(movie), false) = (movie.as_mut(), repeat) {
+                            movie.record(frame, key, true);
+                        }
                     }
                 }
                 Event::KeyUp {
                     keycode: Some(keycode),
                     ..
                 } => {
-                    if let Some(key) = key_to_input(keycode) {
+                    if let Some(key) = key_to_input(keycode, &controls) {
                         emu.keypress(key, false);
+                        if let Some(movie) = movie.as_mut() {
+                            movie.record(frame, key, false);
+                        }
                     }
                 }
                 _ => {}
             }
         }
 
-        for _ in 0..CYCLES_PER_FRAME {
-            emu.cycle();
+        let mut observers: Vec<&mut dyn Observer> = Vec::new();
+        if let Some(profiler) = profiler.as_mut() {
+            observers.push(profiler);
+        }
+        if let Some(coverage) = coverage.as_mut() {
+            observers.push(coverage);
+        }
+        for _ in 0..cycles_per_frame {
+            if let Err(e) = emu.cycle_observed(&mut observers) {
+                panic!("{}", e);
+            }
         }
         emu.cycle_timer();
-        draw(&mut canvas, &emu.get_display());
-        play(&device, emu.get_sound());
+        if let Some(profiler) = profiler.as_mut() {
+            profiler.end_frame();
+        }
+        // Only redraw when a pixel changed
+        let changed = match phosphor.as_mut() {
+            Some(phosphor) => phosphor.update(emu.get_display()),
+            None => emu.frame_changed(),
+        };
+        if changed {
+            match &phosphor {
+                Some(phosphor) => phosphor.render_rgba8(&palette, 1, &mut pixels),
+                None => render::render_rgba8(emu.get_display(), &palette, 1, &mut pixels),
+            }
+            if options.filters.is_empty() {
+                texture.update(None, &pixels, render::WIDTH * 4)
+            } else {
+                let image = options.filters.apply(&pixels, SCALE);
+                texture.update(None, &image.pixels, image.width * 4)
+            }
+            .expect("Failed to update texture");
+            canvas
+                .copy(&texture, None, None)
+                .expect("Failed to copy texture");
+            canvas.present();
+        }
+        emu.clear_dirty();
+        if let Some(recorder) = recorder.as_mut() {
+            recorder
+                .add_frame(&options.filters.apply(&pixels, SCALE))
+                .expect("Failed to record frame");
+        }
+        device.lock().0.set_sound(emu.get_sound());
+        if let Some((generator, wav)) = audio.as_mut() {
+            generator.render_frame(emu.get_sound(), &mut samples);
+            wav.write(&samples).expect("Failed to write WAV");
+            samples.clear();
+        }
 
         print!("\x1B[2J\x1B[1;1H");
         println!("{}", emu);
 
-        canvas.present();
         std::thread::sleep(Duration::new(0, 1_000_000_000u32 / FPS));
+        frame += 1;
     }
+
+    if let Some(recorder) = recorder {
+        stop_recording(recorder);
+    }
+    if let (Some((_, wav)), Some(path)) = (audio, &options.record_audio) {
+        wav.finish().expect("Failed to write WAV");
+        println!("Wrote {}", path);
+    }
+    if let (Some(mut movie), Some(path)) = (movie, &options.record_movie) {
+        movie.finish(frame);
+        let mut file = std::fs::File::create(path).expect("Failed to create movie");
+        movie.write(&mut file).expect("Failed to write movie");
+        println!("Wrote {}", path);
+    }
+    if let (Some(profiler), Some(prefix)) = (profiler, options.profile) {
+        write_profile(&profiler, &emu, &prefix);
+    }
+    if let (Some(coverage), Some(prefix)) = (coverage, options.coverage) {
+        write_coverage(&coverage, &emu, rom.len(), &prefix);
+    }
+}
+
+// <rom name>-<frame>.png in the current directory
+fn screenshot_path(rom_name: &str, frame: u64) -> String {
+    let stem = std::path::Path::new(rom_name)
+        .file_stem()
+        .map_or("screenshot".into(), |stem| stem.to_string_lossy());
+    format!("{}-{}.png", stem, frame)
 }
 
-struct SquareWave {
-    phase_inc: f32,
-    phase: f32,
-    volume: f32,
+// <rom name>-<frame> with the extension of --record, or .gif
+fn recording_path(rom_name: &str, record: Option<&str>, frame: u64) -> String {
+    let extension = record
+        .and_then(|path| std::path::Path::new(path).extension())
+        .map_or("gif".into(), |extension| extension.to_string_lossy());
+    let stem = std::path::Path::new(rom_name)
+        .file_stem()
+        .map_or("recording".into(), |stem| stem.to_string_lossy());
+    format!("{}-{}.{}", stem, frame, extension)
 }
 
-impl AudioCallback for SquareWave {
+fn stop_recording(recorder: Recorder) {
+    let frames = recorder.finish().expect("Failed to finish recording");
+    println!("Recorded {} frames", frames);
+}
+
+fn write_profile(profiler: &Profiler, emu: &Chip8, prefix: &str) {
+    let mut report =
+        std::fs::File::create(format!("{}.txt", prefix)).expect("Failed to create profile report");
+    profiler
+        .report(emu, &mut report, PROFILE_TOP)
+        .expect("Failed to write profile report");
+    let mut folded = std::fs::File::create(format!("{}.folded", prefix))
+        .expect("Failed to create folded stacks");
+    profiler
+        .write_folded(&mut folded)
+        .expect("Failed to write folded stacks");
+    println!("Wrote {}.txt and {}.folded", prefix, prefix);
+}
+
+fn write_coverage(coverage: &Coverage, emu: &Chip8, rom_len: usize, prefix: &str) {
+    let mut disassembly = std::fs::File::create(format!("{}.txt", prefix))
+        .expect("Failed to create coverage disassembly");
+    coverage
+        .write_disassembly(emu, rom_len, &mut disassembly)
+        .expect("Failed to write coverage disassembly");
+    let heatmap = std::fs::File::create(format!("{}.png", prefix))
+        .expect("Failed to create coverage heatmap");
+    coverage
+        .write_heatmap(std::io::BufWriter::new(heatmap))
+        .expect("Failed to write coverage heatmap");
+    println!("Wrote {}.txt and {}.png", prefix, prefix);
+}
+
+// Feeds SDL from the backend's generator
+struct Speaker(Generator);
+
+impl AudioCallback for Speaker {
     type Channel = f32;
 
     fn callback(&mut self, out: &mut [f32]) {
-        for x in out.iter_mut() {
-            *x = if self.phase < 0.5 {
-                self.volume
-            } else {
-                -self.volume
-            };
-            self.phase = (self.phase + self.phase_inc) % 1.0;
-        }
+        self.0.fill(out);
     }
 }
 
-fn play(device: &sdl2::audio::AudioDevice<SquareWave>, play: bool) {
-    if play {
-        device.resume();
-    } else {
-        device.pause();
+// The tone chosen on the command line, at the given sample rate
+fn generator(rate: u32, options: &Options) -> Generator {
+    let mut generator = Generator::new(rate);
+    if let Some(waveform) = options.waveform {
+        generator.set_waveform(waveform);
+    }
+    if let Some(pitch) = options.pitch {
+        generator.set_frequency(pitch);
     }
+    if let Some(volume) = options.volume {
+        generator.set_volume(volume);
+    }
+    generator
 }
 
-fn draw(canvas: &mut Canvas<Window>, display: &[bool; 64 * 32]) {
-    canvas.set_draw_color(Color::RGB(0, 0, 0));
-    canvas.clear();
-    canvas.set_draw_color(Color::RGB(255, 255, 255));
-    for (i, &pixel) in display.iter().enumerate() {
-        let x = (i % 64) as i32 * 10;
-        let y = (i / 64) as i32 * 10;
-        if pixel {
-            canvas
-                .fill_rect(Rect::new(x, y, 10, 10))
-                .expect("Failed to draw rect");
-        }
-    }
+// Arrow keys, space and enter for the game actions the database lists,
+// on top of the usual keypad
+fn controls(metadata: &Metadata) -> Vec<(Keycode, u8)> {
+    metadata
+        .keys
+        .iter()
+        .filter_map(|(action, &key)| {
+            let keycode = match action.as_str() {
+                "up" => Keycode::Up,
+                "down" => Keycode::Down,
+                "left" => Keycode::Left,
+                "right" => Keycode::Right,
+                "a" => Keycode::Space,
+                "b" => Keycode::Return,
+                _ => return None,
+            };
+            Some((keycode, key))
+        })
+        .collect()
 }
 
-fn key_to_input(keycode: Keycode) -> Option<u8> {
-    KEYMAP.iter().position(|&k| k == keycode).map(|i| i as u8)
+fn key_to_input(keycode: Keycode, controls: &[(Keycode, u8)]) -> Option<u8> {
+    controls
+        .iter()
+        .find(|&&(k, _)| k == keycode)
+        .map(|&(_, key)| key)
+        .or_else(|| KEYMAP.iter().position(|&k| k == keycode).map(|i| i as u8))
 }
 
 const COLEMAK_DH: &[Keycode; 16] = &[
@@ -165,6 +529,7 @@ const COLEMAK_DH: &[Keycode; 16] = &[
     Keycode::V,
 ];
 
+#[allow(dead_code)]
 const QWERTY: &[Keycode; 16] = &[
     Keycode::Num1,
     Keycode::Num2,
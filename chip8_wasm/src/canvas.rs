@@ -0,0 +1,53 @@
+use crate::{check_scale, Chip8Wasm};
+use chip8_backend::render;
+use wasm_bindgen::{prelude::*, Clamped};
+use web_sys::{CanvasRenderingContext2d, HtmlCanvasElement, ImageData};
+
+// Draws an emulator's frames on a 2D canvas; one per canvas, so a page can
+// show several emulators
+#[wasm_bindgen]
+pub struct CanvasRenderer {
+    canvas: HtmlCanvasElement,
+    ctx: CanvasRenderingContext2d,
+    // Scale of the last draw, None until the first
+    scale: Option<usize>,
+}
+
+#[wasm_bindgen]
+impl CanvasRenderer {
+    #[wasm_bindgen(constructor)]
+    pub fn new(canvas: HtmlCanvasElement) -> Result<CanvasRenderer, JsValue> {
+        let ctx = canvas
+            .get_context("2d")?
+            .and_then(|context| context.dyn_into::<CanvasRenderingContext2d>().ok())
+            .ok_or_else(|| JsValue::from_str("Canvas has no 2d context"))?;
+        Ok(CanvasRenderer {
+            canvas,
+            ctx,
+            scale: None,
+        })
+    }
+
+    // Puts the whole frame on the canvas as one image, if anything changed,
+    // resizing the canvas to 64x32 times the scale
+    #[wasm_bindgen]
+    pub fn draw(&mut self, chip8: &mut Chip8Wasm, scale: usize) -> Result<(), JsValue> {
+        check_scale(scale)?;
+        if !chip8.frame_changed() && self.scale == Some(scale) {
+            return Ok(());
+        }
+        let width = (render::WIDTH * scale) as u32;
+        let height = (render::HEIGHT * scale) as u32;
+        if self.scale != Some(scale) {
+            self.canvas.set_width(width);
+            self.canvas.set_height(height);
+            self.scale = Some(scale);
+        }
+        let image = ImageData::new_with_u8_clamped_array_and_sh(
+            Clamped(chip8.next_frame(scale)),
+            width,
+            height,
+        )?;
+        self.ctx.put_image_data(&image, 0.0, 0.0)
+    }
+}
@@ -1,15 +1,47 @@
+mod canvas;
 mod utils;
-use chip8_backend::Chip8;
-use js_sys::Uint8Array;
-use wasm_bindgen::prelude::*;
-use web_sys::{CanvasRenderingContext2d, HtmlCanvasElement, KeyboardEvent};
+use chip8_backend::audio::{Generator, Waveform};
+use chip8_backend::database;
+use chip8_backend::filter::Pipeline;
+use chip8_backend::phosphor::Phosphor;
+use chip8_backend::quirks::{detect, Platform, Quirks};
+use chip8_backend::render::{self, Palette};
+use chip8_backend::{Chip8, Error, MAX_ROM_SIZE};
+use wasm_bindgen::{prelude::*, Clamped};
+use web_sys::KeyboardEvent;
+
+pub use canvas::CanvasRenderer;
 
 const KEYMAP: &[&str; 16] = QWERTY;
+// Until set_sample_rate gives the AudioContext's
+const SAMPLE_RATE: u32 = 44100;
+// Unless the ROM database says otherwise
+const CYCLES_PER_FRAME: u32 = 10;
+const FRAME_MS: f64 = 1000.0 / 60.0;
+// Longest time run catches up on, e.g. after the tab was in the background
+const MAX_CATCH_UP_MS: f64 = 250.0;
 
 #[wasm_bindgen]
 pub struct Chip8Wasm {
     chip8: Chip8,
-    ctx: CanvasRenderingContext2d,
+    // Reloaded by reset
+    rom: Vec<u8>,
+    platform: Platform,
+    cycles_per_frame: u32,
+    paused: bool,
+    // Time run hasn't used up yet, in ms
+    elapsed: f64,
+    palette: Palette,
+    phosphor: Option<Phosphor>,
+    filters: Pipeline,
+    // RGBA frame, reused between renders
+    pixels: Vec<u8>,
+    // Whether the picture changed since it was last rendered
+    changed: bool,
+    audio: Generator,
+    muted: bool,
+    // Sound from frames run but not yet taken by take_audio
+    samples: Vec<f32>,
 }
 
 #[wasm_bindgen]
@@ -19,15 +51,19 @@ impl Chip8Wasm {
         utils::set_panic_hook();
         Chip8Wasm {
             chip8: Chip8::new(),
-            // most ergonomic rust library
-            ctx: web_sys::window()
-                .and_then(|window| window.document())
-                .and_then(|document| document.get_element_by_id("canvas"))
-                .and_then(|element| element.dyn_into::<HtmlCanvasElement>().ok())
-                .and_then(|canvas| canvas.get_context("2d").ok())
-                .unwrap()
-                .and_then(|context| context.dyn_into::<CanvasRenderingContext2d>().ok())
-                .unwrap_or_else(|| panic!("Failed to initialize CanvasRenderingContext2d")),
+            rom: Vec::new(),
+            platform: Platform::Chip8,
+            cycles_per_frame: CYCLES_PER_FRAME,
+            paused: false,
+            elapsed: 0.0,
+            palette: Palette::default(),
+            phosphor: None,
+            filters: Pipeline::default(),
+            pixels: Vec::new(),
+            changed: true,
+            audio: Generator::new(SAMPLE_RATE),
+            muted: false,
+            samples: Vec::new(),
         }
     }
 
@@ -36,14 +72,176 @@ impl Chip8Wasm {
         true
     }
 
+    // Restarts the loaded ROM, keeping the settings
     #[wasm_bindgen]
     pub fn reset(&mut self) {
         self.chip8.reset();
+        self.chip8
+            .load_rom(&self.rom)
+            .expect("load_rom only keeps ROMs that fit");
+        if let Some(phosphor) = self.phosphor.as_mut() {
+            phosphor.reset();
+        }
+        self.elapsed = 0.0;
+        self.samples.clear();
+        self.changed = true;
+    }
+
+    // Also picks the platform, quirks, speed and colours for the ROM. Fails,
+    // keeping the current ROM, if it doesn't fit in memory.
+    #[wasm_bindgen]
+    pub fn load_rom(&mut self, rom: &[u8]) -> Result<(), JsError> {
+        if rom.len() > MAX_ROM_SIZE {
+            return Err(JsError::new(&Error::RomTooLarge(rom.len()).to_string()));
+        }
+        self.rom = rom.to_vec();
+        let detection = detect(&self.rom);
+        self.platform = detection.platform;
+        self.chip8.set_quirks(detection.quirks);
+        let metadata = database::lookup(&self.rom);
+        self.cycles_per_frame = metadata
+            .as_ref()
+            .and_then(|m| m.tickrate)
+            .unwrap_or(CYCLES_PER_FRAME);
+        self.palette = metadata
+            .as_ref()
+            .and_then(|m| m.colors.as_ref())
+            .and_then(Palette::from_database)
+            .unwrap_or_default();
+        self.reset();
+        Ok(())
+    }
+
+    // "Title (release) by authors" for ROMs in the database
+    #[wasm_bindgen]
+    pub fn title(&self) -> Option<String> {
+        database::lookup(&self.rom).map(|m| m.display_name())
+    }
+
+    // Runs as many 60 Hz frames as fit in the time since the last call, plus
+    // any left over from before; returns how many ran. Stops and pauses on
+    // an instruction the interpreter can't run.
+    #[wasm_bindgen]
+    pub fn run(&mut self, elapsed_ms: f64) -> Result<u32, JsValue> {
+        if self.paused || self.rom.is_empty() {
+            return Ok(0);
+        }
+        self.elapsed += elapsed_ms.clamp(0.0, MAX_CATCH_UP_MS);
+        let mut frames = 0;
+        while self.elapsed >= FRAME_MS {
+            self.elapsed -= FRAME_MS;
+            self.frame()?;
+            frames += 1;
+        }
+        Ok(frames)
+    }
+
+    #[wasm_bindgen]
+    pub fn paused(&self) -> bool {
+        self.paused
+    }
+
+    #[wasm_bindgen]
+    pub fn set_paused(&mut self, paused: bool) {
+        self.paused = paused;
+        self.elapsed = 0.0;
+        self.audio.set_sound(self.sound());
+    }
+
+    // Instructions per frame
+    #[wasm_bindgen]
+    pub fn cycles_per_frame(&self) -> u32 {
+        self.cycles_per_frame
+    }
+
+    #[wasm_bindgen]
+    pub fn set_cycles_per_frame(&mut self, cycles: u32) {
+        self.cycles_per_frame = cycles.max(1);
+    }
+
+    // shift, load_store, jump, vf_reset or clipping
+    #[wasm_bindgen]
+    pub fn quirk(&self, name: &str) -> Result<bool, JsValue> {
+        let mut quirks = self.chip8.get_quirks();
+        Ok(*quirk(&mut quirks, name)?)
+    }
+
+    #[wasm_bindgen]
+    pub fn set_quirk(&mut self, name: &str, value: bool) -> Result<(), JsValue> {
+        let mut quirks = self.chip8.get_quirks();
+        *quirk(&mut quirks, name)? = value;
+        self.chip8.set_quirks(quirks);
+        Ok(())
     }
 
+    // Keyboard key for a CHIP-8 key, 0 to F
     #[wasm_bindgen]
-    pub fn load_rom(&mut self, rom: Uint8Array) {
-        self.chip8.load_rom(&rom.to_vec());
+    pub fn key_binding(&self, key: u8) -> String {
+        KEYMAP[key as usize & 0xF].to_string()
+    }
+
+    // Whether a key, 0 to F, is held
+    #[wasm_bindgen]
+    pub fn key_pressed(&self, key: u8) -> bool {
+        self.chip8.get_keys()[key as usize & 0xF]
+    }
+
+    // Short platform name: chip8, schip or xochip
+    #[wasm_bindgen]
+    pub fn platform(&self) -> String {
+        self.platform.name().to_string()
+    }
+
+    // Override the detected platform
+    #[wasm_bindgen]
+    pub fn set_platform(&mut self, name: &str) -> Result<(), JsValue> {
+        let platform: Platform = name.parse().map_err(|e: String| JsValue::from_str(&e))?;
+        self.platform = platform;
+        self.chip8.set_quirks(platform.quirks());
+        Ok(())
+    }
+
+    // "#rrggbb" colours, background first
+    #[wasm_bindgen]
+    pub fn palette(&self) -> Vec<String> {
+        self.palette
+            .colors()
+            .iter()
+            .map(|[r, g, b]| format!("#{:02x}{:02x}{:02x}", r, g, b))
+            .collect()
+    }
+
+    #[wasm_bindgen]
+    pub fn set_palette(&mut self, colors: Vec<String>) -> Result<(), JsValue> {
+        self.palette = Palette::from_hex(&colors)
+            .ok_or_else(|| JsValue::from_str("Expected at least two #rrggbb colours"))?;
+        self.changed = true;
+        Ok(())
+    }
+
+    // Anti-flicker filter: "decay:<fraction>", "or:<frames>" or "off"
+    #[wasm_bindgen]
+    pub fn set_phosphor(&mut self, mode: &str) -> Result<(), JsValue> {
+        self.phosphor = match mode {
+            "off" => None,
+            _ => Some(Phosphor::new(
+                mode.parse().map_err(|e: String| JsValue::from_str(&e))?,
+            )),
+        };
+        if let Some(phosphor) = self.phosphor.as_mut() {
+            phosphor.update(self.chip8.get_display());
+        }
+        self.changed = true;
+        Ok(())
+    }
+
+    // Post-processing filters separated by commas, e.g. "scale2x,scanlines",
+    // or "" for none
+    #[wasm_bindgen]
+    pub fn set_filters(&mut self, filters: &str) -> Result<(), JsValue> {
+        self.filters = filters.parse().map_err(|e: String| JsValue::from_str(&e))?;
+        self.changed = true;
+        Ok(())
     }
 
     #[wasm_bindgen]
@@ -53,37 +251,244 @@ impl Chip8Wasm {
         }
     }
 
+    // Press or release a key, 0 to F, e.g. from an on-screen keypad
+    #[wasm_bindgen]
+    pub fn set_key(&mut self, key: u8, pressed: bool) {
+        self.chip8.keypress(key & 0xF, pressed);
+    }
+
+    // CHIP-8 key bound to a KeyboardEvent.key, if any
+    #[wasm_bindgen]
+    pub fn key_index(&self, name: &str) -> Option<u8> {
+        key_to_input(name)
+    }
+
     #[wasm_bindgen]
     pub fn cycle(&mut self) {
         self.chip8.cycle();
     }
 
+    // Also the 60 Hz tick for the phosphor filter
     #[wasm_bindgen]
     pub fn cycle_timer(&mut self) {
         self.chip8.cycle_timer();
+        if let Some(phosphor) = self.phosphor.as_mut() {
+            self.changed |= phosphor.update(self.chip8.get_display());
+        }
+        self.audio.set_sound(self.sound());
+    }
+
+    #[wasm_bindgen]
+    pub fn pc(&self) -> u16 {
+        self.chip8.get_pc()
+    }
+
+    #[wasm_bindgen]
+    pub fn i(&self) -> u16 {
+        self.chip8.get_i()
+    }
+
+    #[wasm_bindgen]
+    pub fn sp(&self) -> u8 {
+        self.chip8.get_sp()
+    }
+
+    // Opcode at the PC
+    #[wasm_bindgen]
+    pub fn opcode(&self) -> u16 {
+        self.chip8.get_opcode()
+    }
+
+    #[wasm_bindgen]
+    pub fn delay_timer(&self) -> u8 {
+        self.chip8.get_dt()
+    }
+
+    #[wasm_bindgen]
+    pub fn sound_timer(&self) -> u8 {
+        self.chip8.get_st()
+    }
+
+    // V0 to VF
+    #[wasm_bindgen]
+    pub fn registers(&self) -> Vec<u8> {
+        self.chip8.get_registers().to_vec()
+    }
+
+    #[wasm_bindgen]
+    pub fn stack(&self) -> Vec<u16> {
+        self.chip8.get_stack().to_vec()
+    }
+
+    #[wasm_bindgen]
+    pub fn memory(&self) -> Vec<u8> {
+        self.chip8.get_memory().to_vec()
+    }
+
+    // One byte per pixel, 1 when lit, row by row
+    #[wasm_bindgen]
+    pub fn display(&self) -> Vec<u8> {
+        (0..render::HEIGHT)
+            .flat_map(|y| (0..render::WIDTH).map(move |x| (x, y)))
+            .map(|(x, y)| self.chip8.get_pixel(x, y) as u8)
+            .collect()
+    }
+
+    // Sound is made by the backend's generator as run emulates frames, and
+    // taken with take_audio. XO-CHIP pattern audio isn't emulated yet.
+    #[wasm_bindgen]
+    pub fn set_sample_rate(&mut self, rate: u32) {
+        self.audio.set_rate(rate);
+    }
+
+    // Samples for the frames run since the last call, at most a second's
+    #[wasm_bindgen]
+    pub fn take_audio(&mut self) -> Vec<f32> {
+        std::mem::take(&mut self.samples)
+    }
+
+    // Muting fades the tone out, the sound timer keeps running
+    #[wasm_bindgen]
+    pub fn set_muted(&mut self, muted: bool) {
+        self.muted = muted;
+        self.audio.set_sound(self.sound());
+    }
+
+    #[wasm_bindgen]
+    pub fn muted(&self) -> bool {
+        self.muted
     }
 
+    // 0 to 1
     #[wasm_bindgen]
-    pub fn draw(&mut self, scale: usize) {
-        for (i, &pixel) in self.chip8.get_display().iter().enumerate() {
-            let x = (i % 64) as f64 * scale as f64;
-            let y = (i / 64) as f64 * scale as f64;
-            if pixel {
-                self.ctx.set_fill_style(&JsValue::from_str("#FFFFFF"));
-                self.ctx.fill_rect(x, y, scale as f64, scale as f64);
-            } else {
-                // self.ctx.clear_rect(x, y, scale as f64, scale as f64);
-                self.ctx.set_fill_style(&JsValue::from_str("#000000"));
-                self.ctx.fill_rect(x, y, scale as f64, scale as f64);
+    pub fn set_volume(&mut self, volume: f32) {
+        self.audio.set_volume(volume);
+    }
+
+    #[wasm_bindgen]
+    pub fn volume(&self) -> f32 {
+        self.audio.get_volume()
+    }
+
+    // square, sine, triangle or noise
+    #[wasm_bindgen]
+    pub fn set_waveform(&mut self, name: &str) -> Result<(), JsValue> {
+        let waveform: Waveform = name.parse().map_err(|e: String| JsValue::from_str(&e))?;
+        self.audio.set_waveform(waveform);
+        Ok(())
+    }
+
+    // Hz
+    #[wasm_bindgen]
+    pub fn set_pitch(&mut self, frequency: f32) {
+        self.audio.set_frequency(frequency);
+    }
+
+    // Whether the picture changed since the last framebuffer or draw
+    #[wasm_bindgen]
+    pub fn frame_changed(&self) -> bool {
+        self.changed || self.chip8.frame_changed()
+    }
+
+    // RGBA frame at this scale, in the palette and with the phosphor and
+    // filters applied, for an ImageData or a WebGL texture
+    #[wasm_bindgen]
+    pub fn framebuffer(&mut self, scale: usize) -> Result<Clamped<Vec<u8>>, JsError> {
+        check_scale(scale)?;
+        Ok(Clamped(self.next_frame(scale).to_vec()))
+    }
+
+    // PNG of the frame as framebuffer returns it
+    #[wasm_bindgen]
+    pub fn screenshot(&mut self, scale: usize) -> Result<Vec<u8>, JsValue> {
+        check_scale(scale)?;
+        let mut png = Vec::new();
+        render::encode_png(
+            self.render(scale),
+            render::WIDTH * scale,
+            render::HEIGHT * scale,
+            &mut png,
+        )
+        .map_err(|e| JsValue::from_str(&e.to_string()))?;
+        Ok(png)
+    }
+}
+
+impl Chip8Wasm {
+    fn sound(&self) -> bool {
+        self.chip8.get_sound() && !self.muted && !self.paused
+    }
+
+    fn frame(&mut self) -> Result<(), JsValue> {
+        for _ in 0..self.cycles_per_frame {
+            if let Err(e) = self.chip8.try_cycle() {
+                self.set_paused(true);
+                return Err(JsValue::from_str(&e.to_string()));
             }
         }
+        self.cycle_timer();
+        let sound = self.sound();
+        self.audio.render_frame(sound, &mut self.samples);
+        let max = self.audio.get_rate() as usize;
+        if self.samples.len() > max {
+            self.samples.drain(..self.samples.len() - max);
+        }
+        Ok(())
+    }
+
+    // Renders the frame into pixels at the given scale, filtering at one
+    // pixel per pixel first if there are filters
+    fn render(&mut self, scale: usize) -> &[u8] {
+        let render_scale = if self.filters.is_empty() { scale } else { 1 };
+        self.pixels.resize(render::rgba8_len(render_scale), 0);
+        match &self.phosphor {
+            Some(phosphor) => phosphor.render_rgba8(&self.palette, render_scale, &mut self.pixels),
+            None => render::render_rgba8(
+                self.chip8.get_display(),
+                &self.palette,
+                render_scale,
+                &mut self.pixels,
+            ),
+        }
+        if !self.filters.is_empty() {
+            self.pixels = self.filters.apply(&self.pixels, scale).pixels;
+        }
+        &self.pixels
+    }
+
+    // Renders the frame for showing, marking it as seen by frame_changed
+    pub(crate) fn next_frame(&mut self, scale: usize) -> &[u8] {
+        self.changed = false;
+        self.chip8.clear_dirty();
+        self.render(scale)
     }
 }
 
+// Rendering at scale 0 would panic, which leaves the instance unusable
+pub(crate) fn check_scale(scale: usize) -> Result<(), JsError> {
+    if scale == 0 {
+        return Err(JsError::new("Scale must be at least 1"));
+    }
+    Ok(())
+}
+
+impl Default for Chip8Wasm {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn quirk<'a>(quirks: &'a mut Quirks, name: &str) -> Result<&'a mut bool, JsValue> {
+    quirks
+        .get_mut(name)
+        .ok_or_else(|| JsValue::from_str(&format!("Unknown quirk {}", name)))
+}
+
 fn key_to_input(key: &str) -> Option<u8> {
     KEYMAP.iter().position(|&k| k == key).map(|i| i as u8)
 }
 
+#[allow(dead_code)]
 const COLEMAK_DH: &[&str; 16] = &[
     "1", "2", "3", "4", "q", "w", "f", "p", "a", "r", "s", "t", "x", "c", "d", "v",
 ];
@@ -0,0 +1,142 @@
+// Runs under Node with: wasm-pack test --node
+#![cfg(target_arch = "wasm32")]
+
+use chip8_wasm::Chip8Wasm;
+use wasm_bindgen_test::*;
+
+// V0 = 0x2A, I = 0x208, draw a 1-row sprite at (0, 0), loop forever,
+// with the sprite's row of 8 lit pixels after the code
+const SPRITE: &[u8] = &[
+    0x60, 0x00, 0xA2, 0x0A, 0xD0, 0x01, 0x60, 0x2A, 0x12, 0x08, 0xFF,
+];
+
+fn pixel(frame: &[u8], x: usize, y: usize, scale: usize) -> &[u8] {
+    let i = (y * 64 * scale + x) * 4;
+    &frame[i..i + 4]
+}
+
+#[wasm_bindgen_test]
+fn runs_without_a_dom() {
+    let mut chip8 = Chip8Wasm::new();
+    assert_eq!(chip8.run(1000.0).unwrap(), 0);
+    let frame = chip8.framebuffer(2).unwrap();
+    assert_eq!(frame.len(), 64 * 2 * 32 * 2 * 4);
+    assert_eq!(pixel(&frame, 0, 0, 2), [0, 0, 0, 255]);
+}
+
+#[wasm_bindgen_test]
+fn rejects_scale_0() {
+    let mut chip8 = Chip8Wasm::new();
+    assert!(chip8.framebuffer(0).is_err());
+    assert!(chip8.screenshot(0).is_err());
+    chip8.set_filters("bloom").unwrap();
+    assert!(chip8.framebuffer(0).is_err());
+    assert_eq!(chip8.framebuffer(1).unwrap().len(), 64 * 32 * 4);
+}
+
+#[wasm_bindgen_test]
+fn runs_frames_from_elapsed_time() {
+    let mut chip8 = Chip8Wasm::new();
+    chip8.load_rom(SPRITE).unwrap();
+    // Not quite a frame yet, then enough for one more
+    assert_eq!(chip8.run(10.0).unwrap(), 0);
+    assert_eq!(chip8.run(10.0).unwrap(), 1);
+    assert_eq!(chip8.pc(), 0x208);
+    assert_eq!(chip8.i(), 0x20A);
+    assert_eq!(chip8.registers()[0], 0x2A);
+
+    let display = chip8.display();
+    assert_eq!(display.len(), 64 * 32);
+    assert_eq!(display[..9], [1, 1, 1, 1, 1, 1, 1, 1, 0]);
+    assert!(display[64..].iter().all(|&pixel| pixel == 0));
+
+    assert!(chip8.frame_changed());
+    let frame = chip8.framebuffer(1).unwrap();
+    assert_eq!(pixel(&frame, 7, 0, 1), [255, 255, 255, 255]);
+    assert_eq!(pixel(&frame, 8, 0, 1), [0, 0, 0, 255]);
+    assert!(!chip8.frame_changed());
+}
+
+#[wasm_bindgen_test]
+fn applies_the_palette() {
+    let mut chip8 = Chip8Wasm::new();
+    chip8.load_rom(SPRITE).unwrap();
+    chip8.run(20.0).unwrap();
+    chip8
+        .set_palette(vec!["#102030".to_string(), "#ffcc00".to_string()])
+        .unwrap();
+    assert!(chip8.frame_changed());
+    let frame = chip8.framebuffer(1).unwrap();
+    assert_eq!(pixel(&frame, 0, 0, 1), [0xFF, 0xCC, 0x00, 255]);
+    assert_eq!(pixel(&frame, 0, 1, 1), [0x10, 0x20, 0x30, 255]);
+    assert!(chip8.set_palette(vec!["red".to_string()]).is_err());
+}
+
+#[wasm_bindgen_test]
+fn pauses_and_resets() {
+    let mut chip8 = Chip8Wasm::new();
+    chip8.load_rom(SPRITE).unwrap();
+    chip8.set_paused(true);
+    assert_eq!(chip8.run(100.0).unwrap(), 0);
+    chip8.set_paused(false);
+    assert_eq!(chip8.run(90.0).unwrap(), 5);
+    chip8.reset();
+    assert_eq!(chip8.pc(), 0x200);
+    assert!(chip8.display().iter().all(|&pixel| pixel == 0));
+}
+
+#[wasm_bindgen_test]
+fn pauses_on_errors() {
+    let mut chip8 = Chip8Wasm::new();
+    // 00EE with an empty stack
+    chip8.load_rom(&[0x00, 0xEE]).unwrap();
+    assert!(chip8.run(20.0).is_err());
+    assert!(chip8.paused());
+}
+
+#[wasm_bindgen_test]
+fn rejects_roms_too_large_for_memory() {
+    let mut chip8 = Chip8Wasm::new();
+    chip8.load_rom(SPRITE).unwrap();
+    assert!(chip8.load_rom(&[0; 4096 - 0x200 + 1]).is_err());
+    assert!(chip8.load_rom(&[0; 4096 - 0x200]).is_ok());
+}
+
+#[wasm_bindgen_test]
+fn takes_keys_by_index() {
+    let mut chip8 = Chip8Wasm::new();
+    chip8.set_key(0xA, true);
+    assert!(chip8.key_pressed(0xA));
+    chip8.set_key(0xA, false);
+    assert!(!chip8.key_pressed(0xA));
+    assert_eq!(chip8.key_index("x"), Some(0xD));
+    assert_eq!(chip8.key_binding(0xD), "x");
+    assert_eq!(chip8.key_index("Escape"), None);
+}
+
+#[wasm_bindgen_test]
+fn changes_quirks_and_platform() {
+    let mut chip8 = Chip8Wasm::new();
+    chip8.set_quirk("shift", true).unwrap();
+    assert!(chip8.quirk("shift").unwrap());
+    assert!(chip8.quirk("nonsense").is_err());
+    chip8.set_platform("schip").unwrap();
+    assert_eq!(chip8.platform(), "schip");
+    assert!(chip8.set_platform("gameboy").is_err());
+}
+
+#[wasm_bindgen_test]
+fn makes_sound_while_the_timer_runs() {
+    let mut chip8 = Chip8Wasm::new();
+    chip8.set_sample_rate(6000);
+    // ST = V0 = 30, loop forever
+    chip8
+        .load_rom(&[0x60, 0x1E, 0xF0, 0x18, 0x12, 0x04])
+        .unwrap();
+    chip8.run(20.0).unwrap();
+    assert_eq!(chip8.sound_timer(), 29);
+    let samples = chip8.take_audio();
+    assert_eq!(samples.len(), 100);
+    assert!(samples.iter().any(|&sample| sample != 0.0));
+    assert!(chip8.take_audio().is_empty());
+}
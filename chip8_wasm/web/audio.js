@@ -0,0 +1,78 @@
+// @ts-check
+/** @typedef {import("../pkg/chip8_wasm.js").Chip8Wasm} Chip8Wasm */
+
+// How far ahead of the audio clock sound is queued
+const LEAD = 0.05;
+// Sound further ahead than this is dropped instead of piling up latency
+const MAX_LEAD = 0.2;
+
+// Plays the emulator's sound through Web Audio: samples from
+// Chip8Wasm.take_audio queued back to back on the audio clock
+export class Speaker {
+	next = 0;
+
+	/** @param {Chip8Wasm} chip8 */
+	constructor(chip8) {
+		this.ctx = new AudioContext();
+		chip8.set_sample_rate(this.ctx.sampleRate);
+	}
+
+	// Browsers only start audio after a click or key press
+	/** @returns {Promise<void>} */
+	resume() {
+		return this.ctx.resume();
+	}
+
+	/** @param {Float32Array} samples */
+	play(samples) {
+		if (samples.length === 0 || this.ctx.state !== "running") {
+			return;
+		}
+		const now = this.ctx.currentTime;
+		if (this.next < now) {
+			// First sound, or the page fell behind; start again a little ahead
+			this.next = now + LEAD;
+		} else if (this.next > now + MAX_LEAD) {
+			return;
+		}
+		const buffer = this.ctx.createBuffer(1, samples.length, this.ctx.sampleRate);
+		buffer.copyToChannel(samples, 0);
+		const source = this.ctx.createBufferSource();
+		source.buffer = buffer;
+		source.connect(this.ctx.destination);
+		source.start(this.next);
+		this.next += buffer.duration;
+	}
+}
+
+const FRAME_MS = 1000 / 60;
+
+// Vibrates while the sound timer runs, on phones that can
+export class Vibrator {
+	enabled = false;
+	// When the current vibration ends, in performance.now() time
+	until = 0;
+
+	/** @returns {boolean} */
+	static supported() {
+		return "vibrate" in navigator;
+	}
+
+	// Call once per animation frame
+	/**
+	 * @param {Chip8Wasm} chip8
+	 * @param {number} now
+	 */
+	update(chip8, now) {
+		const remaining =
+			this.enabled && !chip8.paused() ? chip8.sound_timer() * FRAME_MS : 0;
+		if (remaining > 0 && now + remaining > this.until + FRAME_MS) {
+			// Started, or the ROM set the timer again
+			navigator.vibrate(remaining);
+			this.until = now + remaining;
+		} else if (remaining === 0 && this.until > now) {
+			navigator.vibrate(0);
+			this.until = 0;
+		}
+	}
+}
@@ -0,0 +1,21 @@
+// Copies the games from the roms submodule into web/roms/ and lists them in
+// web/roms/index.json for the page's ROM menu. Run with: node web/bundle-roms.mjs
+import { copyFileSync, existsSync, mkdirSync, readdirSync, writeFileSync } from "node:fs";
+import { fileURLToPath } from "node:url";
+
+const source = fileURLToPath(new URL("../../roms/games/", import.meta.url));
+const target = fileURLToPath(new URL("roms/", import.meta.url));
+
+if (!existsSync(source)) {
+	console.error("No ROMs found, run: git submodule update --init");
+	process.exit(1);
+}
+mkdirSync(target, { recursive: true });
+const files = readdirSync(source)
+	.filter((file) => /\.ch8$/i.test(file))
+	.sort();
+for (const file of files) {
+	copyFileSync(source + file, target + file);
+}
+writeFileSync(`${target}index.json`, `${JSON.stringify(files, null, "\t")}\n`);
+console.log(`Bundled ${files.length} ROMs into ${target}`);
@@ -0,0 +1,224 @@
+// @ts-check
+import init, { CanvasRenderer, Chip8Wasm } from "../pkg/chip8_wasm.js";
+import { Speaker, Vibrator } from "./audio.js";
+import { Keypad } from "./keypad.js";
+
+const SCALE = 12;
+
+// Listed by bundle-roms.mjs; missing unless it has been run
+const ROM_LIST = "roms/index.json";
+
+await init();
+
+// Typed by the declaration it's assigned to
+/** @param {string} id */
+const element = (id) => /** @type {any} */ (document.getElementById(id));
+
+/** @type {HTMLHeadingElement} */
+const title = element("title");
+/** @type {HTMLParagraphElement} */
+const status = element("status");
+/** @type {HTMLInputElement} */
+const fileInput = element("fileinput");
+/** @type {HTMLSelectElement} */
+const romList = element("romlist");
+/** @type {HTMLButtonElement} */
+const pauseButton = element("pause");
+/** @type {HTMLButtonElement} */
+const resetButton = element("reset");
+/** @type {HTMLInputElement} */
+const speed = element("speed");
+/** @type {HTMLOutputElement} */
+const speedValue = element("speedvalue");
+/** @type {HTMLSelectElement} */
+const platform = element("platform");
+const quirks = [
+	.../** @type {NodeListOf<HTMLInputElement>} */ (
+		document.querySelectorAll("input[data-quirk]")
+	),
+];
+/** @type {HTMLInputElement} */
+const background = element("background");
+/** @type {HTMLInputElement} */
+const foreground = element("foreground");
+/** @type {HTMLSelectElement} */
+const phosphor = element("phosphor");
+/** @type {HTMLSelectElement} */
+const filters = element("filters");
+/** @type {HTMLInputElement} */
+const mute = element("mute");
+/** @type {HTMLInputElement} */
+const volume = element("volume");
+/** @type {HTMLInputElement} */
+const vibrate = element("vibrate");
+/** @type {HTMLDivElement} */
+const keypadElement = element("keypad");
+
+const chip8 = new Chip8Wasm();
+const renderer = new CanvasRenderer(element("canvas"));
+/** @type {Speaker | null} */
+let speaker = null;
+const keypad = new Keypad(keypadElement, chip8);
+const vibrator = new Vibrator();
+
+// Audio can only start from a user gesture
+const startAudio = () => {
+	if (speaker === null) {
+		speaker = new Speaker(chip8);
+	}
+	speaker.resume();
+};
+
+// Show the emulator's settings, e.g. after loading a ROM
+const showSettings = () => {
+	speed.value = String(chip8.cycles_per_frame());
+	speedValue.value = speed.value;
+	platform.value = chip8.platform();
+	for (const checkbox of quirks) {
+		checkbox.checked = chip8.quirk(/** @type {string} */ (checkbox.dataset.quirk));
+	}
+	const [bg, fg] = chip8.palette();
+	background.value = bg;
+	foreground.value = fg;
+	pauseButton.textContent = chip8.paused() ? "Resume" : "Pause";
+};
+
+/**
+ * @param {Uint8Array} rom
+ * @param {string} name
+ */
+const loadRom = (rom, name) => {
+	try {
+		chip8.load_rom(rom);
+	} catch (e) {
+		status.textContent = `Failed to load ${name}: ${e}`;
+		return;
+	}
+	chip8.set_paused(false);
+	const known = chip8.title();
+	title.textContent = `Chip-8 Emulator | ${known ?? name}`;
+	status.textContent = "";
+	pauseButton.disabled = false;
+	resetButton.disabled = false;
+	showSettings();
+	console.log(`Platform: ${chip8.platform()}`);
+};
+
+fileInput.addEventListener("change", async () => {
+	const file = fileInput.files?.[0];
+	if (!file) {
+		return;
+	}
+	startAudio();
+	loadRom(new Uint8Array(await file.arrayBuffer()), file.name);
+	fileInput.blur();
+});
+
+romList.addEventListener("change", async () => {
+	if (romList.value === "") {
+		return;
+	}
+	startAudio();
+	const response = await fetch(`roms/${encodeURIComponent(romList.value)}`);
+	if (!response.ok) {
+		status.textContent = `Failed to load ${romList.value}`;
+		return;
+	}
+	const name = romList.value.replace(/\.[^.]*$/, "");
+	loadRom(new Uint8Array(await response.arrayBuffer()), name);
+	romList.blur();
+});
+
+fetch(ROM_LIST)
+	.then((response) => (response.ok ? response.json() : []))
+	.then((/** @type {string[]} */ files) => {
+		for (const file of files) {
+			const option = document.createElement("option");
+			option.value = file;
+			option.textContent = file.replace(/\.[^.]*$/, "");
+			romList.append(option);
+		}
+		romList.hidden = files.length === 0;
+	})
+	.catch(() => {});
+
+pauseButton.addEventListener("click", () => {
+	chip8.set_paused(!chip8.paused());
+	status.textContent = "";
+	showSettings();
+});
+
+resetButton.addEventListener("click", () => {
+	chip8.reset();
+	chip8.set_paused(false);
+	status.textContent = "";
+	showSettings();
+});
+
+speed.addEventListener("input", () => {
+	chip8.set_cycles_per_frame(Number(speed.value));
+	speedValue.value = speed.value;
+});
+
+platform.addEventListener("change", () => {
+	chip8.set_platform(platform.value);
+	showSettings();
+});
+
+for (const checkbox of quirks) {
+	checkbox.addEventListener("change", () => {
+		chip8.set_quirk(/** @type {string} */ (checkbox.dataset.quirk), checkbox.checked);
+	});
+}
+
+const setPalette = () => chip8.set_palette([background.value, foreground.value]);
+background.addEventListener("input", setPalette);
+foreground.addEventListener("input", setPalette);
+phosphor.addEventListener("change", () => chip8.set_phosphor(phosphor.value));
+filters.addEventListener("change", () => chip8.set_filters(filters.value));
+mute.addEventListener("change", () => chip8.set_muted(mute.checked));
+volume.addEventListener("input", () => chip8.set_volume(Number(volume.value)));
+element("vibrateoption").hidden = !Vibrator.supported();
+vibrate.addEventListener("change", () => {
+	vibrator.enabled = vibrate.checked;
+});
+
+keypadElement.addEventListener("pointerdown", startAudio);
+
+// Keys typed into the page go to the emulator, except while using a control
+/** @param {KeyboardEvent} evt */
+const forEmulator = (evt) =>
+	!(evt.target instanceof HTMLInputElement || evt.target instanceof HTMLSelectElement);
+
+document.addEventListener("keydown", (evt) => {
+	if (forEmulator(evt)) {
+		startAudio();
+		chip8.keypress(evt, true);
+	}
+});
+
+document.addEventListener("keyup", (evt) => {
+	if (forEmulator(evt)) {
+		chip8.keypress(evt, false);
+	}
+});
+
+// Timing is kept in Rust: run works out how many 60 Hz frames are due
+let last = performance.now();
+/** @param {number} now */
+const loop = (now) => {
+	try {
+		if (chip8.run(now - last) > 0) {
+			speaker?.play(chip8.take_audio());
+		}
+	} catch (e) {
+		status.textContent = `Stopped: ${e}`;
+		showSettings();
+	}
+	last = now;
+	renderer.draw(chip8, SCALE);
+	keypad.update();
+	vibrator.update(chip8, now);
+	window.requestAnimationFrame(loop);
+};
+window.requestAnimationFrame(loop);
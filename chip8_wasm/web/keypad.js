@@ -0,0 +1,89 @@
+// @ts-check
+/** @typedef {import("../pkg/chip8_wasm.js").Chip8Wasm} Chip8Wasm */
+
+// COSMAC VIP keypad layout
+const LAYOUT = [
+	0x1, 0x2, 0x3, 0xc, 0x4, 0x5, 0x6, 0xd, 0x7, 0x8, 0x9, 0xe, 0xa, 0x0, 0xb, 0xf,
+];
+
+// On-screen hex keypad for mouse, pen and touch. Every finger is tracked on
+// its own, so several keys can be held at once, and sliding a finger onto
+// another key moves the press there.
+export class Keypad {
+	/** @type {Map<Element, number>} */
+	buttons = new Map();
+	// Key under each pointer that is down, null over a gap
+	/** @type {Map<number, number | null>} */
+	pointers = new Map();
+
+	/**
+	 * @param {HTMLElement} element
+	 * @param {Chip8Wasm} chip8
+	 */
+	constructor(element, chip8) {
+		this.chip8 = chip8;
+		for (const key of LAYOUT) {
+			const button = document.createElement("button");
+			const hex = key.toString(16).toUpperCase();
+			button.innerHTML = `${hex}<small>${chip8.key_binding(key)}</small>`;
+			button.tabIndex = -1;
+			element.append(button);
+			this.buttons.set(button, key);
+		}
+
+		element.addEventListener("pointerdown", (evt) => {
+			// No focus, text selection or emulated mouse events
+			evt.preventDefault();
+			element.setPointerCapture(evt.pointerId);
+			this.pointers.set(evt.pointerId, null);
+			this.move(evt);
+		});
+		element.addEventListener("pointermove", (evt) => this.move(evt));
+		element.addEventListener("pointerup", (evt) => this.lift(evt));
+		element.addEventListener("pointercancel", (evt) => this.lift(evt));
+		// Long presses would open a menu
+		element.addEventListener("contextmenu", (evt) => evt.preventDefault());
+	}
+
+	// Shows which keys are held, from the keypad or the keyboard
+	update() {
+		for (const [button, key] of this.buttons) {
+			button.classList.toggle("down", this.chip8.key_pressed(key));
+		}
+	}
+
+	/** @param {PointerEvent} evt */
+	move(evt) {
+		if (!this.pointers.has(evt.pointerId)) {
+			return;
+		}
+		const button = document
+			.elementFromPoint(evt.clientX, evt.clientY)
+			?.closest("button");
+		const key = (button && this.buttons.get(button)) ?? null;
+		const previous = this.pointers.get(evt.pointerId) ?? null;
+		if (key === previous) {
+			return;
+		}
+		this.pointers.set(evt.pointerId, key);
+		this.release(previous);
+		if (key !== null) {
+			this.chip8.set_key(key, true);
+		}
+	}
+
+	/** @param {PointerEvent} evt */
+	lift(evt) {
+		const key = this.pointers.get(evt.pointerId) ?? null;
+		this.pointers.delete(evt.pointerId);
+		this.release(key);
+	}
+
+	// Lets go of a key unless another finger is still on it
+	/** @param {number | null} key */
+	release(key) {
+		if (key !== null && ![...this.pointers.values()].includes(key)) {
+			this.chip8.set_key(key, false);
+		}
+	}
+}